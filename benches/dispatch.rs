@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use cogwork::{
+    opcode::Opcode,
+    vm::{Backend, Code, Process, Stackable, VM},
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+/// Straight line arithmetic on locals, mostly runs `load a; load b; <arithmetic>` and
+/// `ldc c; <arithmetic>` which the stack backend fuses.
+fn arithmetic() -> VM {
+    let mut instructions = vec![
+        Opcode::Ldc(0),
+        Opcode::Store(0),
+        Opcode::Ldc(0),
+        Opcode::Store(1),
+    ];
+
+    for _ in 0..1000 {
+        instructions.extend_from_slice(&[
+            Opcode::Load(0),
+            Opcode::Load(1),
+            Opcode::Add,
+            Opcode::Ldc(1),
+            Opcode::Mod,
+            Opcode::Store(1),
+            Opcode::Load(1),
+            Opcode::Load(0),
+            Opcode::Mul,
+            Opcode::Ldc(1),
+            Opcode::Mod,
+            Opcode::Store(0),
+        ]);
+    }
+
+    instructions.extend_from_slice(&[Opcode::Load(0), Opcode::Return]);
+
+    VM::new_vm(
+        vec![Stackable::Int(1), Stackable::Int(7)],
+        Code::new(instructions, 2),
+    )
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+
+    let vm = Arc::new(arithmetic());
+    group.bench_function("stack stepped", |b| {
+        b.iter(|| {
+            let mut process = Process::new_shared_process(vm.clone(), 0);
+
+            while !process.step().is_terminated() {}
+        })
+    });
+    group.bench_function("stack fused", |b| {
+        b.iter(|| Process::new_shared_process(vm.clone(), 0).run().unwrap())
+    });
+
+    for backend in [Backend::Closure, Backend::Register] {
+        let vm = Arc::new(arithmetic().with_backend(backend));
+        group.bench_function(format!("{:?}", backend).to_lowercase(), |b| {
+            b.iter(|| Process::new_shared_process(vm.clone(), 0).run().unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
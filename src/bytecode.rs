@@ -1,16 +1,27 @@
 use std::{any::Any, cell::RefCell};
 
-use crate::vm::Stackable;
+use crate::{
+    module::{Export, Import, Module},
+    vm::Stackable,
+    writer,
+};
 
 use super::opcode::Opcode;
 
 /// # Format summary: </br>
 ///
 /// ## Overview: </br>
-/// Header - Constant Pool - Code </br>
+/// Header - Constant Pool - Code - Debug Info (Optional) </br>
 ///
 /// ## Header: </br>
 /// \[0x47, 0x45, 0x41, 0x52, 0x57, 0x4F, 0x52, 0x4B\]  <-- Magic number: `GEARWORK` </br>
+/// \[u8\] <-- Format version, see [`FORMAT_VERSION`] </br>
+/// \[u8\] <-- Operand encoding, 0x00 for fixed width and 0x01 for compact, see [`Encoding`] </br>
+/// \[\[u8; 4\], \[u8; n_size\]\] <-- Module name, n_size: Size of name bytes, indicated by the first 4 bytes </br>
+/// \[\[u8; 4\], \[\[u8; 4\], \[u8; m_size\], \[u8; 4\], \[u8; f_size\], u8; i_size\]\] <-- Imports, each is a module name, then a function name and its parameter size </br>
+///                                    i_size: Count of imports, indicated by the first 4 bytes </br>
+/// \[\[u8; 4\], \[\[u8; 4\], \[u8; f_size\], u8; e_size\]\] <-- Exports, each is a top level function name and its parameter size </br>
+///                                    e_size: Count of exports, indicated by the first 4 bytes </br>
 ///
 /// ## Constant Pool: </br>
 /// \[\[u8; 4\], \[u8; cp_size\]\] <-- First 4 bytes indicates how many constants </br>
@@ -21,17 +32,27 @@ use super::opcode::Opcode;
 /// \[0x01, \[u8; 8\]\] <-- Long constant </br>
 /// \[0x02, \[u8; 4\]\] <-- Float constant </br>
 /// \[0x03, \[u8; 8\]\] <-- Double constant </br>
-/// \[0x04, \[u8; 8\], \[u8; s_size\]\] <-- String constant, bytes at [1..4]/[1..8] indicates string bytes' len </br>
+/// \[0x04, \[u8; 4\], \[u8; s_size\]\] <-- String constant, bytes at [1..5] indicates string bytes' len as big endian u32 </br>
 ///                                         s_size: Size of string bytes </br>
 ///
 /// ## Code: </br>
-/// \[\[u8; 4\],\[u8; c_size\]\] <-- Represents instructions, the first 4 bytes indicates instruction length.
+/// \[\[u8; 4\], \[u8; 4\], \[u8; c_size\]\] <-- Represents instructions, the first 4 bytes indicates instruction length,
+///                                             the next 4 bytes indicates local variable slots of top level code.
 ///                                  c_size: Size of instructions </br>
 ///
+/// ## Debug Info: </br>
+/// \[\[u8; 4\], \[u8; s_size\], \[u8; 4\], \[\[u8; 4\], \[u8; 4\]; l_size\]\] <-- Source name, then line table </br>
+///                                    s_size: Size of source name bytes, indicated by the first 4 bytes </br>
+///                                    l_size: Count of line table entries, indicated by the 4 bytes after source name </br>
+///                                    Each entry is an instruction position then the source line it and following instructions belong to </br>
+///
 /// ## Instructions: </br>
 /// \[opcode, \[u8; f_size\]\] <-- Instruction, as known as opcode, followed bytes size is based on instruction </br>
 ///                                f_size: Size of followed bytes, based on instruction </br>
 ///
+/// Followed bytes below are laid out by fixed width encoding. Compact encoding writes each
+/// 4 byte operand, and the 2 byte one of `store` and `load`, as LEB128 of 1 to 5 bytes instead. </br>
+///
 /// ## Instruction Set: </br>
 /// | Opcode name   | Opcode index  | Followed bytes    | Description | Note |
 /// |---------------|---------------|-------------------|-------------|------|
@@ -48,7 +69,21 @@ use super::opcode::Opcode;
 /// | load          | 0x0A          | u8, u8            | Load a local variable onto stack ||
 /// | goto          | 0x0B          | u8, u8, u8, u8    | Jump to target instruction index ||
 /// | nop           | 0x0C          |                   | Do nothing code ||
-/// | func          | 0x0D          | u8, u8, u8, u8    | Create a function and enter function scope | The first 2 bytes indicate index of the function name stored in constant pool, the later 2 bytes indicate parameter size and return stack size, respectively. |
+/// | func          | 0x0D          | u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8 | Create a function and enter function scope | The first 4 bytes indicate index of the function name stored in constant pool, the next byte indicates parameter size, the next byte indicates how many values from the top of stack `return` hands to the caller, the next 4 bytes indicate local variable slots of the function. The next byte indicates how many trailing parameters are optional, the next 4 bytes indicate index of the first optional parameter's default in constant pool, with the others following it. The last byte is 1 if extra parameters are collected into an array passed after the others. `invoke` falls back to a function of the same name accepting its parameter size |
+/// | return        | 0x0E          |                   | Leave current function and push its declared return size of top items onto caller's stack, discarding the rest | Top level code hands its whole stack over as the process result |
+/// | invoke        | 0x0F          | u8, u8, u8, u8, u8 | Consume parameters and call the function, caller resumes at the next instruction once it returns | Operands are the same as `coroutine`. The callee starts with its own uninitialized locals, it can't see the caller's |
+/// | coroutine     | 0x10          | u8, u8, u8, u8, u8 | Consume parameters and push a suspended coroutine of the function | The first 4 bytes indicate index of the function name stored in constant pool, the last byte indicates parameter size. |
+/// | yield         | 0x11          |                   | Pop top item and suspend current coroutine, handing the item to its resumer | Only valid inside a coroutine |
+/// | resume        | 0x12          |                   | Pop a coroutine and run it until it yields or returns, push the yielded item or returned items ||
+/// | done          | 0x13          |                   | Pop a coroutine and push Int 1 if it has finished, otherwise Int 0 ||
+/// | spawn         | 0x14          | u8, u8, u8, u8, u8 | Consume parameters and spawn a process running the function, push its pid | Operands are the same as `coroutine`. Requires a scheduler |
+/// | send          | 0x15          |                   | Pop a message then a pid, and append the message to the process's mailbox | Requires a scheduler |
+/// | receive       | 0x16          |                   | Push the oldest message from current process's mailbox, block until one arrives | Requires a scheduler |
+/// | pid           | 0x17          |                   | Push current process's pid | Requires a scheduler |
+/// | setglobal     | 0x18          | u8, u8, u8, u8    | Pop and store top item to global variable | The 4 bytes indicate index of the variable name stored in constant pool. Globals are shared by every frame and process of the VM |
+/// | getglobal     | 0x19          | u8, u8, u8, u8    | Load a global variable onto stack | *Ditto* |
+/// | tailinvoke    | 0x1A          | u8, u8, u8, u8, u8 | Consume parameters and invoke the function in place of the current one, its results are returned to the current function's caller | Operands are the same as `coroutine`. The rest of the current stack is discarded |
+/// | import        | 0x1B          | u8, u8, u8, u8    | Load the module from the VM's module resolver and run its top level code, unless it is loaded already | The 4 bytes indicate index of the module name stored in constant pool. Calls matching an import of the header invoke the module's exported function afterwards |
 ///
 /// Bytecode manipulation library summary:
 ///
@@ -68,16 +103,48 @@ use super::opcode::Opcode;
 #[derive(Debug)]
 pub struct BytecodeBuilder {
     byte_pool: Vec<u8>,
+    /// Emitted in header once the bytecode is built
+    module: Module,
+    encoding: Encoding,
 }
 
 impl BytecodeBuilder {
     pub fn new() -> Self {
+        Self::with_encoding(Encoding::default())
+    }
+
+    /// Lays out operands of all instructions as `encoding` does. Set once here since the
+    /// header holds a single encoding for the whole code.
+    pub fn with_encoding(encoding: Encoding) -> Self {
         Self {
-            byte_pool: vec![0x47, 0x45, 0x41, 0x52, 0x57, 0x4F, 0x52, 0x4B],
+            byte_pool: vec![],
+            module: Module::default(),
+            encoding,
         }
     }
 
-    pub(crate) fn visit_constant_pool(&mut self) -> ConstantBuilder {
+    pub fn visit_module(&mut self, name: &str) {
+        self.module.name = name.to_string();
+    }
+
+    /// Lets code call `function` of `module` with `parameter_size` parameters.
+    pub fn visit_import(&mut self, module: &str, function: &str, parameter_size: u8) {
+        self.module.imports.push(Import {
+            module: module.to_string(),
+            function: function.to_string(),
+            parameter_size,
+        });
+    }
+
+    /// Offers top level `function` with `parameter_size` parameters to other modules.
+    pub fn visit_export(&mut self, function: &str, parameter_size: u8) {
+        self.module.exports.push(Export {
+            function: function.to_string(),
+            parameter_size,
+        });
+    }
+
+    pub(crate) fn visit_constant_pool(&mut self) -> ConstantBuilder<'_> {
         ConstantBuilder {
             parent_builder: self,
             count: 0,
@@ -85,18 +152,93 @@ impl BytecodeBuilder {
         }
     }
 
-    pub fn visit_code(&mut self) -> InstructionBuilder {
+    pub fn visit_code(&mut self) -> InstructionBuilder<'_> {
         InstructionBuilder {
             parent_builder: self,
             generated_constants: vec![],
             labels: vec![],
             byte_pool: vec![],
             pos: 0,
+            source: None,
+            lines: vec![],
+            max_locals: 0,
+            function_scopes: vec![],
+            slot_counts: vec![],
         }
     }
 
     pub fn visit_end(self) -> Vec<u8> {
-        return self.byte_pool;
+        let mut byte_pool = vec![0x47, 0x45, 0x41, 0x52, 0x57, 0x4F, 0x52, 0x4B];
+
+        byte_pool.push(FORMAT_VERSION);
+        byte_pool.push(self.encoding.flag());
+        writer::write_module(&mut byte_pool, &self.module);
+        byte_pool.extend_from_slice(&self.byte_pool);
+        byte_pool
+    }
+}
+
+/// Version of the bytecode format, written after the magic number. The loader rejects other
+/// versions.
+///
+/// Version 2 gives every called function locals of its own, sized by its `func`. Before, a
+/// function shared its caller's locals, so old code reading them would fault on uninitialized
+/// locals instead. Rebuild such code passing those values as parameters or globals.
+pub const FORMAT_VERSION: u8 = 2;
+
+/// Layout of instruction operands that index constants, instructions or local variables, and
+/// of function slot counts. Flagged by the byte following the format version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Big endian u32, or u16 for local variable indices
+    #[default]
+    Fixed,
+    /// Unsigned LEB128, 7 bits per byte from the lowest ones with the high bit set on every
+    /// byte but the last, so operands below 128 take a single byte
+    Compact,
+}
+
+impl Encoding {
+    pub(crate) fn flag(self) -> u8 {
+        match self {
+            Self::Fixed => 0x00,
+            Self::Compact => 0x01,
+        }
+    }
+
+    pub(crate) fn from_flag(flag: u8) -> Option<Self> {
+        match flag {
+            0x00 => Some(Self::Fixed),
+            0x01 => Some(Self::Compact),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn write_u32(self, byte_pool: &mut Vec<u8>, mut operand: u32) {
+        if self == Self::Fixed {
+            byte_pool.extend_from_slice(&operand.to_be_bytes());
+            return;
+        }
+
+        while operand >= 0x80 {
+            byte_pool.push(operand as u8 | 0x80);
+            operand >>= 7;
+        }
+
+        byte_pool.push(operand as u8);
+    }
+
+    pub(crate) fn write_u16(self, byte_pool: &mut Vec<u8>, operand: u16) {
+        match self {
+            Self::Fixed => byte_pool.extend_from_slice(&operand.to_be_bytes()),
+            Self::Compact => self.write_u32(byte_pool, operand as u32),
+        }
+    }
+}
+
+impl Default for BytecodeBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -132,13 +274,11 @@ impl<'a> ConstantBuilder<'a> {
         self.count += 1;
     }
 
+    /// # Panics
+    /// If the string is 4 GiB or longer, its length would not fit in bytecode.
     pub fn visit_string(&mut self, string: String) {
-        let string_bytes = string.as_bytes();
-
         self.byte_pool.push(0x04);
-        self.byte_pool
-            .extend_from_slice(&string_bytes.len().to_be_bytes());
-        self.byte_pool.extend_from_slice(&string_bytes);
+        writer::write_string(&mut self.byte_pool, &string);
         self.count += 1;
     }
 
@@ -174,6 +314,14 @@ pub struct InstructionBuilder<'a> {
     labels: Vec<(u32, &'a RefCell<Label>)>,
     byte_pool: Vec<u8>,
     pos: u32,
+    source: Option<String>,
+    lines: Vec<(u32, u32)>,
+    /// Local variable slots of top level code
+    max_locals: u32,
+    /// Unclosed functions, as byte offset of their slot count operand and slots used so far
+    function_scopes: Vec<(usize, u32)>,
+    /// Closed functions, as byte offset of their slot count operand and their slots
+    slot_counts: Vec<(usize, u32)>,
 }
 
 impl<'a> InstructionBuilder<'a> {
@@ -181,6 +329,21 @@ impl<'a> InstructionBuilder<'a> {
         self.pos += 1;
     }
 
+    fn visit_operand(&mut self, operand: u32) {
+        self.parent_builder
+            .encoding
+            .write_u32(&mut self.byte_pool, operand);
+    }
+
+    fn use_local(&mut self, index: u16) {
+        let max_locals = match self.function_scopes.last_mut() {
+            Some((_, max_locals)) => max_locals,
+            None => &mut self.max_locals,
+        };
+
+        *max_locals = std::cmp::max(*max_locals, index as u32 + 1);
+    }
+
     pub fn visit_ldc(&mut self, stackable: Stackable) {
         self.byte_pool.push(0x00);
 
@@ -192,13 +355,12 @@ impl<'a> InstructionBuilder<'a> {
         // Check if constant pool has function name
         if let Some(index) = constant_index {
             // Copy the index of function name's constant in constant pool
-            self.byte_pool
-                .extend_from_slice(&(index as u32).to_be_bytes());
+            self.visit_operand(index as u32);
         } else {
             // Generate constant for function name
             let index = self.generated_constants.len() as u32;
             self.generated_constants.push(stackable);
-            self.byte_pool.extend_from_slice(&index.to_be_bytes());
+            self.visit_operand(index);
         }
 
         self.advance();
@@ -246,13 +408,19 @@ impl<'a> InstructionBuilder<'a> {
 
     pub fn visit_store(&mut self, index: u16) {
         self.byte_pool.push(0x09);
-        self.byte_pool.extend_from_slice(&index.to_be_bytes());
+        self.parent_builder
+            .encoding
+            .write_u16(&mut self.byte_pool, index);
+        self.use_local(index);
         self.advance();
     }
 
     pub fn visit_load(&mut self, index: u16) {
         self.byte_pool.push(0x0A);
-        self.byte_pool.extend_from_slice(&index.to_be_bytes());
+        self.parent_builder
+            .encoding
+            .write_u16(&mut self.byte_pool, index);
+        self.use_local(index);
         self.advance();
     }
 
@@ -272,7 +440,7 @@ impl<'a> InstructionBuilder<'a> {
 
     fn visit_goto_labeled(&mut self, label: Label) {
         self.byte_pool.push(0x0B);
-        self.byte_pool.extend_from_slice(&label.pos.to_be_bytes());
+        self.visit_operand(label.pos);
         self.advance();
     }
 
@@ -281,7 +449,28 @@ impl<'a> InstructionBuilder<'a> {
         self.advance();
     }
 
-    pub fn visit_func(&mut self, function_name: &'a str, parameter_size: u8) {
+    pub fn visit_func(&mut self, function_name: &'a str, parameter_size: u8, return_size: u8) {
+        self.visit_func_with_arity(function_name, parameter_size, return_size, &[], false);
+    }
+
+    /// Declares a function whose last `defaults.len()` parameters are optional and take these
+    /// defaults when not passed, and which collects extra arguments into an array passed
+    /// after the other parameters if `rest` is set.
+    pub fn visit_func_with_arity(
+        &mut self,
+        function_name: &'a str,
+        parameter_size: u8,
+        return_size: u8,
+        defaults: &[Stackable],
+        rest: bool,
+    ) {
+        if defaults.len() > parameter_size as usize {
+            panic!(
+                "Function {} has more defaults than parameters",
+                function_name
+            );
+        }
+
         self.byte_pool.push(0x0D);
 
         let constant_index = self.generated_constants.iter().position(|s| match s {
@@ -292,40 +481,181 @@ impl<'a> InstructionBuilder<'a> {
         // Check if constant pool has function name
         if let Some(index) = constant_index {
             // Copy the index of function name's constant in constant pool
-            self.byte_pool
-                .extend_from_slice(&(index as u32).to_be_bytes());
+            self.visit_operand(index as u32);
         } else {
             // Generate constant for function name
             let index = self.generated_constants.len() as u32;
             self.generated_constants
                 .push(Stackable::String(function_name.to_string()));
-            self.byte_pool.extend_from_slice(&index.to_be_bytes());
+            self.visit_operand(index);
         }
 
         self.byte_pool
             .extend_from_slice(&parameter_size.to_be_bytes());
+        self.byte_pool.push(return_size);
+
+        // Slot count is inserted once the function's `return` is visited
+        self.function_scopes.push((self.byte_pool.len(), 0));
+
+        // Defaults take consecutive constants, regardless of existing equal ones
+        let defaults_index = match defaults {
+            [] => 0,
+            _ => self.generated_constants.len() as u32,
+        };
+        self.generated_constants.extend_from_slice(defaults);
+        self.byte_pool.push(defaults.len() as u8);
+        self.visit_operand(defaults_index);
+        self.byte_pool.push(rest as u8);
         self.advance();
     }
 
     pub fn visit_return(&mut self) {
         self.byte_pool.push(0x0E);
+
+        // A function's body ends at its first `return`
+        if let Some(slot_count) = self.function_scopes.pop() {
+            self.slot_counts.push(slot_count);
+        }
+
         self.advance();
     }
 
     pub fn visit_invoke(&mut self, function_name: &'a str, parameter_size: u8) {
+        let index = self.function_constant(function_name, parameter_size);
+
+        self.byte_pool.push(0x0F);
+        self.visit_operand(index);
+        self.byte_pool.push(parameter_size);
+        self.advance();
+    }
+
+    pub fn visit_coroutine(&mut self, function_name: &'a str, parameter_size: u8) {
+        let index = self.function_constant(function_name, parameter_size);
+
+        self.byte_pool.push(0x10);
+        self.visit_operand(index);
+        self.byte_pool.push(parameter_size);
+        self.advance();
+    }
+
+    pub fn visit_yield(&mut self) {
+        self.byte_pool.push(0x11);
+        self.advance();
+    }
+
+    pub fn visit_resume(&mut self) {
+        self.byte_pool.push(0x12);
+        self.advance();
+    }
+
+    pub fn visit_done(&mut self) {
+        self.byte_pool.push(0x13);
+        self.advance();
+    }
+
+    pub fn visit_spawn(&mut self, function_name: &'a str, parameter_size: u8) {
+        let index = self.function_constant(function_name, parameter_size);
+
+        self.byte_pool.push(0x14);
+        self.visit_operand(index);
+        self.byte_pool.push(parameter_size);
+        self.advance();
+    }
+
+    pub fn visit_tailinvoke(&mut self, function_name: &'a str, parameter_size: u8) {
+        let index = self.function_constant(function_name, parameter_size);
+
+        self.byte_pool.push(0x1A);
+        self.visit_operand(index);
+        self.byte_pool.push(parameter_size);
+        self.advance();
+    }
+
+    pub fn visit_import(&mut self, module: &str) {
+        let index = self.name_constant(module);
+
+        self.byte_pool.push(0x1B);
+        self.visit_operand(index);
+        self.advance();
+    }
+
+    pub fn visit_send(&mut self) {
+        self.byte_pool.push(0x15);
+        self.advance();
+    }
+
+    pub fn visit_receive(&mut self) {
+        self.byte_pool.push(0x16);
+        self.advance();
+    }
+
+    pub fn visit_pid(&mut self) {
+        self.byte_pool.push(0x17);
+        self.advance();
+    }
+
+    pub fn visit_setglobal(&mut self, name: &str) {
+        let index = self.name_constant(name);
+
+        self.byte_pool.push(0x18);
+        self.visit_operand(index);
+        self.advance();
+    }
+
+    pub fn visit_getglobal(&mut self, name: &str) {
+        let index = self.name_constant(name);
+
+        self.byte_pool.push(0x19);
+        self.visit_operand(index);
+        self.advance();
+    }
+
+    /// Index of the name constant of a called function, which must be declared before or
+    /// imported.
+    fn function_constant(&mut self, function_name: &str, parameter_size: u8) -> u32 {
         let name_index = self.generated_constants.iter().position(|s| match s {
             Stackable::String(name) => name == function_name,
             _ => false,
         });
+        let imported = self.parent_builder.module.imports.iter().any(|import| {
+            import.function == function_name && import.parameter_size == parameter_size
+        });
 
-        if let Some(index) = name_index {
-            self.byte_pool.push(0x0F);
-            self.byte_pool
-                .extend_from_slice(&(index as u32).to_be_bytes());
-            self.byte_pool.push(parameter_size);
-            self.advance();
-        } else {
-            panic!("Undeclared function name {}", function_name);
+        match name_index {
+            Some(index) => index as u32,
+            None if imported => self.name_constant(function_name),
+            None => panic!("Undeclared function name {}", function_name),
+        }
+    }
+
+    /// Index of the string constant `name`, generated if it doesn't exist yet.
+    fn name_constant(&mut self, name: &str) -> u32 {
+        let constant_index = self.generated_constants.iter().position(|s| match s {
+            Stackable::String(constant) => constant == name,
+            _ => false,
+        });
+
+        match constant_index {
+            Some(index) => index as u32,
+            None => {
+                self.generated_constants
+                    .push(Stackable::String(name.to_string()));
+                (self.generated_constants.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Names the source file instructions are compiled from, emitted as debug info.
+    pub fn visit_source(&mut self, source: &str) {
+        self.source = Some(source.to_string());
+    }
+
+    /// Marks following instructions as compiled from `line` of source, emitted as debug info.
+    pub fn visit_line(&mut self, line: u32) {
+        match self.lines.last_mut() {
+            Some((pos, previous_line)) if *pos == self.pos => *previous_line = line,
+            Some((_, previous_line)) if *previous_line == line => {}
+            _ => self.lines.push((self.pos, line)),
         }
     }
 
@@ -346,36 +676,63 @@ impl<'a> InstructionBuilder<'a> {
             Opcode::Load(index) => self.visit_load(index),
             Opcode::Goto(index) => self.visit_goto_labeled(Label { pos: index }),
             Opcode::Nop => self.visit_nop(),
-            Opcode::Func(_, _) => {
-                unimplemented!("Use InstructionBuilder::visit_func(&'a str, u8) instead")
+            Opcode::Func(_, _, _, _, _) => {
+                unimplemented!("Use InstructionBuilder::visit_func(&'a str, u8, u8) instead")
             }
             Opcode::Return => self.visit_return(),
             Opcode::Invoke(_, _) => {
                 unimplemented!("Use InstructionBuilder::visit_invoke(&'a str) instead")
             }
+            Opcode::Coroutine(_, _) => {
+                unimplemented!("Use InstructionBuilder::visit_coroutine(&'a str, u8) instead")
+            }
+            Opcode::Yield => self.visit_yield(),
+            Opcode::Resume => self.visit_resume(),
+            Opcode::Done => self.visit_done(),
+            Opcode::Spawn(_, _) => {
+                unimplemented!("Use InstructionBuilder::visit_spawn(&'a str, u8) instead")
+            }
+            Opcode::Send => self.visit_send(),
+            Opcode::Receive => self.visit_receive(),
+            Opcode::Pid => self.visit_pid(),
+            Opcode::SetGlobal(_) => {
+                unimplemented!("Use InstructionBuilder::visit_setglobal(&str) instead")
+            }
+            Opcode::GetGlobal(_) => {
+                unimplemented!("Use InstructionBuilder::visit_getglobal(&str) instead")
+            }
+            Opcode::TailInvoke(_, _) => {
+                unimplemented!("Use InstructionBuilder::visit_tailinvoke(&'a str, u8) instead")
+            }
+            Opcode::Import(_) => {
+                unimplemented!("Use InstructionBuilder::visit_import(&str) instead")
+            }
         }
     }
 
     pub fn visit_end(self) {
-        // Insert label position for `goto` opcode
-        let byte_pool = self.byte_pool;
+        // Insert label positions of `goto` opcodes and slot counts of functions, which are
+        // known only now
+        let mut operands = self
+            .labels
+            .iter()
+            .map(|(offset, label)| (*offset as usize, label.borrow().pos))
+            .chain(self.slot_counts)
+            .chain(self.function_scopes)
+            .collect::<Vec<_>>();
+        operands.sort_by_key(|(offset, _)| *offset);
+
+        let encoding = self.parent_builder.encoding;
         let mut final_byte_pool = vec![];
-        let mut previous_index = 0;
-        let labels = self.labels;
-
-        for (index, (start_index, label)) in labels.iter().enumerate() {
-            let range = if index == 0 {
-                0..*start_index as usize
-            } else {
-                previous_index..*start_index as usize
-            };
-            let mut seg = byte_pool[range].to_vec();
-            seg.extend_from_slice(&label.to_owned().borrow().pos.to_be_bytes());
-            final_byte_pool.extend_from_slice(&seg);
-            previous_index = *start_index as usize;
+        let mut previous_offset = 0;
+
+        for (offset, operand) in operands {
+            final_byte_pool.extend_from_slice(&self.byte_pool[previous_offset..offset]);
+            encoding.write_u32(&mut final_byte_pool, operand);
+            previous_offset = offset;
         }
 
-        final_byte_pool.extend_from_slice(&byte_pool[previous_index..]);
+        final_byte_pool.extend_from_slice(&self.byte_pool[previous_offset..]);
 
         // Emit constants
         let mut constant_builder = self.parent_builder.visit_constant_pool();
@@ -387,6 +744,8 @@ impl<'a> InstructionBuilder<'a> {
                 Stackable::Float(float) => constant_builder.visit_float(float),
                 Stackable::Double(double) => constant_builder.visit_double(double),
                 Stackable::String(string) => constant_builder.visit_string(string),
+                Stackable::Coroutine(_) => panic!("Coroutine cannot be a constant"),
+                Stackable::Array(_) => panic!("Array cannot be a constant"),
             }
         }
 
@@ -396,7 +755,24 @@ impl<'a> InstructionBuilder<'a> {
         self.parent_builder
             .byte_pool
             .extend_from_slice(&self.pos.to_be_bytes());
+        self.parent_builder
+            .byte_pool
+            .extend_from_slice(&self.max_locals.to_be_bytes());
         self.parent_builder.byte_pool.append(&mut final_byte_pool);
+
+        // Emit debug info
+        if self.source.is_some() || !self.lines.is_empty() {
+            let source = self.source.unwrap_or_default();
+            let byte_pool = &mut self.parent_builder.byte_pool;
+
+            writer::write_string(byte_pool, &source);
+            byte_pool.extend_from_slice(&(self.lines.len() as u32).to_be_bytes());
+
+            for (pos, line) in self.lines {
+                byte_pool.extend_from_slice(&pos.to_be_bytes());
+                byte_pool.extend_from_slice(&line.to_be_bytes());
+            }
+        }
     }
 }
 
@@ -0,0 +1,188 @@
+use std::{collections::HashSet, fmt::Debug};
+
+use crate::{
+    error::VmError,
+    opcode::Opcode,
+    optimizer::function_end,
+    vm::{Process, Stackable, VM},
+};
+
+/// Instruction compiled with its operands resolved.
+type Step = Box<dyn Fn(&mut Process) -> Result<(), VmError> + Send + Sync>;
+
+/// Basic block compiled into a single closure, returns values once the process finishes.
+pub(crate) type Block =
+    Box<dyn Fn(&mut Process) -> Result<Option<Vec<Stackable>>, VmError> + Send + Sync>;
+
+/// Code of a [`VM`] compiled into closures for the closure backend, see
+/// [`VM::with_backend`](crate::vm::VM::with_backend).
+///
+/// Each basic block becomes a closure running the steps of its straight-line instructions, with
+/// constants resolved to their values, then its `goto` or control instruction.
+pub struct CompiledCode {
+    /// Indexed by position of the block's first instruction
+    blocks: Vec<Option<Block>>,
+}
+
+impl CompiledCode {
+    pub(crate) fn compile(vm: &VM) -> Self {
+        let instructions = vm.code().instructions();
+        let leaders = leaders(instructions);
+        let mut blocks = Vec::with_capacity(instructions.len());
+
+        for pos in 0..instructions.len() {
+            blocks.push(match leaders.contains(&pos) {
+                true => Some(compile_block(vm, instructions, &leaders, pos)),
+                false => None,
+            });
+        }
+
+        Self { blocks }
+    }
+
+    /// Block starting at `pos`, `None` if control can't enter there from elsewhere.
+    pub(crate) fn block(&self, pos: u32) -> Option<&Block> {
+        self.blocks.get(pos as usize)?.as_ref()
+    }
+}
+
+impl Debug for CompiledCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let blocks = self.blocks.iter().filter(|block| block.is_some()).count();
+
+        write!(f, "<{} compiled blocks>", blocks)
+    }
+}
+
+/// Whether the instruction runs as a step, without moving control elsewhere.
+fn is_straight(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Ldc(_)
+            | Opcode::Dump
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Mod
+            | Opcode::Dup
+            | Opcode::Swp
+            | Opcode::Store(_)
+            | Opcode::Load(_)
+            | Opcode::Nop
+            | Opcode::SetGlobal(_)
+            | Opcode::GetGlobal(_)
+    )
+}
+
+/// Positions control can enter from elsewhere: start of code, `goto` targets, and
+/// instructions after control instructions, which include function entries and returns
+/// from invocations. Positions after a function body are entered once `func` skips it.
+fn leaders(instructions: &[Opcode]) -> HashSet<usize> {
+    let mut leaders = HashSet::from([0]);
+
+    for (pos, opcode) in instructions.iter().enumerate() {
+        match opcode {
+            Opcode::Goto(target) => {
+                leaders.insert(*target as usize);
+            }
+            Opcode::Func(_, _, _, _, _) => {
+                leaders.insert(function_end(instructions, pos) + 1);
+            }
+            _ => {}
+        }
+
+        if !is_straight(*opcode) {
+            leaders.insert(pos + 1);
+        }
+    }
+
+    leaders
+}
+
+fn compile_block(
+    vm: &VM,
+    instructions: &[Opcode],
+    leaders: &HashSet<usize>,
+    start: usize,
+) -> Block {
+    let mut steps = vec![];
+    let mut end = start;
+
+    while let Some(opcode) = instructions.get(end) {
+        if !is_straight(*opcode) || (end > start && leaders.contains(&end)) {
+            break;
+        }
+
+        if let Some(step) = compile_step(vm, *opcode) {
+            steps.push(step);
+        }
+
+        end += 1;
+    }
+
+    let end_pos = end as u32;
+
+    // Block ends at the next leader or with the control instruction that ends it
+    let ends_block = !leaders.contains(&end) || end == start;
+
+    match instructions.get(end) {
+        Some(Opcode::Goto(target)) if ends_block => {
+            let target = *target;
+
+            Box::new(move |process| {
+                for step in &steps {
+                    step(process)?;
+                }
+
+                process.goto(target);
+                Ok(None)
+            })
+        }
+        Some(opcode) if !is_straight(*opcode) && ends_block => {
+            // Control instructions run as interpreted
+            Box::new(move |process| {
+                for step in &steps {
+                    step(process)?;
+                }
+
+                process.goto(end_pos);
+                process.execute_instruction()
+            })
+        }
+        _ => Box::new(move |process| {
+            for step in &steps {
+                step(process)?;
+            }
+
+            process.goto(end_pos);
+            Ok(None)
+        }),
+    }
+}
+
+fn compile_step(vm: &VM, opcode: Opcode) -> Option<Step> {
+    Some(match opcode {
+        Opcode::Ldc(index) => match vm.constants().get(index as usize).cloned() {
+            Some(constant) => Box::new(move |process| process.push_value(constant.clone())),
+            None => Box::new(move |_| {
+                Err(VmError::UnknownConstant {
+                    index: index as usize,
+                })
+            }),
+        },
+        Opcode::Dump => Box::new(Process::dump),
+        Opcode::Add => Box::new(Process::add),
+        Opcode::Sub => Box::new(Process::sub),
+        Opcode::Mul => Box::new(Process::mul),
+        Opcode::Div => Box::new(Process::div),
+        Opcode::Mod => Box::new(Process::r#mod),
+        Opcode::Dup => Box::new(Process::dup),
+        Opcode::Swp => Box::new(Process::swp),
+        Opcode::Store(index) => Box::new(move |process| process.store(index as usize)),
+        Opcode::Load(index) => Box::new(move |process| process.load(index as usize)),
+        Opcode::SetGlobal(name_index) => Box::new(move |process| process.setglobal(name_index)),
+        Opcode::GetGlobal(name_index) => Box::new(move |process| process.getglobal(name_index)),
+        _ => return None,
+    })
+}
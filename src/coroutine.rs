@@ -0,0 +1,115 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex, TryLockError},
+};
+
+use crate::{
+    error::VmError,
+    vm::{Process, ProcessStatus, Stackable},
+};
+
+/// Outcome of resuming a [`Coroutine`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoroutineState {
+    /// Coroutine suspended on `yield` and handed this value to the resumer.
+    Yielded(Stackable),
+    /// Coroutine returned from its function with these values.
+    Finished(Vec<Stackable>),
+    /// Coroutine is blocked on `receive`, resume it again once its process has mail.
+    Waiting,
+}
+
+/// A function call with its own frame stack, which suspends on `yield` and continues
+/// where it left off on the next resume.
+pub struct Coroutine {
+    process: Process,
+}
+
+impl Coroutine {
+    pub(crate) fn new(process: Process) -> Self {
+        Self { process }
+    }
+
+    /// Runs the coroutine until it yields or returns, `depth` is the resumer's call depth
+    /// which nested frames are counted on top of.
+    pub(crate) fn resume_at(&mut self, depth: usize) -> Result<CoroutineState, VmError> {
+        if self.is_finished() {
+            return Err(VmError::CoroutineFinished);
+        }
+
+        self.process.set_base_depth(depth);
+
+        loop {
+            match self.process.step() {
+                ProcessStatus::Running => {}
+                ProcessStatus::Yielded => {
+                    if let Some(value) = self.process.take_yielded() {
+                        return Ok(CoroutineState::Yielded(value));
+                    }
+                }
+                ProcessStatus::Finished(values) => {
+                    return Ok(CoroutineState::Finished(values.clone()));
+                }
+                ProcessStatus::Faulted(err) => return Err(err.clone()),
+                ProcessStatus::Waiting => return Ok(CoroutineState::Waiting),
+            }
+        }
+    }
+
+    pub fn resume(&mut self) -> Result<CoroutineState, VmError> {
+        self.resume_at(0)
+    }
+
+    /// Whether the coroutine returned or faulted, a finished coroutine cannot be resumed.
+    pub fn is_finished(&self) -> bool {
+        self.process.status().is_terminated()
+    }
+}
+
+/// Shared handle to a [`Coroutine`], this is how coroutines live on the operand stack.
+#[derive(Clone)]
+pub struct CoroutineRef(Arc<Mutex<Coroutine>>);
+
+impl CoroutineRef {
+    pub(crate) fn new(coroutine: Coroutine) -> Self {
+        Self(Arc::new(Mutex::new(coroutine)))
+    }
+
+    pub(crate) fn resume_at(&self, depth: usize) -> Result<CoroutineState, VmError> {
+        match self.0.try_lock() {
+            Ok(mut coroutine) => coroutine.resume_at(depth),
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().resume_at(depth),
+            Err(TryLockError::WouldBlock) => Err(VmError::CoroutineRunning),
+        }
+    }
+
+    /// Resumes the coroutine from the host.
+    pub fn resume(&self) -> Result<CoroutineState, VmError> {
+        self.resume_at(0)
+    }
+
+    /// Whether the coroutine can no longer be resumed, a running coroutine is not finished.
+    pub fn is_finished(&self) -> bool {
+        match self.0.try_lock() {
+            Ok(coroutine) => coroutine.is_finished(),
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().is_finished(),
+            Err(TryLockError::WouldBlock) => false,
+        }
+    }
+}
+
+impl PartialEq for CoroutineRef {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Debug for CoroutineRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_finished() {
+            f.write_str("<coroutine finished>")
+        } else {
+            f.write_str("<coroutine>")
+        }
+    }
+}
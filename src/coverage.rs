@@ -0,0 +1,115 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{opcode::Opcode, vm::VM};
+
+/// Hit counts per instruction position, recorded by a process with coverage enabled, see
+/// [`Process::enable_coverage`](crate::vm::Process::enable_coverage).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    hits: Vec<u64>,
+}
+
+impl Coverage {
+    pub(crate) fn new(instructions_size: usize) -> Self {
+        Self {
+            hits: vec![0; instructions_size],
+        }
+    }
+
+    pub(crate) fn hit(&mut self, pos: u32) {
+        if let Some(hits) = self.hits.get_mut(pos as usize) {
+            *hits += 1;
+        }
+    }
+
+    /// Hit counts indexed by instruction position.
+    pub fn hits(&self) -> &[u64] {
+        &self.hits
+    }
+
+    /// Adds hit counts of another run of the same code.
+    pub fn merge(&mut self, other: &Coverage) {
+        if self.hits.len() < other.hits.len() {
+            self.hits.resize(other.hits.len(), 0);
+        }
+
+        for (hits, other_hits) in self.hits.iter_mut().zip(&other.hits) {
+            *hits += other_hits;
+        }
+    }
+
+    /// Report in lcov tracefile format. Instructions map onto source lines through the VM's
+    /// debug info, without it line `n` is the instruction at `n - 1` of `source`.
+    pub fn to_lcov(&self, vm: &VM, source: &str) -> String {
+        let debug_info = vm.debug_info();
+        let line_of = |pos: u32| match debug_info {
+            Some(debug_info) => debug_info.line_of(pos),
+            None => Some(pos + 1),
+        };
+        let hits_at = |pos: usize| self.hits.get(pos).copied().unwrap_or_default();
+
+        let mut report = String::new();
+        let source = debug_info
+            .map(|debug_info| debug_info.source())
+            .filter(|source| !source.is_empty())
+            .unwrap_or(source);
+
+        let _ = writeln!(report, "TN:");
+        let _ = writeln!(report, "SF:{}", source);
+
+        // Functions are entered at the instruction after their `func`
+        let mut functions = vec![];
+
+        for (pos, opcode) in vm.code().instructions().iter().enumerate() {
+            if let Opcode::Func(name_index, parameter_size, _, _, _) = opcode {
+                let name = match vm.constants().get(*name_index as usize) {
+                    Some(name) => format!("{:?}/{}", name, parameter_size),
+                    None => format!("<Unknown function name>/{}", parameter_size),
+                };
+
+                if let Some(line) = line_of(pos as u32) {
+                    functions.push((line, name, hits_at(pos + 1)));
+                }
+            }
+        }
+
+        for (line, name, _) in &functions {
+            let _ = writeln!(report, "FN:{},{}", line, name);
+        }
+
+        for (_, name, hits) in &functions {
+            let _ = writeln!(report, "FNDA:{},{}", hits, name);
+        }
+
+        let _ = writeln!(report, "FNF:{}", functions.len());
+        let _ = writeln!(
+            report,
+            "FNH:{}",
+            functions.iter().filter(|(_, _, hits)| *hits > 0).count()
+        );
+
+        // A line is as hit as its most executed instruction
+        let mut lines = BTreeMap::new();
+
+        for pos in 0..vm.code().instructions().len() {
+            if let Some(line) = line_of(pos as u32) {
+                let line_hits = lines.entry(line).or_insert(0);
+                *line_hits = std::cmp::max(*line_hits, hits_at(pos));
+            }
+        }
+
+        for (line, hits) in &lines {
+            let _ = writeln!(report, "DA:{},{}", line, hits);
+        }
+
+        let _ = writeln!(report, "LF:{}", lines.len());
+        let _ = writeln!(
+            report,
+            "LH:{}",
+            lines.values().filter(|hits| **hits > 0).count()
+        );
+        let _ = writeln!(report, "end_of_record");
+
+        report
+    }
+}
@@ -0,0 +1,459 @@
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    debugger::{Debugger, StopReason},
+    vm::{Process, ProcessStatus},
+    Loader,
+};
+
+/// The only thread a debugged process has.
+const THREAD_ID: i64 = 1;
+
+/// Debug Adapter Protocol server speaking over a reader and writer, usually stdin and stdout,
+/// which maps requests onto a [`Debugger`].
+///
+/// Source lines map onto instruction positions through the program's debug info, without it
+/// line `n` is the instruction at `n - 1`.
+///
+/// Running requests block until the program stops, so `pause` is answered with an error.
+pub struct DapServer<R, W> {
+    input: R,
+    output: W,
+    seq: i64,
+    program: Option<String>,
+    debugger: Option<Debugger>,
+    /// Requested breakpoint lines, applied once the program is launched
+    breakpoint_lines: Vec<u64>,
+    console: Arc<Mutex<Vec<u8>>>,
+    stop_on_entry: bool,
+    configured: bool,
+    started: bool,
+}
+
+impl<R: BufRead, W: Write> DapServer<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            seq: 1,
+            program: None,
+            debugger: None,
+            breakpoint_lines: Vec::new(),
+            console: Arc::new(Mutex::new(Vec::new())),
+            stop_on_entry: false,
+            configured: false,
+            started: false,
+        }
+    }
+
+    /// Serves requests until `disconnect` or end of input.
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_message()? {
+            if request["type"] != "request" {
+                continue;
+            }
+
+            let command = request["command"].as_str().unwrap_or_default().to_string();
+            let arguments = &request["arguments"];
+
+            match self.handle(&command, arguments) {
+                Ok(body) => self.respond(&request, true, None, body)?,
+                Err(message) => self.respond(&request, false, Some(message), Value::Null)?,
+            }
+
+            match command.as_str() {
+                "initialize" => self.send_event("initialized", Value::Null)?,
+                "disconnect" => return Ok(()),
+                "launch" | "configurationDone" => self.start()?,
+                "continue" => self.resume(|debugger| debugger.cont())?,
+                "next" => self.resume(|debugger| debugger.step_over())?,
+                "stepIn" => self.resume(|debugger| debugger.step_into())?,
+                "stepOut" => self.resume(|debugger| debugger.step_out())?,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, command: &str, arguments: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+            })),
+            "launch" => {
+                let program = arguments["program"]
+                    .as_str()
+                    .ok_or("Missing `program` argument")?;
+                let bytecode = fs::read(program)
+                    .map_err(|err| format!("Unable to read {}: {}", program, err))?;
+                let vm = Loader::new(&bytecode)
+                    .try_load()
+                    .map_err(|err| format!("Unable to load {}: {}", program, err))?;
+                let mut process = Process::new_process(vm, 0);
+                process.set_output(self.console.clone());
+
+                self.program = Some(program.to_string());
+                self.debugger = Some(Debugger::new(process));
+                self.apply_breakpoints();
+                self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => {
+                let lines = arguments["breakpoints"]
+                    .as_array()
+                    .map(|breakpoints| {
+                        breakpoints
+                            .iter()
+                            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+
+                self.breakpoint_lines = lines;
+
+                let verified = self.apply_breakpoints();
+                let breakpoints = self
+                    .breakpoint_lines
+                    .iter()
+                    .zip(verified)
+                    .map(|(line, verified)| json!({ "verified": verified, "line": line }))
+                    .collect::<Vec<_>>();
+
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "configurationDone" => {
+                self.configured = true;
+
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({
+                "threads": [{ "id": THREAD_ID, "name": "main" }],
+            })),
+            "stackTrace" => {
+                let debugger = self.debugger()?;
+                let source = self.source();
+                let frames = debugger
+                    .call_stack()
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(id, frame)| {
+                        json!({
+                            "id": id,
+                            "name": debugger.process().vm().function_name(frame.function()),
+                            "source": source,
+                            "line": line_of(debugger, frame.pos()),
+                            "column": 1,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+            }
+            "scopes" => {
+                let frame_id = arguments["frameId"].as_u64().unwrap_or_default();
+
+                Ok(json!({
+                    "scopes": [
+                        {
+                            "name": "Locals",
+                            "variablesReference": frame_id * 2 + 1,
+                            "expensive": false,
+                        },
+                        {
+                            "name": "Operand Stack",
+                            "variablesReference": frame_id * 2 + 2,
+                            "expensive": false,
+                        },
+                    ],
+                }))
+            }
+            "variables" => {
+                let reference = arguments["variablesReference"].as_u64().unwrap_or_default();
+                let frame = reference
+                    .checked_sub(1)
+                    .and_then(|reference| {
+                        self.debugger
+                            .as_ref()?
+                            .call_stack()
+                            .get((reference / 2) as usize)
+                    })
+                    .ok_or("Unknown variables reference")?;
+
+                let variables = if reference % 2 == 1 {
+                    frame
+                        .local_variable()
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, value)| Some((index, value.as_ref()?)))
+                        .map(|(index, value)| variable(index.to_string(), format!("{:?}", value)))
+                        .collect::<Vec<_>>()
+                } else {
+                    frame
+                        .stack()
+                        .iter()
+                        .enumerate()
+                        .map(|(index, value)| {
+                            variable(format!("[{}]", index), format!("{:?}", value))
+                        })
+                        .collect::<Vec<_>>()
+                };
+
+                Ok(json!({ "variables": variables }))
+            }
+            "continue" => {
+                self.debugger()?;
+
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.debugger()?;
+
+                Ok(Value::Null)
+            }
+            // Requests are only read while the program is stopped, there is nothing to pause
+            "pause" => {
+                Err("Pausing is unsupported, the program runs until its next stop".to_string())
+            }
+            "disconnect" => Ok(Value::Null),
+            _ => Err(format!("Unsupported request {}", command)),
+        }
+    }
+
+    /// Sets requested breakpoints on the launched program, returns whether each line maps
+    /// onto instructions. Lines are unverified until launch.
+    fn apply_breakpoints(&mut self) -> Vec<bool> {
+        let debugger = match &mut self.debugger {
+            Some(debugger) => debugger,
+            None => return vec![false; self.breakpoint_lines.len()],
+        };
+
+        debugger.clear_breakpoints();
+
+        let instructions_size = debugger.process().vm().code().instructions().len() as u64;
+
+        self.breakpoint_lines
+            .iter()
+            .map(|line| {
+                if debugger.process().vm().debug_info().is_some() {
+                    debugger.add_line_breakpoint(*line as u32)
+                } else if *line > 0 && *line <= instructions_size {
+                    debugger.add_breakpoint((*line - 1) as u32);
+                    true
+                } else {
+                    false
+                }
+            })
+            .collect()
+    }
+
+    fn debugger(&self) -> Result<&Debugger, String> {
+        self.debugger
+            .as_ref()
+            .ok_or_else(|| "No program launched".to_string())
+    }
+
+    /// Source named by the program's debug info, the launched bytecode without it.
+    fn source(&self) -> Value {
+        let source = self
+            .debugger
+            .as_ref()
+            .and_then(|debugger| debugger.process().vm().debug_info())
+            .map(|debug_info| debug_info.source())
+            .filter(|source| !source.is_empty());
+
+        match (source, &self.program) {
+            (Some(source), _) => json!({ "path": source }),
+            (None, Some(program)) => json!({ "path": program }),
+            (None, None) => Value::Null,
+        }
+    }
+
+    /// Starts running once the program is launched and configuration is done.
+    fn start(&mut self) -> io::Result<()> {
+        if self.started || !self.configured || self.debugger.is_none() {
+            return Ok(());
+        }
+
+        self.started = true;
+
+        if self.stop_on_entry {
+            self.send_stopped("entry")
+        } else {
+            self.resume(|debugger| debugger.cont())
+        }
+    }
+
+    fn resume(&mut self, action: impl FnOnce(&mut Debugger) -> StopReason) -> io::Result<()> {
+        let reason = match &mut self.debugger {
+            Some(debugger) => action(debugger),
+            None => return Ok(()),
+        };
+
+        self.flush_console()?;
+
+        match reason {
+            StopReason::Breakpoint(_) => self.send_stopped("breakpoint"),
+            StopReason::Step => self.send_stopped("step"),
+            StopReason::Waiting => self.send_stopped("waiting"),
+            StopReason::Terminated(status) => {
+                let exit_code = match status {
+                    ProcessStatus::Faulted(err) => {
+                        self.send_event(
+                            "output",
+                            json!({ "category": "stderr", "output": format!("{}\n", err) }),
+                        )?;
+
+                        1
+                    }
+                    _ => 0,
+                };
+
+                self.send_event("exited", json!({ "exitCode": exit_code }))?;
+                self.send_event("terminated", Value::Null)
+            }
+        }
+    }
+
+    fn flush_console(&mut self) -> io::Result<()> {
+        let output = std::mem::take(
+            &mut *self
+                .console
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+
+        if output.is_empty() {
+            return Ok(());
+        }
+
+        self.send_event(
+            "output",
+            json!({
+                "category": "stdout",
+                "output": String::from_utf8_lossy(&output),
+            }),
+        )
+    }
+
+    fn send_stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.send_event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        )
+    }
+
+    fn respond(
+        &mut self,
+        request: &Value,
+        success: bool,
+        message: Option<String>,
+        body: Value,
+    ) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": success,
+            "command": request["command"],
+        });
+
+        if let Some(message) = message {
+            response["message"] = json!(message);
+        }
+
+        if !body.is_null() {
+            response["body"] = body;
+        }
+
+        self.write_message(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+
+        if !body.is_null() {
+            message["body"] = body;
+        }
+
+        self.write_message(message)
+    }
+
+    fn write_message(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+
+        let content = message.to_string();
+
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            content.len(),
+            content
+        )?;
+        self.output.flush()
+    }
+
+    /// Reads a `Content-Length` framed message, `None` at end of input.
+    fn read_message(&mut self) -> io::Result<Option<Value>> {
+        let mut content_length = None;
+
+        loop {
+            let mut header = String::new();
+
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+
+            let header = header.trim();
+
+            if header.is_empty() {
+                if content_length.is_some() {
+                    break;
+                }
+
+                continue;
+            }
+
+            if let Some(length) = header.strip_prefix("Content-Length:") {
+                content_length = length.trim().parse::<usize>().ok();
+            }
+        }
+
+        let mut content = vec![0; content_length.unwrap()];
+        self.input.read_exact(&mut content)?;
+
+        serde_json::from_slice(&content)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// Source line of the instruction at `pos`, positions before the first line entry belong to
+/// the first line.
+fn line_of(debugger: &Debugger, pos: u32) -> u32 {
+    let debug_info = debugger.process().vm().debug_info();
+
+    debug_info
+        .and_then(|debug_info| {
+            debug_info
+                .line_of(pos)
+                .or_else(|| debug_info.lines().first().map(|(_, line)| *line))
+        })
+        .unwrap_or(pos + 1)
+}
+
+fn variable(name: String, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
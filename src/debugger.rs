@@ -0,0 +1,315 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    opcode::Opcode,
+    vm::{Frame, Process, ProcessStatus},
+};
+
+const HELP: &str = "\
+break <pos>     Set breakpoint at instruction position
+line <line>     Set breakpoint at source line, requires debug info
+delete <pos>    Remove breakpoint
+continue        Run until breakpoint or termination
+step            Step one instruction, into functions
+next            Step one instruction, over functions
+finish          Run until current function returns
+stack           Print operand stack
+locals          Print local variables
+backtrace       Print call stack
+list            Print instructions around current one
+quit            Exit debugger
+";
+
+/// Why a [`Debugger`] handed control back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// About to execute the instruction at this breakpoint.
+    Breakpoint(u32),
+    /// Requested step completed.
+    Step,
+    /// Process is blocked on `receive` until its mailbox gets a message, stepping again
+    /// retries the instruction.
+    Waiting,
+    /// Process can no longer run.
+    Terminated(ProcessStatus),
+}
+
+/// Drives a [`Process`] with breakpoints and stepping, and exposes its state for inspection.
+pub struct Debugger {
+    process: Process,
+    breakpoints: BTreeSet<u32>,
+    /// Whether an instruction ran yet, before that a breakpoint at the first one stops `cont`
+    started: bool,
+}
+
+impl Debugger {
+    pub fn new(process: Process) -> Self {
+        Self {
+            process,
+            breakpoints: BTreeSet::new(),
+            started: false,
+        }
+    }
+
+    pub fn process(&self) -> &Process {
+        &self.process
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u32> {
+        &self.breakpoints
+    }
+
+    /// Returns false if there is already a breakpoint at `pos`.
+    pub fn add_breakpoint(&mut self, pos: u32) -> bool {
+        self.breakpoints.insert(pos)
+    }
+
+    /// Returns false if there is no breakpoint at `pos`.
+    pub fn remove_breakpoint(&mut self, pos: u32) -> bool {
+        self.breakpoints.remove(&pos)
+    }
+
+    /// Sets breakpoints at every instruction range compiled from source `line`, returns
+    /// false if the VM has no debug info or nothing was compiled from the line.
+    pub fn add_line_breakpoint(&mut self, line: u32) -> bool {
+        let positions = self
+            .process
+            .vm()
+            .debug_info()
+            .map(|debug_info| debug_info.positions_of(line))
+            .unwrap_or_default();
+
+        for pos in &positions {
+            self.add_breakpoint(*pos);
+        }
+
+        !positions.is_empty()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Runs until a breakpoint is reached or the process terminates. Stops right away at a
+    /// breakpoint on the first instruction if nothing ran yet.
+    pub fn cont(&mut self) -> StopReason {
+        if !self.started {
+            if let Some(pos) = self.breakpoint_hit() {
+                self.started = true;
+                return StopReason::Breakpoint(pos);
+            }
+        }
+
+        self.run_while(|_| true)
+    }
+
+    /// Executes one instruction, entering invoked functions.
+    pub fn step_into(&mut self) -> StopReason {
+        self.step_instruction().unwrap_or(StopReason::Step)
+    }
+
+    /// Executes one instruction of the current frame, running invoked functions through.
+    pub fn step_over(&mut self) -> StopReason {
+        let depth = self.process.frames().len();
+
+        self.run_while(|process| process.frames().len() > depth)
+    }
+
+    /// Runs until the current frame returns.
+    pub fn step_out(&mut self) -> StopReason {
+        let depth = self.process.frames().len();
+
+        self.run_while(|process| process.frames().len() >= depth)
+    }
+
+    /// Steps once, then keeps stepping while `predicate` holds and no breakpoint is hit.
+    fn run_while(&mut self, predicate: impl Fn(&Process) -> bool) -> StopReason {
+        if let Some(reason) = self.step_instruction() {
+            return reason;
+        }
+
+        while predicate(&self.process) {
+            if let Some(pos) = self.breakpoint_hit() {
+                return StopReason::Breakpoint(pos);
+            }
+
+            if let Some(reason) = self.step_instruction() {
+                return reason;
+            }
+        }
+
+        match self.breakpoint_hit() {
+            Some(pos) => StopReason::Breakpoint(pos),
+            None => StopReason::Step,
+        }
+    }
+
+    fn step_instruction(&mut self) -> Option<StopReason> {
+        self.started = true;
+
+        match self.process.step() {
+            ProcessStatus::Running | ProcessStatus::Yielded => None,
+            ProcessStatus::Waiting => Some(StopReason::Waiting),
+            status => Some(StopReason::Terminated(status.clone())),
+        }
+    }
+
+    fn breakpoint_hit(&self) -> Option<u32> {
+        self.process
+            .root_pos()
+            .filter(|pos| self.breakpoints.contains(pos))
+    }
+
+    /// Call stack, outermost frame first.
+    pub fn call_stack(&self) -> &[Frame] {
+        self.process.frames()
+    }
+
+    /// Innermost frame, `None` once the process finished.
+    pub fn current_frame(&self) -> Option<&Frame> {
+        self.process.frames().last()
+    }
+
+    /// Instruction at `pos` with its constant operand resolved, e.g. `3: Invoke(1, 1) ; add/1`.
+    pub fn describe(&self, pos: u32) -> Option<String> {
+        let vm = self.process.vm();
+        let opcode = vm.code().instructions().get(pos as usize)?;
+        let comment = match opcode {
+            Opcode::Ldc(index) => vm
+                .constants()
+                .get(*index as usize)
+                .map(|c| format!("{:?}", c)),
+            Opcode::SetGlobal(index) | Opcode::GetGlobal(index) | Opcode::Import(index) => vm
+                .constants()
+                .get(*index as usize)
+                .map(|name| format!("{:?}", name)),
+            Opcode::Func(index, size, _, _, _)
+            | Opcode::Invoke(index, size)
+            | Opcode::Coroutine(index, size)
+            | Opcode::Spawn(index, size)
+            | Opcode::TailInvoke(index, size) => vm
+                .constants()
+                .get(*index as usize)
+                .map(|name| format!("{:?}/{}", name, size)),
+            _ => None,
+        };
+
+        Some(match comment {
+            Some(comment) => format!("{}: {:?} ; {}", pos, opcode, comment),
+            None => format!("{}: {:?}", pos, opcode),
+        })
+    }
+
+    /// Reads commands from `input` until it ends or `quit` is entered, see `help` for commands.
+    pub fn prompt(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "(cwdb) ")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            let mut args = line.split_whitespace();
+
+            match (args.next(), args.next()) {
+                (Some("quit" | "q"), _) => return Ok(()),
+                (Some("help" | "h"), _) => write!(output, "{}", HELP)?,
+                (Some("break" | "b"), Some(pos)) => match pos.parse() {
+                    Ok(pos) => {
+                        self.add_breakpoint(pos);
+                        writeln!(output, "Breakpoint at {}", pos)?;
+                    }
+                    Err(_) => writeln!(output, "Invalid position {}", pos)?,
+                },
+                (Some("line"), Some(line)) => match line.parse() {
+                    Ok(line) if self.add_line_breakpoint(line) => {
+                        writeln!(output, "Breakpoint at line {}", line)?
+                    }
+                    _ => writeln!(output, "No instructions at line {}", line)?,
+                },
+                (Some("delete" | "d"), Some(pos)) => match pos.parse() {
+                    Ok(pos) if self.remove_breakpoint(pos) => {
+                        writeln!(output, "Deleted breakpoint at {}", pos)?
+                    }
+                    _ => writeln!(output, "No breakpoint at {}", pos)?,
+                },
+                (Some("continue" | "c"), _) => {
+                    let reason = self.cont();
+                    self.report(&reason, &mut output)?;
+                }
+                (Some("step" | "s"), _) => {
+                    let reason = self.step_into();
+                    self.report(&reason, &mut output)?;
+                }
+                (Some("next" | "n"), _) => {
+                    let reason = self.step_over();
+                    self.report(&reason, &mut output)?;
+                }
+                (Some("finish" | "f"), _) => {
+                    let reason = self.step_out();
+                    self.report(&reason, &mut output)?;
+                }
+                (Some("stack"), _) => {
+                    if let Some(frame) = self.current_frame() {
+                        writeln!(output, "{:?}", frame.stack())?;
+                    }
+                }
+                (Some("locals"), _) => {
+                    if let Some(frame) = self.current_frame() {
+                        for (index, value) in frame.local_variable().iter().enumerate() {
+                            if let Some(value) = value {
+                                writeln!(output, "{} = {:?}", index, value)?;
+                            }
+                        }
+                    }
+                }
+                (Some("backtrace" | "bt"), _) => {
+                    for (depth, frame) in self.call_stack().iter().enumerate().rev() {
+                        writeln!(
+                            output,
+                            "#{} {} at {}",
+                            depth,
+                            self.process.vm().function_name(frame.function()),
+                            frame.pos()
+                        )?;
+                    }
+                }
+                (Some("list" | "l"), _) => {
+                    if let Some(pos) = self.process.pos() {
+                        for line_pos in pos.saturating_sub(3)..pos + 4 {
+                            if let Some(line) = self.describe(line_pos) {
+                                let marker = if line_pos == pos { "=>" } else { "  " };
+                                writeln!(output, "{} {}", marker, line)?;
+                            }
+                        }
+                    }
+                }
+                (Some(command), _) => writeln!(output, "Unknown command {}, try `help`", command)?,
+                (None, _) => {}
+            }
+
+            write!(output, "(cwdb) ")?;
+            output.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn report(&self, reason: &StopReason, output: &mut impl Write) -> io::Result<()> {
+        match reason {
+            StopReason::Breakpoint(pos) => writeln!(output, "Breakpoint hit at {}", pos)?,
+            StopReason::Step => {}
+            StopReason::Waiting => writeln!(output, "Process is waiting for a message")?,
+            StopReason::Terminated(status) => {
+                return writeln!(output, "Process terminated: {:?}", status);
+            }
+        }
+
+        if let Some(line) = self.process.pos().and_then(|pos| self.describe(pos)) {
+            writeln!(output, "=> {}", line)?;
+        }
+
+        Ok(())
+    }
+}
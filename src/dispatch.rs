@@ -0,0 +1,41 @@
+use crate::opcode::Opcode;
+
+/// Pre-decoded instruction, see [`decode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Instruction {
+    /// Executed as the plain opcode at the same position
+    Plain,
+    /// `load a; load b; <arithmetic>`
+    LoadLoadArith(u16, u16, Opcode),
+    /// `ldc c; <arithmetic>`
+    LdcArith(u32, Opcode),
+}
+
+/// Translates code into pre-decoded instructions at the same positions, fusing common runs
+/// into superinstructions that start at the first one. Jumps into the middle of a fused run
+/// still land on the plain instructions.
+///
+/// Only the two runs of [`Instruction`] are fused, everything else runs through the plain
+/// `Opcode` match. Jump targets are instruction positions already, and there is no
+/// conditional jump to fuse with a comparison. `benches/dispatch.rs` compares fused dispatch
+/// with single steps and the other backends.
+pub(crate) fn decode(instructions: &[Opcode]) -> Vec<Instruction> {
+    (0..instructions.len())
+        .map(|pos| match instructions[pos..] {
+            [Opcode::Load(a), Opcode::Load(b), arithmetic, ..] if is_arithmetic(arithmetic) => {
+                Instruction::LoadLoadArith(a, b, arithmetic)
+            }
+            [Opcode::Ldc(index), arithmetic, ..] if is_arithmetic(arithmetic) => {
+                Instruction::LdcArith(index, arithmetic)
+            }
+            _ => Instruction::Plain,
+        })
+        .collect()
+}
+
+fn is_arithmetic(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod
+    )
+}
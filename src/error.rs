@@ -0,0 +1,359 @@
+use std::fmt::Display;
+
+use crate::module::{Export, Import};
+
+/// Errors raised by a running [`Process`](crate::vm::Process).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// Operand stack would grow beyond [`VmLimits::max_stack_size`](crate::vm::VmLimits).
+    StackOverflow { limit: usize },
+    /// Local variable slot is outside the frame's declared slots, which are capped at
+    /// [`VmLimits::max_locals`](crate::vm::VmLimits).
+    LocalsExceeded { index: usize, limit: usize },
+    /// Nested invocation would go deeper than [`VmLimits::max_call_depth`](crate::vm::VmLimits).
+    CallDepthExceeded { limit: usize },
+    /// String value is longer than [`VmLimits::max_string_length`](crate::vm::VmLimits).
+    StringTooLong { length: usize, limit: usize },
+    /// Live values would take more than [`VmLimits::max_heap_bytes`](crate::vm::VmLimits).
+    HeapExhausted { requested: usize, limit: usize },
+    /// Operand stack holds fewer items than an instruction consumes.
+    StackUnderflow {
+        required_size: usize,
+        actual_size: usize,
+    },
+    /// `ldc` refers outside of the constant pool.
+    UnknownConstant { index: usize },
+    /// `load` reads a local variable that was never stored.
+    UninitializedLocal { index: usize },
+    /// `invoke` refers to a function that is not declared in scope.
+    UnknownFunction { name: String, parameter_size: u8 },
+    /// `invoke` passes a number of parameters no function of the name in scope accepts,
+    /// `candidates` are the parameter sizes each of them accepts.
+    ArityMismatch {
+        name: String,
+        parameter_size: u8,
+        candidates: Vec<String>,
+    },
+    /// Function declares more optional parameters than parameters.
+    InvalidArity {
+        name: String,
+        parameter_size: u8,
+        optional: u8,
+    },
+    /// Arithmetic operand is not Int, Long, Float or Double.
+    InvalidOperand { value: String },
+    /// `yield` executed outside of a coroutine.
+    YieldOutsideCoroutine,
+    /// Resumed a coroutine that already returned.
+    CoroutineFinished,
+    /// Resumed a coroutine from within itself.
+    CoroutineRunning,
+    /// `spawn`, `send`, `receive` or `pid` executed by a process without a scheduler.
+    NoScheduler,
+    /// `send` targets a pid that was never spawned.
+    UnknownProcess { pid: u32 },
+    /// `getglobal` reads a global variable that was never set.
+    UndefinedGlobal { name: String },
+    /// `import` executed by a VM without a [`ModuleResolver`](crate::module::ModuleResolver).
+    NoModuleResolver,
+    /// Module resolver has no bytecode for the imported module.
+    UnknownModule { name: String },
+    /// Module resolver returned bytecode the loader rejects.
+    MalformedModule { name: String, reason: LoadError },
+    /// Called an imported function before `import` loaded its module.
+    ModuleNotLoaded { name: String },
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StackOverflow { limit } => {
+                write!(f, "Stack overflow: operand stack exceeded {} items", limit)
+            }
+            Self::LocalsExceeded { index, limit } => write!(
+                f,
+                "Unable to access local variable {}: frame has {} local slots",
+                index, limit
+            ),
+            Self::CallDepthExceeded { limit } => {
+                write!(f, "Call depth exceeded VM's limit of {} frames", limit)
+            }
+            Self::StringTooLong { length, limit } => write!(
+                f,
+                "String of {} bytes exceeded VM's limit of {} bytes",
+                length, limit
+            ),
+            Self::HeapExhausted { requested, limit } => write!(
+                f,
+                "Heap exhausted: {} bytes requested but VM allows {} bytes",
+                requested, limit
+            ),
+            Self::StackUnderflow {
+                required_size,
+                actual_size,
+            } => write!(
+                f,
+                "Stack underflow: requires {}+ items on stack but got {}",
+                required_size, actual_size
+            ),
+            Self::UnknownConstant { index } => {
+                write!(f, "Unable to load constant at index {}", index)
+            }
+            Self::UninitializedLocal { index } => {
+                write!(f, "Unable to load uninitialized local variable {}", index)
+            }
+            Self::UnknownFunction {
+                name,
+                parameter_size,
+            } => write!(
+                f,
+                "Unknown function {} with {} parameters",
+                name, parameter_size
+            ),
+            Self::ArityMismatch {
+                name,
+                parameter_size,
+                candidates,
+            } => write!(
+                f,
+                "Function {} does not take {} parameters, candidates take {}",
+                name,
+                parameter_size,
+                candidates.join(", ")
+            ),
+            Self::InvalidArity {
+                name,
+                parameter_size,
+                optional,
+            } => write!(
+                f,
+                "Function {} declares {} optional parameters but takes only {}",
+                name, optional, parameter_size
+            ),
+            Self::InvalidOperand { value } => write!(
+                f,
+                "Invalid operand {}, operands must be Int, Long, Float or Double",
+                value
+            ),
+            Self::YieldOutsideCoroutine => f.write_str("Unable to yield outside of a coroutine"),
+            Self::CoroutineFinished => f.write_str("Unable to resume a finished coroutine"),
+            Self::CoroutineRunning => f.write_str("Unable to resume a running coroutine"),
+            Self::NoScheduler => f.write_str("Process is not running on a scheduler"),
+            Self::UnknownProcess { pid } => write!(f, "Unknown process {}", pid),
+            Self::UndefinedGlobal { name } => {
+                write!(f, "Unable to get undefined global variable {}", name)
+            }
+            Self::NoModuleResolver => f.write_str("VM has no module resolver to import from"),
+            Self::UnknownModule { name } => write!(f, "Unable to resolve module {}", name),
+            Self::MalformedModule { name, reason } => {
+                write!(f, "Unable to load module {}: {}", name, reason)
+            }
+            Self::ModuleNotLoaded { name } => {
+                write!(f, "Module {} is called before it is imported", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Errors found by [`verify`](crate::verifier::verify) before code runs, at instruction `pos`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// Instruction consumes more items than the stack holds on some path.
+    StackUnderflow {
+        pos: u32,
+        required_size: usize,
+        actual_size: usize,
+    },
+    /// Paths reaching the instruction leave different stack depths.
+    InconsistentStack {
+        pos: u32,
+        depth: usize,
+        other_depth: usize,
+    },
+    /// `return` leaves fewer items than the function declares to return.
+    MissingReturnValues {
+        pos: u32,
+        return_size: u8,
+        actual_size: usize,
+    },
+    /// `tailinvoke` calls a function declaring a different return size than the one it
+    /// replaces.
+    ReturnSizeMismatch {
+        pos: u32,
+        return_size: u8,
+        callee_return_size: u8,
+    },
+    /// Call refers to a function that no `func` declares with an arity accepting it.
+    UnknownFunction {
+        pos: u32,
+        name: String,
+        parameter_size: u8,
+    },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StackUnderflow {
+                pos,
+                required_size,
+                actual_size,
+            } => write!(
+                f,
+                "Stack underflow at {}: requires {}+ items on stack but got {}",
+                pos, required_size, actual_size
+            ),
+            Self::InconsistentStack {
+                pos,
+                depth,
+                other_depth,
+            } => write!(
+                f,
+                "Inconsistent stack at {}: reached with {} and {} items on stack",
+                pos, depth, other_depth
+            ),
+            Self::MissingReturnValues {
+                pos,
+                return_size,
+                actual_size,
+            } => write!(
+                f,
+                "Missing return values at {}: function returns {} items but got {}",
+                pos, return_size, actual_size
+            ),
+            Self::ReturnSizeMismatch {
+                pos,
+                return_size,
+                callee_return_size,
+            } => write!(
+                f,
+                "Return size mismatch at {}: function returns {} items but tail called one returns {}",
+                pos, return_size, callee_return_size
+            ),
+            Self::UnknownFunction {
+                pos,
+                name,
+                parameter_size,
+            } => write!(
+                f,
+                "Unknown function {} with {} parameters at {}",
+                name, parameter_size, pos
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Errors found by [`link`](crate::linker::link) while merging modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// More than one module is named `name`.
+    DuplicateModule { name: String },
+    /// Module exports a function it doesn't declare at top level.
+    UndeclaredExport { module: String, export: Export },
+    /// Imports no linked module exports, paired with the module importing them.
+    UnresolvedImports { imports: Vec<(String, Import)> },
+    /// Module imports a function of the same name and parameter size from another module too.
+    AmbiguousImport { module: String, import: Import },
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DuplicateModule { name } => write!(f, "Module {} is linked more than once", name),
+            Self::UndeclaredExport { module, export } => write!(
+                f,
+                "Module {} exports {} but does not declare it",
+                module, export
+            ),
+            Self::UnresolvedImports { imports } => {
+                write!(f, "Unresolved imports:")?;
+
+                for (module, import) in imports {
+                    write!(f, "\n  {} imported by {}", import, module)?;
+                }
+
+                Ok(())
+            }
+            Self::AmbiguousImport { module, import } => write!(
+                f,
+                "Module {} imports {}/{} from more than one module",
+                module, import.function, import.parameter_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Errors found by a [`Loader`](crate::Loader) in malformed bytecode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// Bytecode doesn't start with `GEARWORK`.
+    InvalidHeader { header: Vec<u8> },
+    /// Bytecode is of another format version than [`FORMAT_VERSION`](crate::bytecode::FORMAT_VERSION).
+    UnsupportedVersion { version: u8 },
+    /// Header names no known [`Encoding`](crate::bytecode::Encoding).
+    UnknownEncoding { flag: u8 },
+    /// Bytecode ends in the middle of a value.
+    UnexpectedEnd,
+    /// Constant pool holds a value of an unknown type.
+    UnknownConstantTag { tag: u8 },
+    /// Code holds an unknown instruction.
+    UnknownOpcode { opcode: u8 },
+    /// String is not valid UTF-8.
+    InvalidString { error: std::str::Utf8Error },
+    /// Compact operand doesn't fit in u32.
+    OperandOverflow,
+    /// Compact local variable index doesn't fit in u16.
+    LocalIndexOverflow { index: u32 },
+    /// Function declared at `pos` has more optional parameters than parameters.
+    InvalidArity {
+        pos: u32,
+        parameter_size: u8,
+        optional: u8,
+    },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidHeader { header } => write!(
+                f,
+                "Invalid header, should be `GEARWORK` (ascii form), but got `{}` (ascii form)",
+                header.iter().map(|u| *u as char).collect::<String>()
+            ),
+            Self::UnsupportedVersion { version } => write!(
+                f,
+                "Unsupported bytecode version {}, expected {}. Functions no longer share their caller's locals since version 2, rebuild the bytecode",
+                version,
+                crate::bytecode::FORMAT_VERSION
+            ),
+            Self::UnknownEncoding { flag } => {
+                write!(f, "Unexpected operand encoding {:#04X?}", flag)
+            }
+            Self::UnexpectedEnd => write!(f, "Unexpected end of bytecode"),
+            Self::UnknownConstantTag { tag } => write!(f, "Unexpected constant tag {}", tag),
+            Self::UnknownOpcode { opcode } => write!(f, "Unexpected opcode {:#04X?}", opcode),
+            Self::InvalidString { error } => write!(f, "Invalid string: {}", error),
+            Self::OperandOverflow => write!(f, "Operand overflows u32"),
+            Self::LocalIndexOverflow { index } => {
+                write!(f, "Local variable index {} overflows u16", index)
+            }
+            Self::InvalidArity {
+                pos,
+                parameter_size,
+                optional,
+            } => write!(
+                f,
+                "Function at {} declares {} optional parameters but takes only {}",
+                pos, optional, parameter_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
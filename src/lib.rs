@@ -4,8 +4,26 @@ extern crate enum_index_derive;
 extern crate arrayvec;
 
 pub mod bytecode;
+pub mod closure;
+pub mod coroutine;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub(crate) mod dispatch;
+pub mod error;
+pub mod linker;
 pub(crate) mod loader;
+pub mod module;
 pub mod opcode;
+pub mod optimizer;
+pub mod profiler;
+pub mod register;
+pub mod scheduler;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod verifier;
 pub mod vm;
+pub(crate) mod writer;
 
 pub use loader::Loader;
+pub use writer::Writer;
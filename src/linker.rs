@@ -0,0 +1,368 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    error::LinkError,
+    module::{Import, Module},
+    opcode::{Arity, Opcode},
+    optimizer::{function_end, function_scopes},
+    vm::{Code, DebugInfo, Stackable, VM},
+};
+
+/// Links `entry` with the modules it imports from, directly or through other modules, into a
+/// single VM.
+///
+/// Code of every imported module runs before the entry's, dependencies first, so their top
+/// level functions and globals are declared once the entry runs. Top level functions of an
+/// imported module are declared in the top frame, the rest of its top level code runs in a
+/// frame of its own, so its stack and locals are gone once the next module starts and a top
+/// level `return` only ends that frame. Functions and globals of imported modules are renamed
+/// to `<module>.<name>` so that modules can't clash, calls matching an import by name and
+/// parameter size call the exported function.
+///
+/// Constant pools are merged without duplicates. The linked VM keeps limits, debug info and
+/// exports of `entry`, modules none of the linked ones imports are left out. Debug info of
+/// imported modules is dropped, as it refers to their own source.
+pub fn link(entry: VM, dependencies: Vec<VM>) -> Result<VM, LinkError> {
+    let entry_name = entry.module().name.clone();
+    let mut modules: HashMap<String, VM> = HashMap::new();
+
+    for vm in dependencies.into_iter().chain(std::iter::once(entry)) {
+        let name = vm.module().name.clone();
+
+        if modules.insert(name.clone(), vm).is_some() {
+            return Err(LinkError::DuplicateModule { name });
+        }
+    }
+
+    for vm in modules.values() {
+        check_exports(vm)?;
+        check_imports(vm)?;
+    }
+
+    let unresolved = link_order(&modules, &entry_name)
+        .into_iter()
+        .flat_map(|name| {
+            modules[&name]
+                .module()
+                .imports
+                .iter()
+                .filter(|import| !is_exported(&modules, import))
+                .map(|import| (name.clone(), import.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    if !unresolved.is_empty() {
+        return Err(LinkError::UnresolvedImports {
+            imports: unresolved,
+        });
+    }
+
+    let mut linker = Linker {
+        entry_name: &entry_name,
+        constants: vec![],
+        instructions: vec![],
+    };
+
+    for name in link_order(&modules, &entry_name) {
+        linker.append(&modules[&name]);
+    }
+
+    let entry = &modules[&entry_name];
+    let max_locals = entry.code().max_locals();
+    let offset = (linker.instructions.len() - entry.code().instructions().len()) as u32;
+    let module = Module {
+        name: entry_name.clone(),
+        imports: vec![],
+        exports: entry.module().exports.clone(),
+    };
+    let mut linked = VM::new_vm(linker.constants, Code::new(linker.instructions, max_locals))
+        .with_limits(*entry.limits())
+        .with_module(module);
+
+    if let Some(debug_info) = entry.debug_info() {
+        let lines = debug_info
+            .lines()
+            .iter()
+            .map(|(pos, line)| (pos + offset, *line))
+            .collect();
+
+        linked = linked.with_debug_info(DebugInfo::new(debug_info.source().to_string(), lines));
+    }
+
+    Ok(linked)
+}
+
+/// Every export has to be a top level function of the module.
+fn check_exports(vm: &VM) -> Result<(), LinkError> {
+    let instructions = vm.code().instructions();
+    let scopes = function_scopes(instructions);
+
+    for export in &vm.module().exports {
+        let declared = instructions
+            .iter()
+            .enumerate()
+            .any(|(pos, opcode)| match opcode {
+                Opcode::Func(name_index, parameter_size, _, _, _) => {
+                    scopes[pos] == 0
+                        && *parameter_size == export.parameter_size
+                        && vm.constants().get(*name_index as usize)
+                            == Some(&Stackable::String(export.function.clone()))
+                }
+                _ => false,
+            });
+
+        if !declared {
+            return Err(LinkError::UndeclaredExport {
+                module: vm.module().name.clone(),
+                export: export.clone(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// A function may only be imported from one module, calls couldn't tell them apart otherwise.
+fn check_imports(vm: &VM) -> Result<(), LinkError> {
+    let imports = &vm.module().imports;
+
+    for (index, import) in imports.iter().enumerate() {
+        let ambiguous = imports[..index].iter().any(|other| {
+            other.module != import.module
+                && other.function == import.function
+                && other.parameter_size == import.parameter_size
+        });
+
+        if ambiguous {
+            return Err(LinkError::AmbiguousImport {
+                module: vm.module().name.clone(),
+                import: import.clone(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn is_exported(modules: &HashMap<String, VM>, import: &Import) -> bool {
+    modules.get(&import.module).is_some_and(|vm| {
+        vm.module().exports.iter().any(|export| {
+            export.function == import.function && export.parameter_size == import.parameter_size
+        })
+    })
+}
+
+/// Modules reachable from the entry through imports, every module after the ones it imports
+/// unless they import each other, entry last.
+fn link_order(modules: &HashMap<String, VM>, entry_name: &str) -> Vec<String> {
+    fn visit(
+        modules: &HashMap<String, VM>,
+        name: &str,
+        visited: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        let vm = match modules.get(name) {
+            Some(vm) if visited.insert(name.to_string()) => vm,
+            _ => return,
+        };
+
+        for import in &vm.module().imports {
+            visit(modules, &import.module, visited, order);
+        }
+
+        order.push(name.to_string());
+    }
+
+    let mut visited = HashSet::new();
+    let mut order = vec![];
+
+    visit(modules, entry_name, &mut visited, &mut order);
+
+    order
+}
+
+struct Linker<'a> {
+    entry_name: &'a str,
+    constants: Vec<Stackable>,
+    instructions: Vec<Opcode>,
+}
+
+impl Linker<'_> {
+    fn append(&mut self, vm: &VM) {
+        if vm.module().name == self.entry_name {
+            self.append_entry(vm);
+        } else {
+            self.append_dependency(vm);
+        }
+    }
+
+    fn append_entry(&mut self, vm: &VM) {
+        let instructions = vm.code().instructions();
+        let offset = self.instructions.len() as u32;
+        let positions = (0..=instructions.len() as u32)
+            .map(|pos| offset + pos)
+            .collect::<Vec<_>>();
+
+        for opcode in instructions {
+            let opcode = self.relocate(vm, *opcode, &positions);
+
+            self.instructions.push(opcode);
+        }
+    }
+
+    /// Appends top level functions of the module, then the rest of its top level code as the
+    /// body of an initializer function, then a call to it.
+    fn append_dependency(&mut self, vm: &VM) {
+        let instructions = vm.code().instructions();
+        let mut hoisted = vec![false; instructions.len()];
+        let mut pos = 0;
+
+        while pos < instructions.len() {
+            if let Opcode::Func(_, _, _, _, _) = instructions[pos] {
+                let end = function_end(instructions, pos);
+
+                hoisted[pos..=end].fill(true);
+                pos = end;
+            }
+
+            pos += 1;
+        }
+
+        let (functions, top_level): (Vec<usize>, Vec<usize>) =
+            (0..instructions.len()).partition(|pos| hoisted[*pos]);
+        let offset = self.instructions.len() as u32;
+        let body = offset + functions.len() as u32 + 1;
+        let init_return = body + top_level.len() as u32;
+        // Running off the end returns from the initializer
+        let mut positions = vec![init_return; instructions.len() + 1];
+
+        for (index, pos) in functions.iter().enumerate() {
+            positions[*pos] = offset + index as u32;
+        }
+
+        for (index, pos) in top_level.iter().enumerate() {
+            positions[*pos] = body + index as u32;
+        }
+
+        // Functions are declared already, top level code jumping to one skips it instead
+        let mut top_level_positions = positions.clone();
+
+        for pos in (0..instructions.len()).rev() {
+            if hoisted[pos] {
+                top_level_positions[pos] = top_level_positions[pos + 1];
+            }
+        }
+
+        for pos in functions {
+            let opcode = self.relocate(vm, instructions[pos], &positions);
+
+            self.instructions.push(opcode);
+        }
+
+        let init = self.constant(Stackable::String(format!("{}.<init>", vm.module().name)));
+
+        self.instructions.push(Opcode::Func(
+            init,
+            0,
+            0,
+            vm.code().max_locals(),
+            Arity::default(),
+        ));
+
+        for pos in top_level {
+            let opcode = match instructions[pos] {
+                // Only functions' `return`s were hoisted, the initializer has a single one
+                Opcode::Return => Opcode::Goto(init_return),
+                opcode => self.relocate(vm, opcode, &top_level_positions),
+            };
+
+            self.instructions.push(opcode);
+        }
+
+        self.instructions.push(Opcode::Return);
+        self.instructions.push(Opcode::Invoke(init, 0));
+    }
+
+    /// Rewrites constant indices and names of `opcode` for the linked constant pool, and
+    /// `goto` targets by `positions`, which maps every position of `vm`'s code and its end.
+    fn relocate(&mut self, vm: &VM, mut opcode: Opcode, positions: &[u32]) -> Opcode {
+        let module = vm.module();
+
+        match &mut opcode {
+            Opcode::Ldc(index) | Opcode::Import(index) => {
+                *index = match vm.constants().get(*index as usize) {
+                    Some(constant) => self.constant(constant.clone()),
+                    None => u32::MAX,
+                }
+            }
+            Opcode::Goto(target) => {
+                *target = positions[std::cmp::min(*target as usize, positions.len() - 1)]
+            }
+            Opcode::Func(index, _, _, _, arity) => {
+                *index = self.name(vm, *index, &module.name);
+
+                if arity.optional > 0 {
+                    let defaults =
+                        arity.defaults as usize..arity.defaults as usize + arity.optional as usize;
+
+                    // Defaults have to stay consecutive
+                    arity.defaults = match vm.constants().get(defaults) {
+                        Some(defaults) => {
+                            self.constants.extend_from_slice(defaults);
+                            (self.constants.len() - defaults.len()) as u32
+                        }
+                        None => u32::MAX,
+                    };
+                }
+            }
+            Opcode::Invoke(index, parameter_size)
+            | Opcode::Coroutine(index, parameter_size)
+            | Opcode::Spawn(index, parameter_size)
+            | Opcode::TailInvoke(index, parameter_size) => {
+                let import = module.imports.iter().find(|import| {
+                    import.parameter_size == *parameter_size
+                        && vm.constants().get(*index as usize)
+                            == Some(&Stackable::String(import.function.clone()))
+                });
+
+                *index = match import {
+                    Some(import) => self.name(vm, *index, &import.module),
+                    None => self.name(vm, *index, &module.name),
+                };
+            }
+            Opcode::SetGlobal(index) | Opcode::GetGlobal(index) => {
+                *index = self.name(vm, *index, &module.name);
+            }
+            _ => {}
+        }
+
+        opcode
+    }
+
+    /// Index of the name constant at `index` of `vm`, qualified by `module` unless it is the
+    /// entry.
+    fn name(&mut self, vm: &VM, index: u32, module: &str) -> u32 {
+        match vm.constants().get(index as usize) {
+            Some(Stackable::String(name)) if module != self.entry_name => {
+                self.constant(Stackable::String(format!("{}.{}", module, name)))
+            }
+            Some(constant) => self.constant(constant.clone()),
+            None => u32::MAX,
+        }
+    }
+
+    fn constant(&mut self, constant: Stackable) -> u32 {
+        match self
+            .constants
+            .iter()
+            .position(|c| c.is_same_constant(&constant))
+        {
+            Some(index) => index as u32,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() as u32 - 1
+            }
+        }
+    }
+}
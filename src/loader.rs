@@ -1,19 +1,27 @@
 use std::{slice::Iter, str};
 
 use crate::{
-    opcode::Opcode,
-    vm::{Code, Stackable, VM},
+    bytecode::{Encoding, FORMAT_VERSION},
+    error::LoadError,
+    module::{Export, Import, Module},
+    opcode::{Arity, Opcode},
+    vm::{Code, DebugInfo, Stackable, VM},
 };
 
 trait ConvertibleData<const COUNT: usize> {
-    fn take_convert<'a>(iter: &mut impl Iterator<Item = &'a u8>) -> Self
+    fn take_convert<'a>(iter: &mut impl Iterator<Item = &'a u8>) -> Result<Self, LoadError>
     where
         Self: Sized,
     {
         let mut container = [0u8; COUNT];
-        let sliced_bits = &iter.by_ref().take(COUNT).map(|u| *u).collect::<Vec<u8>>()[..];
+        let sliced_bits = &iter.by_ref().take(COUNT).copied().collect::<Vec<u8>>()[..];
+
+        if sliced_bits.len() != COUNT {
+            return Err(LoadError::UnexpectedEnd);
+        }
+
         container.copy_from_slice(sliced_bits);
-        Self::from_be_bytes(container)
+        Ok(Self::from_be_bytes(container))
     }
 
     fn from_be_bytes(from: [u8; COUNT]) -> Self;
@@ -69,71 +77,90 @@ impl ConvertibleData<8> for u64 {
 
 pub struct Loader<'a> {
     bytecode: Iter<'a, u8>,
+    /// Read from header
+    encoding: Encoding,
 }
 
 impl<'a> Loader<'a> {
-    pub fn new(bytecode: &'a Vec<u8>) -> Self {
+    pub fn new(bytecode: &'a [u8]) -> Self {
         Self {
             bytecode: bytecode.iter(),
+            encoding: Encoding::default(),
         }
     }
 
-    pub fn load(mut self) -> VM {
+    /// # Panics
+    /// If the bytecode is malformed, see [`Loader::try_load`].
+    pub fn load(self) -> VM {
+        self.try_load().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Loads like [`Loader::load`], but returns why malformed bytecode could not be loaded
+    /// instead of panicking, for hosts which must outlive bad input.
+    pub fn try_load(mut self) -> Result<VM, LoadError> {
         // Validate header first
-        self.validate_header();
+        self.validate_header()?;
+
+        let version = *self.next()?;
+
+        if version != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion { version });
+        }
+
+        let flag = *self.next()?;
+        self.encoding = Encoding::from_flag(flag).ok_or(LoadError::UnknownEncoding { flag })?;
+
+        let module = self.load_module()?;
 
         // Load constants
-        let constant_pool_size = self.read_data::<u32, 4>() as usize;
-        let mut constants = Vec::with_capacity(constant_pool_size);
+        let constant_pool_size = self.read_data::<u32, 4>()? as usize;
+        let mut constants = Vec::with_capacity(self.capacity(constant_pool_size));
 
         for _ in 0..constant_pool_size {
-            match self.next() {
+            match self.next()? {
                 0x00 => {
                     // Integer constant
-                    let integer = self.read_data::<i32, 4>();
+                    let integer = self.read_data::<i32, 4>()?;
 
                     constants.push(Stackable::Int(integer));
                 }
                 0x01 => {
                     // Long constant
-                    let long = self.read_data::<i64, 8>();
+                    let long = self.read_data::<i64, 8>()?;
 
                     constants.push(Stackable::Long(long));
                 }
                 0x02 => {
                     // Float constant
-                    let float = self.read_data::<f32, 4>();
+                    let float = self.read_data::<f32, 4>()?;
 
                     constants.push(Stackable::Float(float));
                 }
                 0x03 => {
                     // Double constant
-                    let double = self.read_data::<f64, 8>();
+                    let double = self.read_data::<f64, 8>()?;
 
                     constants.push(Stackable::Double(double));
                 }
                 0x04 => {
                     // String constant
-                    let string_size = self.read_data::<u64, 8>() as usize;
-                    let string_bytes = self.read(string_size);
+                    let string = self.read_string()?;
 
-                    match str::from_utf8(&string_bytes) {
-                        Ok(string) => constants.push(Stackable::String(string.to_string())),
-                        Err(err) => panic!("{}", err),
-                    }
+                    constants.push(Stackable::String(string));
                 }
-                tag @ _ => panic!("Unexpected constant tag {}", tag),
+                tag => return Err(LoadError::UnknownConstantTag { tag: *tag }),
             }
         }
 
-        let instructions_size = self.read_data::<u32, 4>() as usize;
-        let mut instructions = Vec::with_capacity(instructions_size);
+        let instructions_size = self.read_data::<u32, 4>()? as usize;
+        let max_locals = self.read_data::<u32, 4>()?;
+        let mut instructions = Vec::with_capacity(self.capacity(instructions_size));
 
         for _ in 0..instructions_size {
-            match self.next() {
+            match self.next()? {
                 0x00 => {
                     // ldc
-                    let index = self.read_data::<u32, 4>();
+                    let index = self.read_operand()?;
 
                     instructions.push(Opcode::Ldc(index));
                 }
@@ -155,11 +182,11 @@ impl<'a> Loader<'a> {
                 }
                 0x05 => {
                     // div
-                    instructions.push(Opcode::Add);
+                    instructions.push(Opcode::Div);
                 }
                 0x06 => {
                     // mod
-                    instructions.push(Opcode::Div);
+                    instructions.push(Opcode::Mod);
                 }
                 0x07 => {
                     // dup
@@ -171,19 +198,19 @@ impl<'a> Loader<'a> {
                 }
                 0x09 => {
                     // store
-                    let index = self.read_data::<u16, 2>();
+                    let index = self.read_local_index()?;
 
                     instructions.push(Opcode::Store(index));
                 }
                 0x0A => {
                     // load
-                    let index = self.read_data::<u16, 2>();
+                    let index = self.read_local_index()?;
 
                     instructions.push(Opcode::Load(index));
                 }
                 0x0B => {
                     // goto
-                    let index = self.read_data::<u32, 4>();
+                    let index = self.read_operand()?;
 
                     instructions.push(Opcode::Goto(index));
                 }
@@ -193,10 +220,31 @@ impl<'a> Loader<'a> {
                 }
                 0x0D => {
                     // func
-                    let function_name_index = self.read_data::<u32, 4>();
-                    let parameter_size = self.read_data::<u8, 1>();
+                    let function_name_index = self.read_operand()?;
+                    let parameter_size = self.read_data::<u8, 1>()?;
+                    let return_size = self.read_data::<u8, 1>()?;
+                    let max_locals = self.read_operand()?;
+                    let arity = Arity {
+                        optional: self.read_data::<u8, 1>()?,
+                        defaults: self.read_operand()?,
+                        rest: self.read_data::<u8, 1>()? != 0,
+                    };
+
+                    if arity.optional > parameter_size {
+                        return Err(LoadError::InvalidArity {
+                            pos: instructions.len() as u32,
+                            parameter_size,
+                            optional: arity.optional,
+                        });
+                    }
 
-                    instructions.push(Opcode::Func(function_name_index, parameter_size));
+                    instructions.push(Opcode::Func(
+                        function_name_index,
+                        parameter_size,
+                        return_size,
+                        max_locals,
+                        arity,
+                    ));
                 }
                 0x0E => {
                     // return
@@ -204,37 +252,215 @@ impl<'a> Loader<'a> {
                 }
                 0x0F => {
                     // invoke
-                    let function_name = self.read_data::<u32, 4>();
-                    let parameter_size = self.read_data::<u8, 1>();
+                    let function_name = self.read_operand()?;
+                    let parameter_size = self.read_data::<u8, 1>()?;
 
                     instructions.push(Opcode::Invoke(function_name, parameter_size));
                 }
-                opcode @ _ => panic!("Unexpected opcode {:#04X?}", opcode),
+                0x10 => {
+                    // coroutine
+                    let function_name_index = self.read_operand()?;
+                    let parameter_size = self.read_data::<u8, 1>()?;
+
+                    instructions.push(Opcode::Coroutine(function_name_index, parameter_size));
+                }
+                0x11 => {
+                    // yield
+                    instructions.push(Opcode::Yield);
+                }
+                0x12 => {
+                    // resume
+                    instructions.push(Opcode::Resume);
+                }
+                0x13 => {
+                    // done
+                    instructions.push(Opcode::Done);
+                }
+                0x14 => {
+                    // spawn
+                    let function_name_index = self.read_operand()?;
+                    let parameter_size = self.read_data::<u8, 1>()?;
+
+                    instructions.push(Opcode::Spawn(function_name_index, parameter_size));
+                }
+                0x15 => {
+                    // send
+                    instructions.push(Opcode::Send);
+                }
+                0x16 => {
+                    // receive
+                    instructions.push(Opcode::Receive);
+                }
+                0x17 => {
+                    // pid
+                    instructions.push(Opcode::Pid);
+                }
+                0x18 => {
+                    // setglobal
+                    let name_index = self.read_operand()?;
+
+                    instructions.push(Opcode::SetGlobal(name_index));
+                }
+                0x19 => {
+                    // getglobal
+                    let name_index = self.read_operand()?;
+
+                    instructions.push(Opcode::GetGlobal(name_index));
+                }
+                0x1A => {
+                    // tailinvoke
+                    let function_name_index = self.read_operand()?;
+                    let parameter_size = self.read_data::<u8, 1>()?;
+
+                    instructions.push(Opcode::TailInvoke(function_name_index, parameter_size));
+                }
+                0x1B => {
+                    // import
+                    let name_index = self.read_operand()?;
+
+                    instructions.push(Opcode::Import(name_index));
+                }
+                opcode => return Err(LoadError::UnknownOpcode { opcode: *opcode }),
             }
         }
 
-        VM::new_vm(constants, Code::new(instructions))
+        let vm = VM::new_vm(constants, Code::new(instructions, max_locals)).with_module(module);
+
+        // Debug info is optional
+        if self.bytecode.as_slice().is_empty() {
+            Ok(vm)
+        } else {
+            Ok(vm.with_debug_info(self.load_debug_info()?))
+        }
+    }
+
+    /// Room to reserve for `count` items of at least a byte each, so a corrupt count can't
+    /// reserve more than the bytecode could hold.
+    fn capacity(&self, count: usize) -> usize {
+        std::cmp::min(count, self.bytecode.as_slice().len())
+    }
+
+    fn load_module(&mut self) -> Result<Module, LoadError> {
+        let name = self.read_string()?;
+        let imports_size = self.read_data::<u32, 4>()? as usize;
+        let mut imports = Vec::with_capacity(self.capacity(imports_size));
+
+        for _ in 0..imports_size {
+            imports.push(Import {
+                module: self.read_string()?,
+                function: self.read_string()?,
+                parameter_size: self.read_data::<u8, 1>()?,
+            });
+        }
+
+        let exports_size = self.read_data::<u32, 4>()? as usize;
+        let mut exports = Vec::with_capacity(self.capacity(exports_size));
+
+        for _ in 0..exports_size {
+            exports.push(Export {
+                function: self.read_string()?,
+                parameter_size: self.read_data::<u8, 1>()?,
+            });
+        }
+
+        Ok(Module {
+            name,
+            imports,
+            exports,
+        })
+    }
+
+    fn load_debug_info(&mut self) -> Result<DebugInfo, LoadError> {
+        let source = self.read_string()?;
+
+        let lines_size = self.read_data::<u32, 4>()? as usize;
+        let mut lines = Vec::with_capacity(self.capacity(lines_size));
+
+        for _ in 0..lines_size {
+            let pos = self.read_data::<u32, 4>()?;
+            let line = self.read_data::<u32, 4>()?;
+
+            lines.push((pos, line));
+        }
+
+        Ok(DebugInfo::new(source, lines))
+    }
+
+    fn validate_header(&mut self) -> Result<(), LoadError> {
+        let header = self.read(8)?;
+
+        if header != [0x47, 0x45, 0x41, 0x52, 0x57, 0x4F, 0x52, 0x4B] {
+            return Err(LoadError::InvalidHeader { header });
+        }
+
+        Ok(())
+    }
+
+    /// String prefixed by 4 bytes of its length.
+    fn read_string(&mut self) -> Result<String, LoadError> {
+        let size = self.read_data::<u32, 4>()? as usize;
+        let bytes = self.read(size)?;
+
+        str::from_utf8(&bytes)
+            .map(str::to_string)
+            .map_err(|error| LoadError::InvalidString { error })
+    }
+
+    /// Operand indexing constants or instructions, or a slot count.
+    fn read_operand(&mut self) -> Result<u32, LoadError> {
+        match self.encoding {
+            Encoding::Fixed => self.read_data::<u32, 4>(),
+            Encoding::Compact => self.read_leb128(),
+        }
+    }
+
+    fn read_local_index(&mut self) -> Result<u16, LoadError> {
+        match self.encoding {
+            Encoding::Fixed => self.read_data::<u16, 2>(),
+            Encoding::Compact => {
+                let index = self.read_leb128()?;
+
+                u16::try_from(index).map_err(|_| LoadError::LocalIndexOverflow { index })
+            }
+        }
     }
 
-    fn validate_header(&mut self) {
-        let header = &self.read(8);
-        if header != &[0x47, 0x45, 0x41, 0x52, 0x57, 0x4F, 0x52, 0x4B] {
-            panic!(
-                "Invalid header, should be `GEARWORK` (ascii form), but got `{}` (ascii form)",
-                header.iter().map(|u| *u as char).collect::<String>()
-            );
+    fn read_leb128(&mut self) -> Result<u32, LoadError> {
+        let mut operand = 0u32;
+
+        for shift in (0..32).step_by(7) {
+            let byte = *self.next()?;
+
+            if shift == 28 && byte > 0x0F {
+                return Err(LoadError::OperandOverflow);
+            }
+
+            operand |= ((byte & 0x7F) as u32) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(operand);
+            }
         }
+
+        unreachable!()
     }
 
-    fn next(&mut self) -> &u8 {
-        self.bytecode.by_ref().next().unwrap()
+    fn next(&mut self) -> Result<&u8, LoadError> {
+        self.bytecode
+            .by_ref()
+            .next()
+            .ok_or(LoadError::UnexpectedEnd)
     }
 
-    fn read(&mut self, n: usize) -> Vec<u8> {
-        self.bytecode.by_ref().take(n).map(|u| *u).collect()
+    fn read(&mut self, n: usize) -> Result<Vec<u8>, LoadError> {
+        if n > self.bytecode.as_slice().len() {
+            return Err(LoadError::UnexpectedEnd);
+        }
+
+        Ok(self.bytecode.by_ref().take(n).copied().collect())
     }
 
-    fn read_data<CD, const COUNT: usize>(&mut self) -> CD
+    fn read_data<CD, const COUNT: usize>(&mut self) -> Result<CD, LoadError>
     where
         CD: ConvertibleData<COUNT>,
     {
@@ -1,6 +1,170 @@
-use cogwork::{bytecode::*, vm::Stackable, Loader};
+use std::{env, fs, io, process};
+
+use cogwork::{
+    bytecode::*,
+    dap::DapServer,
+    debugger::Debugger,
+    linker,
+    profiler::Profiler,
+    verifier,
+    vm::{Backend, Process, ProcessStatus, Stackable},
+    Loader, Writer,
+};
+
+const USAGE: &str =
+    "Usage: cogwork [run <file> [--register | --closure] | debug [file] | dap | profile <file> <folded output> | coverage <file> <lcov output> | link <output> <entry> [module ...]]";
 
 fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    match args[..] {
+        [] => run(&demo_bytecode(), Backend::Stack),
+        ["run", path] => run(&read_bytecode(path), Backend::Stack),
+        ["run", path, "--register"] => run(&read_bytecode(path), Backend::Register),
+        ["run", path, "--closure"] => run(&read_bytecode(path), Backend::Closure),
+        ["debug"] => debug(&demo_bytecode()),
+        ["debug", path] => debug(&read_bytecode(path)),
+        ["dap"] => dap(),
+        ["profile", path, folded_path] => profile(&read_bytecode(path), folded_path),
+        ["coverage", path, lcov_path] => coverage(path, lcov_path),
+        ["link", output_path, entry_path, ref module_paths @ ..] => {
+            link(output_path, entry_path, module_paths)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+fn read_bytecode(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Unable to read {}: {}", path, err);
+        process::exit(1);
+    })
+}
+
+fn run(bytecode: &[u8], backend: Backend) {
+    // Load bytecode to vm and load
+    let loader = Loader::new(bytecode);
+    let vm = loader.load();
+
+    if let Err(err) = verifier::verify(&vm) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    let vm = vm.with_backend(backend);
+
+    if vm.backend() != backend {
+        eprintln!(
+            "Code is not supported by {:?} backend, running on stack",
+            backend
+        );
+    }
+
+    if let Err(err) = vm.execute() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn debug(bytecode: &[u8]) {
+    let vm = Loader::new(bytecode).load();
+    let mut debugger = Debugger::new(Process::new_process(vm, 0));
+    let stdin = io::stdin();
+
+    if let Err(err) = debugger.prompt(stdin.lock(), io::stdout()) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn dap() {
+    let stdin = io::stdin();
+    let mut server = DapServer::new(stdin.lock(), io::stdout());
+
+    if let Err(err) = server.run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn profile(bytecode: &[u8], folded_path: &str) {
+    let vm = Loader::new(bytecode).load();
+    let mut profiler = Profiler::new(Process::new_process(vm, 0));
+
+    if let ProcessStatus::Faulted(err) = profiler.run() {
+        eprintln!("{}", err);
+    }
+
+    let profile = profiler.into_profile();
+
+    println!("{:<12} {:>10}", "opcode", "count");
+    for (name, count) in &profile.opcode_counts {
+        println!("{:<12} {:>10}", name, count);
+    }
+
+    println!();
+    println!(
+        "{:<24} {:>8} {:>14} {:>14}",
+        "function", "calls", "inclusive", "exclusive"
+    );
+    for (name, time) in &profile.functions {
+        println!(
+            "{:<24} {:>8} {:>14?} {:>14?}",
+            name, time.calls, time.inclusive, time.exclusive
+        );
+    }
+
+    if let Err(err) = fs::write(folded_path, profile.folded_stacks()) {
+        eprintln!("Unable to write {}: {}", folded_path, err);
+        process::exit(1);
+    }
+}
+
+fn coverage(path: &str, lcov_path: &str) {
+    let vm = Loader::new(&read_bytecode(path)).load();
+    let mut process = Process::new_process(vm, 0);
+    process.enable_coverage();
+
+    while !process.step().is_terminated() {}
+
+    if let ProcessStatus::Faulted(err) = process.status() {
+        eprintln!("{}", err);
+    }
+
+    let report = match process.coverage() {
+        Some(coverage) => coverage.to_lcov(process.vm(), path),
+        None => unreachable!(),
+    };
+
+    if let Err(err) = fs::write(lcov_path, report) {
+        eprintln!("Unable to write {}: {}", lcov_path, err);
+        process::exit(1);
+    }
+}
+
+fn link(output_path: &str, entry_path: &str, module_paths: &[&str]) {
+    let entry = Loader::new(&read_bytecode(entry_path)).load();
+    let modules = module_paths
+        .iter()
+        .map(|path| Loader::new(&read_bytecode(path)).load())
+        .collect();
+
+    let vm = linker::link(entry, modules).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    if let Err(err) = fs::write(output_path, Writer::new(&vm).write()) {
+        eprintln!("Unable to write {}: {}", output_path, err);
+        process::exit(1);
+    }
+}
+
+fn demo_bytecode() -> Vec<u8> {
     // Emit bytecode
     let mut bytecode_builder = BytecodeBuilder::new();
 
@@ -9,16 +173,16 @@ fn main() {
 
     instruction_builder.visit_ldc(Stackable::Int(10));
     instruction_builder.visit_dup();
-    instruction_builder.visit_store(0); // a
+    instruction_builder.visit_setglobal("a");
     {
-        instruction_builder.visit_func("add", 1);
+        instruction_builder.visit_func("add", 1, 1);
         {
-            instruction_builder.visit_func("mul", 1);
+            instruction_builder.visit_func("mul", 1, 1);
             instruction_builder.visit_ldc(Stackable::Int(90));
             instruction_builder.visit_mul();
             instruction_builder.visit_return();
         }
-        instruction_builder.visit_load(0); // a
+        instruction_builder.visit_getglobal("a");
         instruction_builder.visit_add();
         instruction_builder.visit_invoke("mul", 1);
         instruction_builder.visit_return();
@@ -26,29 +190,23 @@ fn main() {
     }
     instruction_builder.visit_dump();
     instruction_builder.visit_return();
-    
+
     /*
      * This section of code equivalents to the following py code
-     * 
+     *
      * ```py
      * a = 10
-     * 
+     *
      * def add(x):
      *     def mul(z):
      *         return z * 90
      *     return mul(x + a)
-     * 
+     *
      * add(10)
      * ```
      */
 
     instruction_builder.visit_end();
     // Build bytecode
-    let bytecode = bytecode_builder.visit_end();
-
-    // Load bytecode to vm and load
-    let loader = Loader::new(&bytecode);
-    let vm = loader.load();
-
-    vm.execute();
+    bytecode_builder.visit_end()
 }
@@ -0,0 +1,49 @@
+use std::fmt::Display;
+
+/// Module metadata stored in bytecode header: the module's name, functions it calls from other
+/// modules and functions it offers to them. Resolved by [`link`](crate::linker::link), or at
+/// runtime once `import` loads the modules from a [`ModuleResolver`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Module {
+    pub name: String,
+    pub imports: Vec<Import>,
+    pub exports: Vec<Export>,
+}
+
+/// Function of another module, calls of the same name and parameter size refer to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    pub module: String,
+    pub function: String,
+    pub parameter_size: u8,
+}
+
+impl Display for Import {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}/{}",
+            self.module, self.function, self.parameter_size
+        )
+    }
+}
+
+/// Top level function other modules may import.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub function: String,
+    pub parameter_size: u8,
+}
+
+impl Display for Export {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.function, self.parameter_size)
+    }
+}
+
+/// Supplies bytecode of modules `import` loads at runtime, implemented by the embedder, see
+/// [`VM::with_resolver`](crate::vm::VM::with_resolver).
+pub trait ModuleResolver: Send + Sync {
+    /// Bytecode of the module `name`, `None` if there is no such module.
+    fn resolve(&self, name: &str) -> Option<Vec<u8>>;
+}
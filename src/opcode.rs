@@ -1,19 +1,101 @@
+/// Optional and rest parameters of a `func` besides its required ones, which come first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Arity {
+    /// Trailing parameters that take their default when not passed, counted in the
+    /// function's parameter size
+    pub optional: u8,
+    /// Constant pool index of the first optional parameter's default, the others follow it
+    pub defaults: u32,
+    /// Whether arguments beyond the parameter size are collected into an array passed after
+    /// the other parameters
+    pub rest: bool,
+}
+
+impl Arity {
+    /// Whether a function of `parameter_size` parameters accepts `argument_size` arguments.
+    pub fn accepts(&self, parameter_size: u8, argument_size: u8) -> bool {
+        let required = parameter_size.saturating_sub(self.optional);
+
+        argument_size >= required && (self.rest || argument_size <= parameter_size)
+    }
+
+    /// Accepted argument sizes of a function of `parameter_size` parameters, e.g. `1..3` or
+    /// `2+`.
+    pub fn describe(&self, parameter_size: u8) -> String {
+        let required = parameter_size.saturating_sub(self.optional);
+
+        match (self.rest, self.optional) {
+            (true, _) => format!("{}+", required),
+            (false, 0) => parameter_size.to_string(),
+            (false, _) => format!("{}..{}", required, parameter_size),
+        }
+    }
+}
+
 #[derive(EnumIndex, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
-    Ldc(u32),        // 0x00
-    Dump,            // 0x01
-    Add,             // 0x02
-    Sub,             // 0x03
-    Mul,             // 0x04
-    Div,             // 0x05
-    Mod,             // 0x06
-    Dup,             // 0x07
-    Swp,             // 0x08
-    Store(u16),      // 0x09
-    Load(u16),       // 0x0A
-    Goto(u32),       // 0x0B
-    Nop,             // 0x0C
-    Func(u32, u8),   // 0x0D
-    Return,          // 0x0E
-    Invoke(u32, u8), // 0x0F
+    Ldc(u32),                      // 0x00
+    Dump,                          // 0x01
+    Add,                           // 0x02
+    Sub,                           // 0x03
+    Mul,                           // 0x04
+    Div,                           // 0x05
+    Mod,                           // 0x06
+    Dup,                           // 0x07
+    Swp,                           // 0x08
+    Store(u16),                    // 0x09
+    Load(u16),                     // 0x0A
+    Goto(u32),                     // 0x0B
+    Nop,                           // 0x0C
+    Func(u32, u8, u8, u32, Arity), // 0x0D
+    Return,                        // 0x0E
+    Invoke(u32, u8),               // 0x0F
+    Coroutine(u32, u8),            // 0x10
+    Yield,                         // 0x11
+    Resume,                        // 0x12
+    Done,                          // 0x13
+    Spawn(u32, u8),                // 0x14
+    Send,                          // 0x15
+    Receive,                       // 0x16
+    Pid,                           // 0x17
+    SetGlobal(u32),                // 0x18
+    GetGlobal(u32),                // 0x19
+    TailInvoke(u32, u8),           // 0x1A
+    Import(u32),                   // 0x1B
+}
+
+impl Opcode {
+    /// Mnemonic of the opcode, as listed in the instruction set table.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ldc(_) => "ldc",
+            Self::Dump => "dump",
+            Self::Add => "add",
+            Self::Sub => "sub",
+            Self::Mul => "mul",
+            Self::Div => "div",
+            Self::Mod => "mod",
+            Self::Dup => "dup",
+            Self::Swp => "swp",
+            Self::Store(_) => "store",
+            Self::Load(_) => "load",
+            Self::Goto(_) => "goto",
+            Self::Nop => "nop",
+            Self::Func(_, _, _, _, _) => "func",
+            Self::Return => "return",
+            Self::Invoke(_, _) => "invoke",
+            Self::Coroutine(_, _) => "coroutine",
+            Self::Yield => "yield",
+            Self::Resume => "resume",
+            Self::Done => "done",
+            Self::Spawn(_, _) => "spawn",
+            Self::Send => "send",
+            Self::Receive => "receive",
+            Self::Pid => "pid",
+            Self::SetGlobal(_) => "setglobal",
+            Self::GetGlobal(_) => "getglobal",
+            Self::TailInvoke(_, _) => "tailinvoke",
+            Self::Import(_) => "import",
+        }
+    }
 }
@@ -0,0 +1,459 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    opcode::Opcode,
+    verifier::declared_return_size,
+    vm::{Code, DebugInfo, Stackable, VM},
+};
+
+/// Runs every pass, peephole again after dead code elimination since removed code leaves
+/// jumps to the next instruction behind.
+pub fn optimize(vm: VM) -> VM {
+    peephole(eliminate_dead_code(peephole(vm)))
+}
+
+/// Peephole optimization pass, rewrites the VM's code until none of the following applies:
+///
+/// - `ldc a; ldc b; <arithmetic>` folds into `ldc` of the result, adding it to the constant pool
+/// - `store n; load n` is removed if no other instruction of the function loads `n`
+/// - `goto` to another `goto` or to `nop`s jumps straight to the final destination
+/// - `goto` to the next instruction is removed
+/// - `nop` is removed
+///
+/// Instructions are only merged if nothing jumps into the middle of them. `goto` targets and
+/// debug info positions are rewritten as instructions move, execution should start at the
+/// position `0` maps to, which stays `0`.
+pub fn peephole(vm: VM) -> VM {
+    let mut constants = vm.constants().to_vec();
+    let mut instructions = vm.code().instructions().to_vec();
+    let mut positions = (0..=instructions.len() as u32).collect::<Vec<_>>();
+
+    loop {
+        let changed = fold_constants(&mut constants, &mut instructions)
+            | remove_store_loads(&mut instructions)
+            | thread_jumps(&mut instructions);
+
+        let instructions_size = instructions.len();
+        let keep = instructions
+            .iter()
+            .map(|opcode| *opcode != Opcode::Nop)
+            .collect::<Vec<_>>();
+        let moved = retain(&mut instructions, &keep);
+
+        for pos in positions.iter_mut() {
+            *pos = moved[*pos as usize];
+        }
+
+        if !changed && instructions.len() == instructions_size {
+            break;
+        }
+    }
+
+    rebuild(&vm, constants, instructions, &positions)
+}
+
+/// Dead code elimination pass, removes instructions unreachable from position `0`, functions
+/// neither exported nor referred to by a reachable `invoke`, `coroutine` or `spawn`, and
+/// constants nothing refers to afterwards. Constant indices, `goto` targets and debug info positions are rewritten.
+///
+/// A function is referenced by its name index and parameter size regardless of scope, and
+/// keeps its `func` and `return` as long as any of its instructions is reachable.
+pub fn eliminate_dead_code(vm: VM) -> VM {
+    let mut instructions = vm.code().instructions().to_vec();
+    let reachable = reachable(&vm);
+    let mut keep = reachable.clone();
+
+    for (pos, opcode) in instructions.iter().enumerate() {
+        if let Opcode::Func(_, _, _, _, _) = opcode {
+            // Reachable `func`s of uncalled functions go as well
+            let end = function_end(&instructions, pos);
+            let body_reachable = reachable[pos + 1..=end].contains(&true);
+
+            keep[pos] = body_reachable;
+            keep[end] = body_reachable;
+        }
+    }
+
+    let positions = retain(&mut instructions, &keep);
+    let constants = compact_constants(vm.constants(), &mut instructions);
+
+    rebuild(&vm, constants, instructions, &positions)
+}
+
+/// Tail call pass, rewrites `invoke` followed by `return` into `tailinvoke`, so the callee
+/// reuses the caller's frame. Only applies within functions, to callees declaring the same
+/// return size. The `return` stays since it ends the function's body. Not part of
+/// [`optimize`], as replaced frames no longer show up on call stacks of debuggers and
+/// profilers.
+pub fn tail_calls(vm: VM) -> VM {
+    let mut instructions = vm.code().instructions().to_vec();
+    let positions = (0..=instructions.len() as u32).collect::<Vec<_>>();
+    let scopes = function_scopes(&instructions);
+    // Return size of each function scope in order of declaration
+    let return_sizes = instructions
+        .iter()
+        .filter_map(|opcode| match opcode {
+            Opcode::Func(_, _, return_size, _, _) => Some(*return_size),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    for pos in 0..instructions.len().saturating_sub(1) {
+        if let [Opcode::Invoke(function_name_index, parameter_size), Opcode::Return] =
+            instructions[pos..pos + 2]
+        {
+            let return_size = scopes[pos].checked_sub(1).map(|scope| return_sizes[scope]);
+            let callee_return_size =
+                declared_return_size(&instructions, function_name_index, parameter_size);
+
+            if return_size.is_some() && return_size == callee_return_size {
+                instructions[pos] = Opcode::TailInvoke(function_name_index, parameter_size);
+            }
+        }
+    }
+
+    rebuild(&vm, vm.constants().to_vec(), instructions, &positions)
+}
+
+/// Marks instructions control can reach from position `0`. A function body is entered once
+/// both its `func` and a call it accepts are reachable, exports count as calls from other
+/// modules.
+fn reachable(vm: &VM) -> Vec<bool> {
+    let instructions = vm.code().instructions();
+    let mut reachable = vec![false; instructions.len()];
+    let mut called = vm
+        .module()
+        .exports
+        .iter()
+        .filter_map(|export| {
+            let name = Stackable::String(export.function.clone());
+            let name_index = vm.constants().iter().position(|c| *c == name)?;
+
+            Some((name_index as u32, export.parameter_size))
+        })
+        .collect::<HashSet<_>>();
+    let mut functions = vec![];
+    let mut pending = vec![0];
+
+    loop {
+        while let Some(pos) = pending.pop() {
+            if pos >= instructions.len() || reachable[pos] {
+                continue;
+            }
+
+            reachable[pos] = true;
+
+            match instructions[pos] {
+                Opcode::Goto(target) => pending.push(target as usize),
+                Opcode::Return => {}
+                Opcode::TailInvoke(function_name_index, parameter_size) => {
+                    called.insert((function_name_index, parameter_size));
+                }
+                Opcode::Func(function_name_index, parameter_size, _, _, arity) => {
+                    functions.push((pos, function_name_index, parameter_size, arity));
+                    pending.push(function_end(instructions, pos) + 1);
+                }
+                Opcode::Invoke(function_name_index, parameter_size)
+                | Opcode::Coroutine(function_name_index, parameter_size)
+                | Opcode::Spawn(function_name_index, parameter_size) => {
+                    called.insert((function_name_index, parameter_size));
+                    pending.push(pos + 1);
+                }
+                _ => pending.push(pos + 1),
+            }
+        }
+
+        // Calls may resolve to any function of the name whose arity accepts them
+        pending.extend(
+            functions
+                .iter()
+                .filter(|(pos, function_name_index, parameter_size, arity)| {
+                    !reachable[pos + 1]
+                        && called.iter().any(|(called_name_index, argument_size)| {
+                            called_name_index == function_name_index
+                                && arity.accepts(*parameter_size, *argument_size)
+                        })
+                })
+                .map(|(pos, _, _, _)| pos + 1),
+        );
+
+        if pending.is_empty() {
+            return reachable;
+        }
+    }
+}
+
+/// Position of the `return` ending the function declared at `pos`, like `func` finds it at
+/// runtime. Last position if there is none.
+pub(crate) fn function_end(instructions: &[Opcode], pos: usize) -> usize {
+    let mut func_level = 0;
+
+    for (end, opcode) in instructions.iter().enumerate().skip(pos + 1) {
+        match opcode {
+            Opcode::Func(_, _, _, _, _) => func_level += 1,
+            Opcode::Return if func_level == 0 => return end,
+            Opcode::Return => func_level -= 1,
+            _ => {}
+        }
+    }
+
+    instructions.len().saturating_sub(1)
+}
+
+/// Drops constants no instruction refers to and rewrites indices of the remaining ones.
+/// Defaults of optional parameters are copied for each function.
+fn compact_constants(constants: &[Stackable], instructions: &mut [Opcode]) -> Vec<Stackable> {
+    let mut compacted = vec![];
+    let mut indices = HashMap::new();
+
+    for opcode in instructions.iter_mut() {
+        if let Opcode::Func(_, _, _, _, arity) = opcode {
+            // Defaults have to stay consecutive
+            if arity.optional > 0 {
+                let defaults =
+                    arity.defaults as usize..arity.defaults as usize + arity.optional as usize;
+
+                arity.defaults = match constants.get(defaults) {
+                    Some(defaults) => {
+                        compacted.extend_from_slice(defaults);
+                        (compacted.len() - defaults.len()) as u32
+                    }
+                    None => u32::MAX,
+                };
+            }
+        }
+
+        let index = match opcode {
+            Opcode::Ldc(index)
+            | Opcode::Func(index, _, _, _, _)
+            | Opcode::Invoke(index, _)
+            | Opcode::Coroutine(index, _)
+            | Opcode::Spawn(index, _)
+            | Opcode::TailInvoke(index, _)
+            | Opcode::SetGlobal(index)
+            | Opcode::GetGlobal(index)
+            | Opcode::Import(index) => index,
+            _ => continue,
+        };
+
+        // Unknown constants stay unknown
+        let constant = match constants.get(*index as usize) {
+            Some(constant) => constant,
+            None => {
+                *index = u32::MAX;
+                continue;
+            }
+        };
+
+        *index = *indices.entry(*index).or_insert_with(|| {
+            compacted.push(constant.clone());
+            compacted.len() as u32 - 1
+        });
+    }
+
+    compacted
+}
+
+/// VM running `instructions` in place of `vm`'s code, `positions` maps old positions onto new
+/// ones for debug info.
+fn rebuild(vm: &VM, constants: Vec<Stackable>, instructions: Vec<Opcode>, positions: &[u32]) -> VM {
+    let code = Code::new(instructions, vm.code().max_locals());
+    let mut optimized = vm.with_code(constants, code);
+
+    if let Some(debug_info) = vm.debug_info() {
+        // Lines whose instructions are all gone collapse onto the next line's position,
+        // which keeps the latter
+        let mut lines: Vec<(u32, u32)> = vec![];
+
+        for (pos, line) in debug_info.lines() {
+            let pos = positions[std::cmp::min(*pos as usize, positions.len() - 1)];
+
+            match lines.last_mut() {
+                Some(last) if last.0 == pos => *last = (pos, *line),
+                _ => lines.push((pos, *line)),
+            }
+        }
+
+        optimized =
+            optimized.with_debug_info(DebugInfo::new(debug_info.source().to_string(), lines));
+    }
+
+    optimized
+}
+
+/// Positions some `goto` jumps to.
+fn jump_targets(instructions: &[Opcode]) -> HashSet<u32> {
+    instructions
+        .iter()
+        .filter_map(|opcode| match opcode {
+            Opcode::Goto(target) => Some(*target),
+            _ => None,
+        })
+        .collect()
+}
+
+fn fold_constants(constants: &mut Vec<Stackable>, instructions: &mut [Opcode]) -> bool {
+    let targets = jump_targets(instructions);
+    let mut changed = false;
+
+    for pos in 0..instructions.len().saturating_sub(2) {
+        let (right, left) = match instructions[pos..pos + 3] {
+            [Opcode::Ldc(right), Opcode::Ldc(left), _] => (right, left),
+            _ => continue,
+        };
+
+        if targets.contains(&(pos as u32 + 1)) || targets.contains(&(pos as u32 + 2)) {
+            continue;
+        }
+
+        let (right, left) = match (constants.get(right as usize), constants.get(left as usize)) {
+            (Some(right), Some(left)) if right.is_numeric() && left.is_numeric() => {
+                (right.clone(), left.clone())
+            }
+            _ => continue,
+        };
+
+        if let Some(result) = Stackable::arithmetic(instructions[pos + 2], right, left) {
+            let index = match constants
+                .iter()
+                .position(|constant| constant.is_same_constant(&result))
+            {
+                Some(index) => index,
+                None => {
+                    constants.push(result);
+                    constants.len() - 1
+                }
+            };
+
+            instructions[pos..pos + 3].copy_from_slice(&[
+                Opcode::Ldc(index as u32),
+                Opcode::Nop,
+                Opcode::Nop,
+            ]);
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+fn remove_store_loads(instructions: &mut [Opcode]) -> bool {
+    let targets = jump_targets(instructions);
+    let scopes = function_scopes(instructions);
+
+    // Loads per local of each function
+    let mut loads = HashMap::new();
+
+    for (opcode, scope) in instructions.iter().zip(&scopes) {
+        if let Opcode::Load(index) = opcode {
+            *loads.entry((*scope, *index)).or_insert(0) += 1;
+        }
+    }
+
+    let mut changed = false;
+
+    for pos in 0..instructions.len().saturating_sub(1) {
+        if let [Opcode::Store(stored), Opcode::Load(loaded)] = instructions[pos..pos + 2] {
+            if stored == loaded
+                && loads[&(scopes[pos], loaded)] == 1
+                && !targets.contains(&(pos as u32 + 1))
+            {
+                instructions[pos..pos + 2].copy_from_slice(&[Opcode::Nop, Opcode::Nop]);
+                changed = true;
+            }
+        }
+    }
+
+    changed
+}
+
+/// Function each instruction belongs to, `0` for top level code. A `func` belongs to the
+/// enclosing function and its body ends at its first `return`.
+pub(crate) fn function_scopes(instructions: &[Opcode]) -> Vec<usize> {
+    let mut scopes = Vec::with_capacity(instructions.len());
+    let mut open = vec![0];
+    let mut next_scope = 1;
+
+    for opcode in instructions {
+        scopes.push(*open.last().unwrap());
+
+        match opcode {
+            Opcode::Func(_, _, _, _, _) => {
+                open.push(next_scope);
+                next_scope += 1;
+            }
+            Opcode::Return if open.len() > 1 => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+
+    scopes
+}
+
+fn thread_jumps(instructions: &mut [Opcode]) -> bool {
+    let mut changed = false;
+
+    for pos in 0..instructions.len() {
+        let target = match instructions[pos] {
+            Opcode::Goto(target) => target,
+            _ => continue,
+        };
+
+        // Guards against `goto` cycles
+        let mut visited = HashSet::from([pos as u32]);
+        let mut destination = target;
+
+        while visited.insert(destination) {
+            match instructions.get(destination as usize) {
+                Some(Opcode::Goto(next)) => destination = *next,
+                Some(Opcode::Nop) => destination += 1,
+                _ => break,
+            }
+        }
+
+        if destination == pos as u32 + 1 {
+            instructions[pos] = Opcode::Nop;
+            changed = true;
+        } else if destination != target {
+            instructions[pos] = Opcode::Goto(destination);
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+/// Removes instructions not marked in `keep` and rewrites `goto` targets, returns the new
+/// position of every old one, including the end of code. Removed instructions map onto the
+/// next remaining one.
+fn retain(instructions: &mut Vec<Opcode>, keep: &[bool]) -> Vec<u32> {
+    let mut moved = Vec::with_capacity(instructions.len() + 1);
+    let mut new_pos = 0;
+
+    for keep in keep {
+        moved.push(new_pos);
+
+        if *keep {
+            new_pos += 1;
+        }
+    }
+
+    moved.push(new_pos);
+
+    let mut keep = keep.iter();
+    instructions.retain(|_| *keep.next().unwrap());
+
+    for opcode in instructions.iter_mut() {
+        if let Opcode::Goto(target) = opcode {
+            // Jumps past the end stay as far past the new end
+            *target = match moved.get(*target as usize) {
+                Some(pos) => *pos,
+                None => *target - (moved.len() as u32 - 1 - new_pos),
+            };
+        }
+    }
+
+    moved
+}
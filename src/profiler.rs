@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    opcode::Opcode,
+    vm::{Process, ProcessStatus},
+};
+
+/// Time spent in a function, see [`Profile::functions`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionTime {
+    pub calls: u64,
+    /// Time spent in the function and everything it invoked.
+    pub inclusive: Duration,
+    /// Time spent in the function's own instructions.
+    pub exclusive: Duration,
+}
+
+/// Measurements collected by a [`Profiler`].
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Executions per opcode mnemonic.
+    pub opcode_counts: BTreeMap<&'static str, u64>,
+    /// Executions per instruction position.
+    pub position_counts: BTreeMap<u32, u64>,
+    /// Time per function, keyed by readable name such as `add/1`.
+    pub functions: BTreeMap<String, FunctionTime>,
+    /// Exclusive time per call stack, frames joined by `;` outermost first.
+    stacks: BTreeMap<String, Duration>,
+}
+
+impl Profile {
+    /// Folded stacks, one `frame;frame;frame nanoseconds` line per call stack, which
+    /// flame graph tools such as `flamegraph.pl` and `inferno` accept.
+    pub fn folded_stacks(&self) -> String {
+        let mut folded = String::new();
+
+        for (stack, time) in &self.stacks {
+            let _ = writeln!(folded, "{} {}", stack, time.as_nanos());
+        }
+
+        folded
+    }
+}
+
+/// Instrumenting profiler, it runs a [`Process`] one instruction at a time and attributes
+/// each instruction's time to the functions on the call stack.
+///
+/// Coroutines and spawned processes run by the profiled process are counted towards the
+/// instruction that resumed them.
+pub struct Profiler {
+    process: Process,
+    profile: Profile,
+}
+
+impl Profiler {
+    pub fn new(process: Process) -> Self {
+        let mut profiler = Self {
+            process,
+            profile: Profile::default(),
+        };
+
+        // Frames the process starts with count as called once
+        for name in profiler.stack_names() {
+            profiler.profile.functions.entry(name).or_default().calls += 1;
+        }
+
+        profiler
+    }
+
+    pub fn process(&self) -> &Process {
+        &self.process
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    pub fn into_profile(self) -> Profile {
+        self.profile
+    }
+
+    /// Runs the process until it finishes, faults or waits on its mailbox.
+    pub fn run(&mut self) -> &ProcessStatus {
+        loop {
+            match self.step() {
+                ProcessStatus::Running | ProcessStatus::Yielded => {}
+                _ => return self.process.status(),
+            }
+        }
+    }
+
+    /// Executes and measures a single instruction.
+    pub fn step(&mut self) -> &ProcessStatus {
+        let names = self.stack_names();
+        let depth = self.process.frames().len();
+        let pos = self.process.pos();
+        let opcode = pos.and_then(|pos| {
+            self.process
+                .vm()
+                .code()
+                .instructions()
+                .get(pos as usize)
+                .copied()
+        });
+
+        let start = Instant::now();
+        self.process.step();
+        let elapsed = start.elapsed();
+
+        if let (Some(pos), Some(opcode)) = (pos, opcode) {
+            *self.profile.opcode_counts.entry(opcode.name()).or_default() += 1;
+            *self.profile.position_counts.entry(pos).or_default() += 1;
+        }
+
+        if let Some(current) = names.last() {
+            self.profile
+                .functions
+                .entry(current.clone())
+                .or_default()
+                .exclusive += elapsed;
+            *self.profile.stacks.entry(names.join(";")).or_default() += elapsed;
+        }
+
+        // Recursive functions are counted once per instruction
+        for (index, name) in names.iter().enumerate() {
+            if !names[..index].contains(name) {
+                self.profile
+                    .functions
+                    .entry(name.clone())
+                    .or_default()
+                    .inclusive += elapsed;
+            }
+        }
+
+        // Tail calls replace the calling frame
+        let called = match opcode {
+            Some(Opcode::TailInvoke(_, _)) => self.process.frames().len() == depth,
+            _ => self.process.frames().len() > depth,
+        };
+
+        if called && !self.process.status().is_terminated() {
+            if let Some(callee) = self.stack_names().pop() {
+                self.profile.functions.entry(callee).or_default().calls += 1;
+            }
+        }
+
+        self.process.status()
+    }
+
+    /// Names of functions on the call stack, outermost first, made safe for folded stacks.
+    fn stack_names(&self) -> Vec<String> {
+        let vm = self.process.vm();
+
+        self.process
+            .frames()
+            .iter()
+            .map(|frame| vm.function_name(frame.function()).replace([';', ' '], "_"))
+            .collect()
+    }
+}
@@ -0,0 +1,821 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    error::VmError,
+    opcode::Opcode,
+    optimizer::{function_end, function_scopes},
+    vm::{FunctionSignature, HeapShare, OutputSink, Stackable, VmLimits, VM},
+};
+
+/// Register of a frame, local variable slots come first, operand stack slots follow.
+pub type Register = u32;
+
+/// Three-address instruction of the register backend, see [`RegisterCode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterOp {
+    /// `dst = constants[index]`
+    Const {
+        dst: Register,
+        index: u32,
+    },
+    /// `dst = src`
+    Move {
+        dst: Register,
+        src: Register,
+    },
+    /// Exchanges values of `a` and `b`
+    Swap {
+        a: Register,
+        b: Register,
+    },
+    /// `dst = left <opcode> right`, `left` being the operand the stack machine has on top
+    Arith {
+        opcode: Opcode,
+        dst: Register,
+        right: Register,
+        left: Register,
+    },
+    Dump {
+        src: Register,
+    },
+    /// Faults unless local `local` was stored, where `load` reads it before forwarding
+    Check {
+        local: Register,
+    },
+    Jump {
+        target: u32,
+    },
+    /// Declares a function whose body starts at `entry` and runs in `registers` registers,
+    /// execution continues at `next`
+    Func {
+        signature: FunctionSignature,
+        entry: u32,
+        next: u32,
+        locals: u32,
+        registers: u32,
+    },
+    /// Invokes a function with parameters in registers from `base`, its `returns` results
+    /// are written from `base` as well
+    Call {
+        signature: FunctionSignature,
+        base: Register,
+        returns: u32,
+    },
+    /// Returns `count` registers from `base`
+    Return {
+        base: Register,
+        count: u32,
+    },
+    SetGlobal {
+        src: Register,
+        name_index: u32,
+    },
+    GetGlobal {
+        dst: Register,
+        name_index: u32,
+    },
+}
+
+/// Code of a [`VM`] translated for the register backend, see
+/// [`VM::with_backend`](crate::vm::VM::with_backend).
+///
+/// Each stack slot at a statically known depth becomes a register, and `load`s are forwarded
+/// to their users, so `load a; load b; add; store c` is a single [`RegisterOp::Arith`] once
+/// `a` and `b` are known to be stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterCode {
+    ops: Vec<RegisterOp>,
+    /// Registers of top level code
+    registers: u32,
+    /// Deepest operand stack of any function
+    max_depth: usize,
+    /// Local variable slots any function uses
+    max_locals: u32,
+}
+
+/// Stack depths of translatable code.
+struct Analysis {
+    /// Depth before each reachable instruction
+    depths: Vec<Option<usize>>,
+    /// Deepest stack of each function, keyed like [`function_scopes`]
+    max_depths: HashMap<usize, usize>,
+    /// Values each function returns
+    returns: HashMap<FunctionSignature, u32>,
+    /// Local variable slots any function loads or stores
+    max_locals: u32,
+}
+
+/// Depths found in a single function, see [`Analysis`].
+struct ScopeAnalysis {
+    depths: HashMap<usize, usize>,
+    max_depth: usize,
+    /// Local variable slots loaded or stored
+    locals: u32,
+}
+
+/// Function or top level code being translated.
+struct Scope {
+    locals: u32,
+    /// Values `return` hands to the caller, the whole stack for top level code
+    return_size: Option<u32>,
+    registers: u32,
+    /// Stack slots whose value is still only in the local register it was loaded from
+    aliases: Vec<Option<Register>>,
+    /// Locals known to be stored on every path since the last jump target
+    stored: Vec<bool>,
+}
+
+impl Scope {
+    fn new(locals: u32, max_depth: usize, return_size: Option<u32>) -> Self {
+        Self {
+            locals,
+            return_size,
+            registers: locals + max_depth as u32,
+            aliases: vec![None; max_depth + 1],
+            stored: vec![false; locals as usize],
+        }
+    }
+
+    fn slot(&self, depth: usize) -> Register {
+        self.locals + depth as Register
+    }
+
+    /// Register holding the value of stack slot `depth`, which is consumed.
+    fn take(&mut self, depth: usize) -> Register {
+        self.aliases[depth]
+            .take()
+            .unwrap_or_else(|| self.slot(depth))
+    }
+
+    /// Copies slots below `depth` loaded from `local` before it is overwritten.
+    fn materialize_local(&mut self, ops: &mut Vec<RegisterOp>, local: Register, depth: usize) {
+        for slot in 0..depth {
+            if self.aliases[slot] == Some(local) {
+                self.aliases[slot] = None;
+                ops.push(RegisterOp::Move {
+                    dst: self.slot(slot),
+                    src: local,
+                });
+            }
+        }
+    }
+
+    /// Copies every slot still loaded from a local into its own register.
+    fn materialize(&mut self, ops: &mut Vec<RegisterOp>) {
+        for slot in 0..self.aliases.len() {
+            if let Some(src) = self.aliases[slot].take() {
+                ops.push(RegisterOp::Move {
+                    dst: self.slot(slot),
+                    src,
+                });
+            }
+        }
+    }
+}
+
+impl RegisterCode {
+    pub fn ops(&self) -> &[RegisterOp] {
+        &self.ops
+    }
+
+    /// Whether the code stays within stack and local limits wherever it goes. Otherwise the
+    /// stack backend runs it, which faults only once a limit is actually hit.
+    pub fn fits(&self, limits: &VmLimits) -> bool {
+        self.max_depth <= limits.max_stack_size && self.max_locals as usize <= limits.max_locals
+    }
+
+    /// Translates stack code, `None` if it can't be expressed with registers. That is the case
+    /// if stack depth at an instruction depends on the path taken or could underflow, `goto`
+    /// leaves a function, a local is outside its function's slots, a function declares
+    /// optional or rest parameters, or code uses coroutines, processes, tail calls or modules.
+    pub(crate) fn translate(vm: &VM) -> Option<Self> {
+        if !vm.module().imports.is_empty() {
+            return None;
+        }
+
+        let instructions = vm.code().instructions();
+        let scopes = function_scopes(instructions);
+        let analysis = analyze(vm, &scopes)?;
+        let targets = instructions
+            .iter()
+            .filter_map(|opcode| match opcode {
+                Opcode::Goto(target) => Some(*target as usize),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        let mut open = vec![Scope::new(
+            vm.code().max_locals(),
+            analysis.max_depths[&0],
+            None,
+        )];
+        // Positions of `return`s ending open functions
+        let mut ends = vec![];
+        let mut ops = vec![];
+        let mut positions = vec![0; instructions.len() + 1];
+        let mut pos = 0;
+
+        while pos < instructions.len() {
+            let scope = open.last_mut().unwrap();
+
+            if targets.contains(&pos) {
+                scope.materialize(&mut ops);
+                scope.stored.fill(false);
+            }
+
+            positions[pos] = ops.len() as u32;
+
+            let depth = match analysis.depths[pos] {
+                Some(depth) => depth,
+                None => {
+                    // Unreachable, declared functions are skipped as a whole
+                    if let Opcode::Func(_, _, _, _, _) = instructions[pos] {
+                        pos = function_end(instructions, pos);
+                    }
+
+                    if ends.last() == Some(&pos) {
+                        ends.pop();
+                        open.pop();
+                    }
+
+                    pos += 1;
+                    continue;
+                }
+            };
+
+            match instructions[pos] {
+                Opcode::Ldc(index) => {
+                    scope.aliases[depth] = None;
+                    ops.push(RegisterOp::Const {
+                        dst: scope.slot(depth),
+                        index,
+                    });
+                }
+                Opcode::Dump => ops.push(RegisterOp::Dump {
+                    src: scope.take(depth - 1),
+                }),
+                opcode @ (Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod) => {
+                    let mut dst = scope.slot(depth - 2);
+
+                    // Results stored right away go straight to the local
+                    if let Some(Opcode::Store(index)) = instructions.get(pos + 1) {
+                        if !targets.contains(&(pos + 1)) {
+                            scope.materialize_local(&mut ops, *index as Register, depth - 2);
+                            scope.stored[*index as usize] = true;
+                            dst = *index as Register;
+                            pos += 1;
+                            positions[pos] = ops.len() as u32;
+                        }
+                    }
+
+                    let right = scope.take(depth - 2);
+                    let left = scope.take(depth - 1);
+
+                    ops.push(RegisterOp::Arith {
+                        opcode,
+                        dst,
+                        right,
+                        left,
+                    });
+                }
+                Opcode::Dup => {
+                    scope.aliases[depth] = scope.aliases[depth - 1];
+
+                    if scope.aliases[depth].is_none() {
+                        ops.push(RegisterOp::Move {
+                            dst: scope.slot(depth),
+                            src: scope.slot(depth - 1),
+                        });
+                    }
+                }
+                Opcode::Swp => match (scope.aliases[depth - 2], scope.aliases[depth - 1]) {
+                    (None, None) => ops.push(RegisterOp::Swap {
+                        a: scope.slot(depth - 2),
+                        b: scope.slot(depth - 1),
+                    }),
+                    (Some(_), Some(_)) => scope.aliases.swap(depth - 2, depth - 1),
+                    (Some(alias), None) => {
+                        ops.push(RegisterOp::Move {
+                            dst: scope.slot(depth - 2),
+                            src: scope.slot(depth - 1),
+                        });
+                        scope.aliases[depth - 2] = None;
+                        scope.aliases[depth - 1] = Some(alias);
+                    }
+                    (None, Some(alias)) => {
+                        ops.push(RegisterOp::Move {
+                            dst: scope.slot(depth - 1),
+                            src: scope.slot(depth - 2),
+                        });
+                        scope.aliases[depth - 2] = Some(alias);
+                        scope.aliases[depth - 1] = None;
+                    }
+                },
+                Opcode::Store(index) => {
+                    scope.materialize_local(&mut ops, index as Register, depth - 1);
+
+                    let src = scope.take(depth - 1);
+
+                    scope.stored[index as usize] = true;
+                    ops.push(RegisterOp::Move {
+                        dst: index as Register,
+                        src,
+                    });
+                }
+                Opcode::Load(index) => {
+                    // Forwarded loads are read later, fault where the stack backend would
+                    if !scope.stored[index as usize] {
+                        scope.stored[index as usize] = true;
+                        ops.push(RegisterOp::Check {
+                            local: index as Register,
+                        });
+                    }
+
+                    scope.aliases[depth] = Some(index as Register);
+                }
+                Opcode::Goto(target) => {
+                    scope.materialize(&mut ops);
+                    ops.push(RegisterOp::Jump { target });
+                }
+                Opcode::Nop => {}
+                Opcode::Func(function_name_index, parameter_size, return_size, max_locals, _) => {
+                    scope.materialize(&mut ops);
+
+                    let body = Scope::new(
+                        max_locals,
+                        analysis.max_depths[&scopes[pos + 1]],
+                        Some(return_size as u32),
+                    );
+                    let end = function_end(instructions, pos);
+
+                    ends.push(end);
+                    ops.push(RegisterOp::Func {
+                        signature: FunctionSignature::new(function_name_index, parameter_size),
+                        // Positions are mapped once everything is emitted
+                        entry: pos as u32 + 1,
+                        next: end as u32 + 1,
+                        locals: max_locals,
+                        registers: body.registers,
+                    });
+                    open.push(body);
+                }
+                Opcode::Return => {
+                    scope.materialize(&mut ops);
+
+                    let count = scope.return_size.unwrap_or(depth as u32);
+
+                    ops.push(RegisterOp::Return {
+                        base: scope.slot(depth - count as usize),
+                        count,
+                    });
+
+                    // Ends the function unless at top level
+                    if ends.last() == Some(&pos) {
+                        ends.pop();
+                        open.pop();
+                    }
+                }
+                Opcode::Invoke(function_name_index, parameter_size) => {
+                    scope.materialize(&mut ops);
+
+                    let signature = FunctionSignature::new(function_name_index, parameter_size);
+
+                    ops.push(RegisterOp::Call {
+                        signature,
+                        base: scope.slot(depth - parameter_size as usize),
+                        returns: analysis.returns[&signature],
+                    });
+                }
+                Opcode::SetGlobal(name_index) => ops.push(RegisterOp::SetGlobal {
+                    src: scope.take(depth - 1),
+                    name_index,
+                }),
+                Opcode::GetGlobal(name_index) => {
+                    scope.aliases[depth] = None;
+                    ops.push(RegisterOp::GetGlobal {
+                        dst: scope.slot(depth),
+                        name_index,
+                    });
+                }
+                _ => return None,
+            }
+
+            pos += 1;
+        }
+
+        // Running off the end returns nothing
+        let top_level = open.pop()?;
+        positions[instructions.len()] = ops.len() as u32;
+        ops.push(RegisterOp::Return {
+            base: top_level.slot(0),
+            count: 0,
+        });
+
+        let position = |pos: u32| positions[std::cmp::min(pos as usize, instructions.len())];
+
+        for op in ops.iter_mut() {
+            match op {
+                RegisterOp::Jump { target } => *target = position(*target),
+                RegisterOp::Func { entry, next, .. } => {
+                    *entry = position(*entry);
+                    *next = position(*next);
+                }
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            ops,
+            registers: top_level.registers,
+            max_depth: analysis.max_depths.values().copied().max().unwrap_or(0),
+            max_locals: analysis.max_locals,
+        })
+    }
+}
+
+/// Finds stack depths of every function.
+fn analyze(vm: &VM, scopes: &[usize]) -> Option<Analysis> {
+    let instructions = vm.code().instructions();
+    // Top level code, then function bodies as scope, entry, entry depth, locals and
+    // return size
+    let mut pending = vec![(0, 0, 0, vm.code().max_locals(), None)];
+    let mut analysis = Analysis {
+        depths: vec![None; instructions.len()],
+        max_depths: HashMap::new(),
+        returns: HashMap::new(),
+        max_locals: 0,
+    };
+
+    for (pos, opcode) in instructions.iter().enumerate() {
+        if let Opcode::Func(function_name_index, parameter_size, return_size, max_locals, arity) =
+            opcode
+        {
+            // Optional and rest parameters are bound by the stack backend only
+            if instructions.get(function_end(instructions, pos)) != Some(&Opcode::Return)
+                || arity.optional > 0
+                || arity.rest
+            {
+                return None;
+            }
+
+            let signature = FunctionSignature::new(*function_name_index, *parameter_size);
+
+            // Same signature in other scopes must agree
+            let returns = *analysis
+                .returns
+                .entry(signature)
+                .or_insert(*return_size as u32);
+
+            if returns != *return_size as u32 {
+                return None;
+            }
+
+            pending.push((
+                scopes[pos + 1],
+                pos + 1,
+                *parameter_size as usize,
+                *max_locals,
+                Some(*return_size as usize),
+            ));
+        }
+    }
+
+    for (scope, entry, entry_depth, locals, return_size) in pending {
+        let scope_analysis = analyze_scope(
+            instructions,
+            scopes,
+            &analysis.returns,
+            scope,
+            (entry, entry_depth),
+            locals,
+            return_size,
+        )?;
+
+        for (pos, depth) in scope_analysis.depths {
+            analysis.depths[pos] = Some(depth);
+        }
+
+        analysis.max_depths.insert(scope, scope_analysis.max_depth);
+        analysis.max_locals = std::cmp::max(analysis.max_locals, scope_analysis.locals);
+    }
+
+    Some(analysis)
+}
+
+/// Stack depths of a function entered at `entry` with a stack of the given depth, `None` if
+/// it is not translatable.
+fn analyze_scope(
+    instructions: &[Opcode],
+    scopes: &[usize],
+    returns: &HashMap<FunctionSignature, u32>,
+    scope: usize,
+    entry: (usize, usize),
+    locals: u32,
+    return_size: Option<usize>,
+) -> Option<ScopeAnalysis> {
+    let mut analysis = ScopeAnalysis {
+        depths: HashMap::new(),
+        max_depth: entry.1,
+        locals: 0,
+    };
+    let mut pending = vec![entry];
+
+    while let Some((pos, depth)) = pending.pop() {
+        let opcode = match instructions.get(pos) {
+            Some(opcode) if scopes[pos] == scope => *opcode,
+            // Only top level code may run off the end
+            None if scope == 0 => continue,
+            _ => return None,
+        };
+
+        if let Some(known) = analysis.depths.get(&pos) {
+            if *known != depth {
+                return None;
+            }
+
+            continue;
+        }
+
+        analysis.depths.insert(pos, depth);
+
+        let (popped, pushed) = stack_effect(opcode, returns)?;
+
+        if depth < popped {
+            return None;
+        }
+
+        if let Opcode::Load(index) | Opcode::Store(index) = opcode {
+            if index as u32 >= locals {
+                return None;
+            }
+
+            analysis.locals = std::cmp::max(analysis.locals, index as u32 + 1);
+        }
+
+        let next_depth = depth - popped + pushed;
+        analysis.max_depth = std::cmp::max(analysis.max_depth, next_depth);
+
+        match opcode {
+            Opcode::Goto(target) => pending.push((target as usize, next_depth)),
+            Opcode::Return if depth < return_size.unwrap_or(0) => return None,
+            Opcode::Return => {}
+            Opcode::Func(_, _, _, _, _) => {
+                pending.push((function_end(instructions, pos) + 1, next_depth));
+            }
+            _ => pending.push((pos + 1, next_depth)),
+        }
+    }
+
+    Some(analysis)
+}
+
+/// Stack effect of an instruction supported by the register backend, as values popped and
+/// pushed. `None` if it is not supported.
+fn stack_effect(
+    opcode: Opcode,
+    returns: &HashMap<FunctionSignature, u32>,
+) -> Option<(usize, usize)> {
+    Some(match opcode {
+        Opcode::Ldc(_) | Opcode::Load(_) | Opcode::GetGlobal(_) => (0, 1),
+        Opcode::Dump | Opcode::Store(_) | Opcode::SetGlobal(_) => (1, 0),
+        Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod => (2, 1),
+        Opcode::Dup => (1, 2),
+        Opcode::Swp => (2, 2),
+        Opcode::Goto(_) | Opcode::Nop | Opcode::Func(_, _, _, _, _) | Opcode::Return => (0, 0),
+        Opcode::Invoke(function_name_index, parameter_size) => {
+            let signature = FunctionSignature::new(function_name_index, parameter_size);
+
+            (parameter_size as usize, *returns.get(&signature)? as usize)
+        }
+        _ => return None,
+    })
+}
+
+/// Activation record of the register backend.
+struct RegisterFrame {
+    registers: Vec<Option<Stackable>>,
+    /// Declared functions as entry, locals and registers
+    functions: HashMap<FunctionSignature, (u32, u32, u32)>,
+    pos: u32,
+    /// Register of the caller results are written from
+    result_base: Register,
+}
+
+impl RegisterFrame {
+    fn read(&self, register: Register) -> Result<&Stackable, VmError> {
+        self.registers[register as usize]
+            .as_ref()
+            .ok_or(VmError::UninitializedLocal {
+                index: register as usize,
+            })
+    }
+
+    /// Writes `value` to `register`, charging it to `heap` in place of the value it replaces.
+    fn write(
+        &mut self,
+        heap: &mut HeapShare,
+        limits: &VmLimits,
+        register: Register,
+        value: Stackable,
+    ) -> Result<(), VmError> {
+        if let Stackable::String(s) = &value {
+            if s.len() > limits.max_string_length {
+                return Err(VmError::StringTooLong {
+                    length: s.len(),
+                    limit: limits.max_string_length,
+                });
+            }
+        }
+
+        heap.allocate(value.heap_size(), limits.max_heap_bytes)?;
+
+        if let Some(previous) = self.registers[register as usize].replace(value) {
+            heap.release(previous.heap_size());
+        }
+
+        Ok(())
+    }
+
+    fn heap_size(&self) -> usize {
+        self.registers
+            .iter()
+            .flatten()
+            .map(Stackable::heap_size)
+            .sum()
+    }
+}
+
+/// Runs register code from its start, like [`Process::run`](crate::vm::Process::run) runs a
+/// fresh process. The code has to [fit](RegisterCode::fits) the VM's limits, call depth,
+/// string length and heap limits are checked as it runs. Heap use counts values held in
+/// registers, which aren't the copies the operand stack would hold, so a program close to
+/// the heap limit may fault at another instruction than on the stack backend.
+pub(crate) fn execute(
+    vm: &VM,
+    code: &RegisterCode,
+    output: Option<&OutputSink>,
+) -> Result<Vec<Stackable>, VmError> {
+    let constant_name = |index: u32| match vm.constants().get(index as usize) {
+        Some(name) => format!("{:?}", name),
+        None => "<Unknown name>".to_string(),
+    };
+    let limits = vm.limits();
+    let mut heap = HeapShare::new(vm.heap().clone());
+    let mut frames = vec![RegisterFrame {
+        registers: vec![None; code.registers as usize],
+        functions: HashMap::new(),
+        pos: 0,
+        result_base: 0,
+    }];
+
+    loop {
+        let frame = frames.last_mut().unwrap();
+        let op = code.ops[frame.pos as usize];
+        frame.pos += 1;
+
+        match op {
+            RegisterOp::Const { dst, index } => {
+                let value = vm.constants().get(index as usize).cloned().ok_or(
+                    VmError::UnknownConstant {
+                        index: index as usize,
+                    },
+                )?;
+
+                frame.write(&mut heap, limits, dst, value)?;
+            }
+            RegisterOp::Move { dst, src } => {
+                let value = frame.read(src)?.clone();
+
+                frame.write(&mut heap, limits, dst, value)?;
+            }
+            RegisterOp::Swap { a, b } => frame.registers.swap(a as usize, b as usize),
+            RegisterOp::Arith {
+                opcode,
+                dst,
+                right,
+                left,
+            } => {
+                let (right, left) = (frame.read(right)?, frame.read(left)?);
+
+                if let Some(operand) = [right, left].into_iter().find(|s| !s.is_numeric()) {
+                    return Err(VmError::InvalidOperand {
+                        value: format!("{:?}", operand),
+                    });
+                }
+
+                let result = Stackable::arithmetic(opcode, right.clone(), left.clone()).unwrap();
+
+                frame.write(&mut heap, limits, dst, result)?;
+            }
+            RegisterOp::Dump { src } => {
+                let item = frame.read(src)?;
+
+                match output {
+                    Some(output) => {
+                        let mut output = output
+                            .lock()
+                            .unwrap_or_else(|poisoned| poisoned.into_inner());
+                        // Output is best effort, a broken sink must not fault the process
+                        let _ = writeln!(output, "{:?}", item);
+                    }
+                    None => println!("{:?}", item),
+                }
+            }
+            RegisterOp::Check { local } => {
+                frame.read(local)?;
+            }
+            RegisterOp::Jump { target } => frame.pos = target,
+            RegisterOp::Func {
+                signature,
+                entry,
+                next,
+                locals,
+                registers,
+            } => {
+                frame
+                    .functions
+                    .insert(signature, (entry, locals, registers));
+                frame.pos = next;
+            }
+            RegisterOp::Call {
+                signature,
+                base,
+                returns: _,
+            } => {
+                let (entry, locals, registers) =
+                    *frame
+                        .functions
+                        .get(&signature)
+                        .ok_or_else(|| VmError::UnknownFunction {
+                            name: constant_name(signature.function_name_index()),
+                            parameter_size: signature.parameter_size(),
+                        })?;
+                let limit = limits.max_call_depth;
+
+                if frames.len() >= limit {
+                    return Err(VmError::CallDepthExceeded { limit });
+                }
+
+                let frame = frames.last_mut().unwrap();
+                let mut callee = RegisterFrame {
+                    registers: vec![None; registers as usize],
+                    functions: frame.functions.clone(),
+                    pos: entry,
+                    result_base: base,
+                };
+
+                for parameter in 0..signature.parameter_size() as usize {
+                    callee.registers[locals as usize + parameter] =
+                        frame.registers[base as usize + parameter].take();
+                }
+
+                frames.push(callee);
+            }
+            RegisterOp::Return { base, count } => {
+                let mut callee = frames.pop().unwrap();
+                let values = callee.registers[base as usize..(base + count) as usize]
+                    .iter_mut()
+                    .map(|value| value.take().unwrap())
+                    .collect::<Vec<_>>();
+
+                heap.release(callee.heap_size());
+                heap.release(values.iter().map(Stackable::heap_size).sum());
+
+                let caller = match frames.last_mut() {
+                    Some(caller) => caller,
+                    None => return Ok(values),
+                };
+
+                for (offset, value) in values.into_iter().enumerate() {
+                    caller.write(
+                        &mut heap,
+                        limits,
+                        callee.result_base + offset as Register,
+                        value,
+                    )?;
+                }
+            }
+            RegisterOp::SetGlobal { src, name_index } => {
+                let value = frame.read(src)?.clone();
+                let counter = heap.counter();
+                counter.charge(value.heap_size(), limits.max_heap_bytes)?;
+
+                if let Some(previous) = vm.lock_globals().insert(name_index, value) {
+                    counter.discharge(previous.heap_size());
+                }
+            }
+            RegisterOp::GetGlobal { dst, name_index } => {
+                let value = vm.lock_globals().get(&name_index).cloned();
+
+                match value {
+                    Some(value) => frame.write(&mut heap, limits, dst, value)?,
+                    None => {
+                        return Err(VmError::UndefinedGlobal {
+                            name: constant_name(name_index),
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
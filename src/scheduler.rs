@@ -0,0 +1,182 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    error::VmError,
+    vm::{HeapCounter, Process, ProcessStatus, Stackable, VM},
+};
+
+/// Identifier of a process managed by a [`Scheduler`], pushed onto stack as an Int.
+pub type Pid = u32;
+
+/// State shared between a [`Scheduler`] and the processes it runs, so `spawn`, `send`
+/// and `receive` can reach other processes.
+#[derive(Default)]
+pub(crate) struct SchedulerState {
+    next_pid: Pid,
+    mailboxes: HashMap<Pid, VecDeque<Stackable>>,
+    spawned: Vec<(Pid, Process)>,
+    /// Heap of the scheduler's VM, messages count on it until they are received
+    heap: HeapCounter,
+}
+
+impl SchedulerState {
+    /// Returns false if `pid` was never spawned. Messages to finished processes are dropped.
+    /// Fails if the message doesn't fit in `limit` heap bytes.
+    pub(crate) fn send(
+        &mut self,
+        pid: Pid,
+        message: Stackable,
+        limit: usize,
+    ) -> Result<bool, VmError> {
+        if pid >= self.next_pid {
+            return Ok(false);
+        }
+
+        if let Some(mailbox) = self.mailboxes.get_mut(&pid) {
+            self.heap.charge(message.heap_size(), limit)?;
+            mailbox.push_back(message);
+        }
+
+        Ok(true)
+    }
+
+    pub(crate) fn receive(&mut self, pid: Pid) -> Option<Stackable> {
+        let message = self.mailboxes.get_mut(&pid)?.pop_front()?;
+        self.heap.discharge(message.heap_size());
+
+        Some(message)
+    }
+
+    /// Drops the mailbox of a terminated process along with messages it never received.
+    fn close(&mut self, pid: Pid) {
+        for message in self.mailboxes.remove(&pid).unwrap_or_default() {
+            self.heap.discharge(message.heap_size());
+        }
+    }
+
+    fn has_mail(&self, pid: Pid) -> bool {
+        self.mailboxes
+            .get(&pid)
+            .map(|mailbox| !mailbox.is_empty())
+            .unwrap_or(false)
+    }
+}
+
+pub(crate) type SchedulerHandle = Arc<Mutex<SchedulerState>>;
+
+pub(crate) fn lock(handle: &SchedulerHandle) -> MutexGuard<'_, SchedulerState> {
+    handle
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Attaches the process to the scheduler behind `handle`, it gets its turn once the
+/// scheduler admits it.
+pub(crate) fn register(handle: &SchedulerHandle, mut process: Process) -> Pid {
+    // One guard, so processes registering at once never share a pid
+    let mut state = lock(handle);
+    let pid = state.next_pid;
+
+    process.attach(pid, handle.clone());
+    state.next_pid += 1;
+    state.mailboxes.insert(pid, VecDeque::new());
+    state.spawned.push((pid, process));
+
+    pid
+}
+
+/// Runs many [`Process`]es on the current thread, interleaving them round-robin with a
+/// fixed budget of instructions, i.e. reductions, per turn. Scheduling is deterministic.
+pub struct Scheduler {
+    state: SchedulerHandle,
+    run_queue: VecDeque<(Pid, Process)>,
+    statuses: BTreeMap<Pid, ProcessStatus>,
+    reductions: usize,
+}
+
+impl Scheduler {
+    /// Creates a scheduler whose main process, pid 0, runs the VM's code from the start.
+    /// Processes get at least one reduction per turn, so `run` always makes progress.
+    pub fn new(vm: VM, reductions: usize) -> Self {
+        let state = SchedulerState {
+            heap: vm.heap().clone(),
+            ..SchedulerState::default()
+        };
+        let mut scheduler = Self {
+            state: Arc::new(Mutex::new(state)),
+            run_queue: VecDeque::new(),
+            statuses: BTreeMap::new(),
+            reductions: reductions.max(1),
+        };
+
+        scheduler.spawn(Process::new_process(vm, 0));
+        scheduler
+    }
+
+    pub fn spawn(&mut self, process: Process) -> Pid {
+        register(&self.state, process)
+    }
+
+    /// Delivers a message from the host, returns false if `pid` was never spawned. Messages
+    /// from the host are not limited by [`VmLimits::max_heap_bytes`](crate::vm::VmLimits).
+    pub fn send(&mut self, pid: Pid, message: Stackable) -> bool {
+        lock(&self.state)
+            .send(pid, message, usize::MAX)
+            .unwrap_or(true)
+    }
+
+    pub fn status(&self, pid: Pid) -> Option<&ProcessStatus> {
+        self.statuses.get(&pid)
+    }
+
+    /// Gives every live process one turn.
+    pub fn tick(&mut self) {
+        self.admit_spawned();
+
+        for _ in 0..self.run_queue.len() {
+            let (pid, mut process) = self.run_queue.pop_front().unwrap();
+            let status = process.run_for(self.reductions).clone();
+
+            if !status.is_terminated() {
+                self.run_queue.push_back((pid, process));
+            } else {
+                lock(&self.state).close(pid);
+            }
+
+            self.statuses.insert(pid, status);
+            self.admit_spawned();
+        }
+    }
+
+    /// Runs until every process finished or waits on an empty mailbox, then returns
+    /// statuses of all processes ever spawned.
+    pub fn run(&mut self) -> &BTreeMap<Pid, ProcessStatus> {
+        self.admit_spawned();
+
+        while self.is_runnable() {
+            self.tick();
+        }
+
+        &self.statuses
+    }
+
+    fn is_runnable(&self) -> bool {
+        let state = lock(&self.state);
+
+        self.run_queue.iter().any(|(pid, _)| {
+            self.statuses.get(pid) != Some(&ProcessStatus::Waiting) || state.has_mail(*pid)
+        })
+    }
+
+    fn admit_spawned(&mut self) {
+        let spawned = std::mem::take(&mut lock(&self.state).spawned);
+
+        for (pid, process) in spawned {
+            self.statuses.insert(pid, process.status().clone());
+            self.run_queue.push_back((pid, process));
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::Write;
+
+use enum_index::EnumIndex;
+
+use crate::{opcode::Opcode, vm::Stackable};
+
+/// An executed instruction, reported to a [`Tracer`] by [`Process::step`](crate::vm::Process::step).
+#[derive(Debug)]
+pub struct TraceEvent<'a> {
+    /// Number of frames when the instruction started executing.
+    pub depth: usize,
+    pub pos: u32,
+    pub opcode: Opcode,
+    /// Operand stack of the executing frame before the instruction.
+    pub stack_before: &'a [Stackable],
+    /// Operand stack of the innermost frame after the instruction, which belongs to another
+    /// frame after `invoke` and `return`.
+    pub stack_after: &'a [Stackable],
+}
+
+/// Receives every instruction a process executes, see
+/// [`Process::set_tracer`](crate::vm::Process::set_tracer).
+pub trait Tracer: Send {
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+/// Writes one readable line per instruction, e.g. `#1 9: Add [10, 10] -> [20]`.
+pub struct TextTracer<W> {
+    output: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<W: Write + Send> Tracer for TextTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        // Tracing is best effort, a broken output must not fault the process
+        let _ = writeln!(
+            self.output,
+            "#{} {}: {:?} {:?} -> {:?}",
+            event.depth, event.pos, event.opcode, event.stack_before, event.stack_after
+        );
+    }
+}
+
+/// Writes a fixed 15 bytes record per instruction, all big endian: </br>
+/// \[u32 pos, u8 opcode index, u16 depth, u32 stack size before, u32 stack size after\]
+pub struct BinaryTracer<W> {
+    output: W,
+}
+
+impl<W: Write> BinaryTracer<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<W: Write + Send> Tracer for BinaryTracer<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        let mut record = [0u8; 15];
+
+        record[0..4].copy_from_slice(&event.pos.to_be_bytes());
+        record[4] = event.opcode.enum_index() as u8;
+        record[5..7].copy_from_slice(&(event.depth.min(u16::MAX as usize) as u16).to_be_bytes());
+        record[7..11].copy_from_slice(&(event.stack_before.len() as u32).to_be_bytes());
+        record[11..15].copy_from_slice(&(event.stack_after.len() as u32).to_be_bytes());
+
+        let _ = self.output.write_all(&record);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use crate::{
+    error::VerifyError,
+    opcode::Opcode,
+    optimizer::{function_end, function_scopes},
+    vm::{Stackable, VM},
+};
+
+/// Checks stack usage of every function body and top level code without running it:
+///
+/// - No instruction consumes more items than the stack holds
+/// - Every path reaching an instruction leaves the same stack depth
+/// - Every `return` of a function leaves at least its declared return size
+/// - Called functions are declared and `tailinvoke` keeps the return size
+///
+/// Calls of imported functions are only checked for their parameters, the stack depth after
+/// `invoke` of one is unknown.
+///
+/// Functions are resolved by name and arity among every `func` of the code, like `invoke`
+/// resolves them in scope. Depths after `resume` are unknown, as they depend on whether the
+/// coroutine yields or returns, so checks are skipped until paths meet a known depth.
+pub fn verify(vm: &VM) -> Result<(), VerifyError> {
+    let instructions = vm.code().instructions();
+    let scopes = function_scopes(instructions);
+
+    verify_scope(vm, &scopes, 0, 0, None)?;
+
+    for (pos, opcode) in instructions.iter().enumerate() {
+        if let Opcode::Func(_, parameter_size, return_size, _, arity) = opcode {
+            let entry_depth = *parameter_size as usize + arity.rest as usize;
+
+            verify_scope(vm, &scopes, pos + 1, entry_depth, Some(*return_size))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Return size of the function a call of `parameter_size` parameters resolves to, the exact
+/// signature or else the one of fewest parameters whose arity accepts them.
+pub(crate) fn declared_return_size(
+    instructions: &[Opcode],
+    function_name_index: u32,
+    parameter_size: u8,
+) -> Option<u8> {
+    let mut candidates = instructions
+        .iter()
+        .filter_map(|opcode| match opcode {
+            Opcode::Func(name_index, size, return_size, _, arity)
+                if *name_index == function_name_index && arity.accepts(*size, parameter_size) =>
+            {
+                Some((*size != parameter_size, *size, *return_size))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    candidates.sort();
+
+    candidates.first().map(|(_, _, return_size)| *return_size)
+}
+
+/// Whether calls of the function refer to an import of the VM's module.
+fn is_imported(vm: &VM, function_name_index: u32, parameter_size: u8) -> bool {
+    vm.module().imports.iter().any(|import| {
+        import.parameter_size == parameter_size
+            && vm.constants().get(function_name_index as usize)
+                == Some(&Stackable::String(import.function.clone()))
+    })
+}
+
+/// Verifies the function body or top level code starting at `entry`.
+fn verify_scope(
+    vm: &VM,
+    scopes: &[usize],
+    entry: usize,
+    entry_depth: usize,
+    return_size: Option<u8>,
+) -> Result<(), VerifyError> {
+    let instructions = vm.code().instructions();
+    let scope = scopes.get(entry).copied().unwrap_or(0);
+    // Depth before each visited instruction, `None` if unknown
+    let mut depths: HashMap<usize, Option<usize>> = HashMap::new();
+    let mut pending = vec![(entry, Some(entry_depth))];
+
+    while let Some((pos, depth)) = pending.pop() {
+        let opcode = match instructions.get(pos) {
+            Some(opcode) if scopes[pos] == scope => *opcode,
+            // Running off the end or jumping out of the function is left to runtime
+            _ => continue,
+        };
+
+        match (depths.get(&pos), depth) {
+            (Some(Some(known)), Some(depth)) if *known != depth => {
+                return Err(VerifyError::InconsistentStack {
+                    pos: pos as u32,
+                    depth: *known,
+                    other_depth: depth,
+                });
+            }
+            // Unknown depth is refined once a known one arrives
+            (Some(None), Some(_)) => {}
+            (Some(_), _) => continue,
+            (None, _) => {}
+        }
+
+        depths.insert(pos, depth);
+
+        let (popped, pushed) = stack_effect(vm, pos, opcode)?;
+
+        if let Some(depth) = depth {
+            if depth < popped {
+                return Err(VerifyError::StackUnderflow {
+                    pos: pos as u32,
+                    required_size: popped,
+                    actual_size: depth,
+                });
+            }
+        }
+
+        let next_depth = match (depth, pushed) {
+            (Some(depth), Some(pushed)) => Some(depth - popped + pushed),
+            _ => None,
+        };
+
+        match opcode {
+            Opcode::Goto(target) => pending.push((target as usize, next_depth)),
+            Opcode::Return => match (return_size, depth) {
+                (Some(return_size), Some(depth)) if depth < return_size as usize => {
+                    return Err(VerifyError::MissingReturnValues {
+                        pos: pos as u32,
+                        return_size,
+                        actual_size: depth,
+                    });
+                }
+                _ => {}
+            },
+            Opcode::TailInvoke(function_name_index, parameter_size) => {
+                let callee_return_size = if is_imported(vm, function_name_index, parameter_size) {
+                    None
+                } else {
+                    declared_return_size(instructions, function_name_index, parameter_size)
+                };
+
+                match (return_size, callee_return_size) {
+                    (Some(return_size), Some(callee_return_size))
+                        if return_size != callee_return_size =>
+                    {
+                        return Err(VerifyError::ReturnSizeMismatch {
+                            pos: pos as u32,
+                            return_size,
+                            callee_return_size,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Opcode::Func(_, _, _, _, _) => {
+                pending.push((function_end(instructions, pos) + 1, next_depth));
+            }
+            _ => pending.push((pos + 1, next_depth)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Stack effect of the instruction at `pos` as items popped and pushed, `None` pushed if it
+/// is not known.
+fn stack_effect(
+    vm: &VM,
+    pos: usize,
+    opcode: Opcode,
+) -> Result<(usize, Option<usize>), VerifyError> {
+    Ok(match opcode {
+        Opcode::Ldc(_) | Opcode::Load(_) | Opcode::GetGlobal(_) | Opcode::Receive | Opcode::Pid => {
+            (0, Some(1))
+        }
+        Opcode::Dump | Opcode::Store(_) | Opcode::SetGlobal(_) | Opcode::Yield => (1, Some(0)),
+        Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod => (2, Some(1)),
+        Opcode::Dup => (1, Some(2)),
+        Opcode::Swp => (2, Some(2)),
+        Opcode::Send => (2, Some(0)),
+        Opcode::Done => (1, Some(1)),
+        Opcode::Resume => (1, None),
+        Opcode::Goto(_)
+        | Opcode::Nop
+        | Opcode::Func(_, _, _, _, _)
+        | Opcode::Return
+        | Opcode::Import(_) => (0, Some(0)),
+        Opcode::Invoke(function_name_index, parameter_size)
+        | Opcode::Coroutine(function_name_index, parameter_size)
+        | Opcode::Spawn(function_name_index, parameter_size)
+        | Opcode::TailInvoke(function_name_index, parameter_size)
+            if is_imported(vm, function_name_index, parameter_size) =>
+        {
+            let pushed = match opcode {
+                // Declared by the imported module, which is only known at runtime
+                Opcode::Invoke(_, _) => None,
+                Opcode::TailInvoke(_, _) => Some(0),
+                _ => Some(1),
+            };
+
+            (parameter_size as usize, pushed)
+        }
+        Opcode::Invoke(function_name_index, parameter_size)
+        | Opcode::Coroutine(function_name_index, parameter_size)
+        | Opcode::Spawn(function_name_index, parameter_size)
+        | Opcode::TailInvoke(function_name_index, parameter_size) => {
+            let return_size = declared_return_size(
+                vm.code().instructions(),
+                function_name_index,
+                parameter_size,
+            )
+            .ok_or_else(|| VerifyError::UnknownFunction {
+                pos: pos as u32,
+                name: vm.constant_name(function_name_index),
+                parameter_size,
+            })?;
+
+            let pushed = match opcode {
+                Opcode::Invoke(_, _) => return_size as usize,
+                Opcode::TailInvoke(_, _) => 0,
+                // Coroutine or pid
+                _ => 1,
+            };
+
+            (parameter_size as usize, Some(pushed))
+        }
+    })
+}
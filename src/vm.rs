@@ -1,13 +1,30 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::HashMap,
     fmt::Debug,
     hash::Hash,
-    rc::Rc,
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
-use enum_index::EnumIndex;
+use crate::{
+    closure::CompiledCode,
+    coroutine::{Coroutine, CoroutineRef, CoroutineState},
+    coverage::Coverage,
+    dispatch::{self, Instruction},
+    error::VmError,
+    loader::Loader,
+    module::{Module, ModuleResolver},
+    opcode::{Arity, Opcode},
+    optimizer::function_scopes,
+    register::{self, RegisterCode},
+    scheduler::{self, Pid, Scheduler, SchedulerHandle},
+};
 
-use crate::opcode::Opcode;
+#[cfg(feature = "trace")]
+use crate::trace::{TraceEvent, Tracer};
 
 macro_rules! make_stackable {
     ($precedence:expr, $expr:expr) => {
@@ -36,7 +53,7 @@ macro_rules! get_value {
             Stackable::Long(l) => l as f64,
             Stackable::Float(f) => f as f64,
             Stackable::Double(d) => d,
-            Stackable::String(_) => unreachable!(),
+            Stackable::String(_) | Stackable::Coroutine(_) | Stackable::Array(_) => unreachable!(),
         }
     };
 }
@@ -48,6 +65,9 @@ pub enum Stackable {
     Float(f32),
     Double(f64),
     String(String),
+    Coroutine(CoroutineRef),
+    /// Arguments collected by a rest parameter
+    Array(Vec<Stackable>),
 }
 
 impl Stackable {
@@ -58,6 +78,36 @@ impl Stackable {
             Self::Float(_) => 2,
             Self::Double(_) => 3,
             Self::String(_) => panic!("String cannot be promoted."),
+            Self::Coroutine(_) => panic!("Coroutine cannot be promoted."),
+            Self::Array(_) => panic!("Array cannot be promoted."),
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Self::Int(_) | Self::Long(_) | Self::Float(_) | Self::Double(_)
+        )
+    }
+
+    /// Whether both are the same constant, unlike `==` floats are compared bit for bit so
+    /// `-0.0` and `0.0` stay apart and NaN equals itself.
+    pub(crate) fn is_same_constant(&self, other: &Stackable) -> bool {
+        match (self, other) {
+            (Self::Float(a), Self::Float(b)) => a.to_bits() == b.to_bits(),
+            (Self::Double(a), Self::Double(b)) => a.to_bits() == b.to_bits(),
+            _ => self == other,
+        }
+    }
+
+    /// Approximate bytes this value occupies, counted against [`VmLimits::max_heap_bytes`].
+    pub(crate) fn heap_size(&self) -> usize {
+        match self {
+            Self::String(s) => std::mem::size_of::<Stackable>() + s.len(),
+            Self::Array(values) => {
+                std::mem::size_of::<Stackable>() + values.iter().map(Self::heap_size).sum::<usize>()
+            }
+            _ => std::mem::size_of::<Stackable>(),
         }
     }
 
@@ -86,6 +136,27 @@ impl Stackable {
             )
         }
     }
+
+    /// Result of arithmetic `opcode` on numeric operands, `left` being the top of stack.
+    /// `None` if `opcode` is not arithmetic.
+    pub(crate) fn arithmetic(
+        opcode: Opcode,
+        right: Stackable,
+        left: Stackable,
+    ) -> Option<Stackable> {
+        let (promoted_right, promoted_left, precedence) = Stackable::promote(right, left);
+        let (left, right) = (get_value!(promoted_left), get_value!(promoted_right));
+        let result_value = match opcode {
+            Opcode::Add => left + right,
+            Opcode::Sub => left - right,
+            Opcode::Mul => left * right,
+            Opcode::Div => left / right,
+            Opcode::Mod => left % right,
+            _ => return None,
+        };
+
+        Some(make_stackable!(precedence, result_value))
+    }
 }
 
 impl Debug for Stackable {
@@ -96,345 +167,1990 @@ impl Debug for Stackable {
             Self::Float(fl) => f.write_fmt(format_args!("{}F", fl)),
             Self::Double(d) => f.write_fmt(format_args!("{}D", d)),
             Self::String(s) => f.write_str(s),
+            Self::Coroutine(c) => c.fmt(f),
+            Self::Array(values) => f.debug_list().entries(values).finish(),
         }
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct FunctionSignature {
     function_name_index: u32,
     parameter_size: u8,
 }
 
+impl FunctionSignature {
+    pub fn new(function_name_index: u32, parameter_size: u8) -> Self {
+        Self {
+            function_name_index,
+            parameter_size,
+        }
+    }
+
+    pub fn function_name_index(&self) -> u32 {
+        self.function_name_index
+    }
+
+    pub fn parameter_size(&self) -> u8 {
+        self.parameter_size
+    }
+}
+
+/// Resource limits enforced by every [`Process`] spawned from a [`VM`], used to sandbox
+/// untrusted bytecode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmLimits {
+    /// Maximum items on a single operand stack.
+    pub max_stack_size: usize,
+    /// Maximum local variable slots per frame, declared slots beyond it are unusable.
+    pub max_locals: usize,
+    /// Maximum nested `invoke` depth.
+    pub max_call_depth: usize,
+    /// Maximum byte length of a string value.
+    pub max_string_length: usize,
+    /// Maximum bytes held by live values across all processes of a VM, including local slots
+    /// of their frames, globals and messages waiting in mailboxes.
+    pub max_heap_bytes: usize,
+}
+
+impl Default for VmLimits {
+    fn default() -> Self {
+        Self {
+            max_stack_size: 1 << 16,
+            max_locals: 1 << 16,
+            max_call_depth: 1 << 10,
+            max_string_length: 1 << 20,
+            max_heap_bytes: 1 << 26,
+        }
+    }
+}
+
+/// Bytes held by live values of a VM's processes, globals and mailboxes, counted against
+/// [`VmLimits::max_heap_bytes`]. Clones count on the same total.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HeapCounter(Arc<AtomicUsize>);
+
+impl HeapCounter {
+    pub(crate) fn usage(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Whether `size` more bytes stay within `limit`.
+    pub(crate) fn fits(&self, size: usize, limit: usize) -> bool {
+        self.usage().saturating_add(size) <= limit
+    }
+
+    pub(crate) fn charge(&self, size: usize, limit: usize) -> Result<(), VmError> {
+        let mut requested = 0;
+
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                requested = usage.saturating_add(size);
+                (requested <= limit).then_some(requested)
+            })
+            .map(|_| ())
+            .map_err(|_| VmError::HeapExhausted { requested, limit })
+    }
+
+    /// Charges `size` regardless of any limit, for values the host hands in.
+    pub(crate) fn charge_unlimited(&self, size: usize) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                Some(usage.saturating_add(size))
+            });
+    }
+
+    pub(crate) fn discharge(&self, size: usize) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                Some(usage.saturating_sub(size))
+            });
+    }
+}
+
+/// Part of a [`HeapCounter`] held by the values of one process. Cloning a process charges its
+/// values once more, dropping it releases them.
 #[derive(Debug)]
-pub struct VM {
-    constants: Vec<Stackable>,
-    code: Code,
+pub(crate) struct HeapShare {
+    counter: HeapCounter,
+    usage: usize,
 }
 
-impl VM {
-    pub fn new_vm(constants: Vec<Stackable>, code: Code) -> Self {
-        VM { constants, code }
+impl HeapShare {
+    pub(crate) fn new(counter: HeapCounter) -> Self {
+        Self { counter, usage: 0 }
     }
 
-    pub fn execute(self) {
-        let main_proc = Process::new_process(self, 0);
+    pub(crate) fn counter(&self) -> &HeapCounter {
+        &self.counter
+    }
+
+    pub(crate) fn allocate(&mut self, size: usize, limit: usize) -> Result<(), VmError> {
+        self.counter.charge(size, limit)?;
+        self.usage += size;
 
-        main_proc.run();
+        Ok(())
+    }
+
+    pub(crate) fn release(&mut self, size: usize) {
+        let size = std::cmp::min(size, self.usage);
+
+        self.usage -= size;
+        self.counter.discharge(size);
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Code {
-    instructions: Vec<Opcode>,
+impl Clone for HeapShare {
+    fn clone(&self) -> Self {
+        self.counter.charge_unlimited(self.usage);
+
+        Self {
+            counter: self.counter.clone(),
+            usage: self.usage,
+        }
+    }
 }
 
-impl Code {
-    pub fn new(instructions: Vec<Opcode>) -> Self {
-        Self { instructions }
+impl Drop for HeapShare {
+    fn drop(&mut self) {
+        self.counter.discharge(self.usage);
     }
 }
 
-#[derive(Clone)]
-pub struct Process {
-    vm: Rc<VM>,
+/// Interpreter running a [`VM`]'s code, see [`VM::with_backend`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Interprets stack code as is
+    #[default]
+    Stack,
+    /// Interprets [`RegisterCode`] translated from stack code. Only [`Process::run`] of a
+    /// fresh process without coverage or tracing uses it, and only if the code's stack depth
+    /// and locals fit the VM's limits. Stepping always interprets stack code.
+    Register,
+    /// Runs basic blocks compiled into closures, see [`CompiledCode`]. Behaves exactly like
+    /// the stack backend, [`Process::run`] uses it unless coverage or tracing is enabled.
+    Closure,
+}
+
+/// A loaded program. Execution only mutates its globals, which are shared by every process
+/// running it, so wrap it in an [`Arc`] and use [`Process::new_shared_process`] to run it
+/// from many threads at once.
+#[derive(Debug)]
+pub struct VM {
+    constants: Vec<Stackable>,
     code: Code,
-    functions: HashMap<FunctionSignature, u32>,
-    stack: Vec<Stackable>,
-    local_variable: BTreeMap<u16, Stackable>,
-    pos: u32,
+    limits: VmLimits,
+    debug_info: Option<DebugInfo>,
+    module: Module,
+    /// Global variables keyed by constant pool index of their names
+    globals: Mutex<HashMap<u32, Stackable>>,
+    /// Set if the register backend runs the code
+    register_code: Option<RegisterCode>,
+    /// Set if the closure backend runs the code
+    compiled_code: Option<CompiledCode>,
+    /// Modules `import` loads, set by [`VM::with_resolver`]
+    modules: Option<Arc<ModuleCache>>,
+    /// Heap use of every process started from this VM
+    heap: HeapCounter,
 }
 
-impl Process {
-    pub fn new_process(vm: VM, pos: u32) -> Self {
-        let code = vm.code.clone();
+/// VMs are equal if they hold the same program as bytecode does, regardless of limits, globals
+/// and selected backend.
+impl PartialEq for VM {
+    fn eq(&self, other: &Self) -> bool {
+        self.constants == other.constants
+            && self.code == other.code
+            && self.debug_info == other.debug_info
+            && self.module == other.module
+    }
+}
 
-        Self {
-            vm: Rc::new(vm),
+impl VM {
+    pub fn new_vm(constants: Vec<Stackable>, code: Code) -> Self {
+        VM {
+            constants,
             code,
-            functions: HashMap::new(),
-            stack: Vec::new(),
-            local_variable: BTreeMap::new(),
-            pos,
+            limits: VmLimits::default(),
+            debug_info: None,
+            module: Module::default(),
+            globals: Mutex::new(HashMap::new()),
+            register_code: None,
+            compiled_code: None,
+            modules: None,
+            heap: HeapCounter::default(),
         }
     }
 
-    pub fn subprocess(&mut self, pos: u32, parameters: Vec<Stackable>) -> Self {
-        Self {
-            vm: self.vm.clone(),
-            code: self.vm.code.clone(),
-            functions: self.functions.clone(),
-            stack: parameters,
-            local_variable: self.local_variable.clone(),
-            pos,
+    /// Selects the interpreter [`Process::run`] executes code with. Code the register backend
+    /// can't express or that doesn't [fit](RegisterCode::fits) the limits stays on the stack
+    /// backend, check [`VM::backend`].
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.register_code = match backend {
+            Backend::Register => RegisterCode::translate(&self),
+            _ => None,
+        };
+        self.compiled_code = match backend {
+            Backend::Closure => Some(CompiledCode::compile(&self)),
+            _ => None,
+        };
+        self
+    }
+
+    pub fn backend(&self) -> Backend {
+        match (&self.register_code, &self.compiled_code) {
+            (Some(code), _) if code.fits(&self.limits) => Backend::Register,
+            (_, Some(_)) => Backend::Closure,
+            _ => Backend::Stack,
         }
     }
 
-    fn get_instruction(&self) -> Option<&Opcode> {
-        self.vm.code.instructions.get(self.pos as usize)
+    /// Register code [`Process::run`] executes, unless it doesn't fit the VM's limits.
+    pub fn register_code(&self) -> Option<&RegisterCode> {
+        self.register_code
+            .as_ref()
+            .filter(|code| code.fits(&self.limits))
     }
 
-    pub fn run(mut self) -> Vec<Stackable> {
-        while let Some(opcode) = self.get_instruction() {
-            let opcode = *opcode;
+    /// VM running other code in place of this one's, keeping limits, module, module resolver
+    /// and backend. Debug info and globals are left behind.
+    pub(crate) fn with_code(&self, constants: Vec<Stackable>, code: Code) -> VM {
+        let backend = match (&self.register_code, &self.compiled_code) {
+            (Some(_), _) => Backend::Register,
+            (_, Some(_)) => Backend::Closure,
+            _ => Backend::Stack,
+        };
+        let mut vm = VM::new_vm(constants, code)
+            .with_limits(self.limits)
+            .with_module(self.module.clone());
+
+        vm.modules = self.modules.clone();
+        vm.with_backend(backend)
+    }
 
-            match opcode {
-                Opcode::Ldc(index) => {
-                    self.ldc(index as usize);
-                }
-                Opcode::Dump => {
-                    self.dump();
-                }
-                Opcode::Add => {
-                    self.add();
-                }
-                Opcode::Sub => {
-                    self.sub();
-                }
-                Opcode::Mul => {
-                    self.mul();
-                }
-                Opcode::Div => {
-                    self.div();
-                }
-                Opcode::Mod => {
-                    self.r#mod();
-                }
-                Opcode::Dup => {
-                    self.dup();
-                }
-                Opcode::Swp => {
-                    self.swp();
-                }
-                Opcode::Store(index) => {
-                    self.store(index as usize);
-                }
-                Opcode::Load(index) => {
-                    self.load(index as usize);
-                }
-                Opcode::Goto(index) => {
-                    self.goto(index);
-                }
-                Opcode::Nop => {
-                    // Do nothing code
-                }
-                Opcode::Func(function_name_index, parameter_size) => {
-                    self.func(function_name_index, parameter_size);
-                }
-                Opcode::Return => {
-                    return self.stack;
-                }
-                Opcode::Invoke(function_name_index, parameter_size) => {
-                    self.invoke(function_name_index, parameter_size);
+    pub fn with_limits(mut self, limits: VmLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_debug_info(mut self, debug_info: DebugInfo) -> Self {
+        self.debug_info = Some(debug_info);
+        self
+    }
+
+    pub fn with_module(mut self, module: Module) -> Self {
+        self.module = module;
+        self
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Lets `import` load modules at runtime from bytecode `resolver` supplies. Loaded modules
+    /// are cached for every process of the VM, each keeps its own constant pool and globals
+    /// and runs with the VM's limits.
+    pub fn with_resolver(mut self, resolver: impl ModuleResolver + 'static) -> Self {
+        self.modules = Some(Arc::new(ModuleCache {
+            resolver: Box::new(resolver),
+            loaded: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Value of the global variable `name`, if it was ever set.
+    pub fn global(&self, name: &str) -> Option<Stackable> {
+        let index = self.name_index(name)?;
+
+        self.lock_globals().get(&index).cloned()
+    }
+
+    /// Sets the global variable `name`, returns false if `name` is not in the constant pool
+    /// since bytecode can never refer to it.
+    pub fn set_global(&self, name: &str, value: Stackable) -> bool {
+        match self.name_index(name) {
+            Some(index) => {
+                self.heap.charge_unlimited(value.heap_size());
+
+                if let Some(previous) = self.lock_globals().insert(index, value) {
+                    self.heap.discharge(previous.heap_size());
                 }
-            }
 
-            if opcode.enum_index() != 0x0B {
-                // Don't move instruction's pos
-                self.pos += 1;
+                true
             }
+            None => false,
         }
+    }
 
-        vec![]
+    /// Bytes currently held by processes started from this VM, its globals and messages
+    /// waiting in mailboxes of a [`Scheduler`] running it.
+    pub fn heap_usage(&self) -> usize {
+        self.heap.usage()
     }
 
-    pub fn ldc(&mut self, index: usize) {
-        let constant = self.vm.constants.get(index);
+    pub(crate) fn heap(&self) -> &HeapCounter {
+        &self.heap
+    }
 
-        if let Some(c) = constant {
-            self.stack.push(c.clone());
-        } else {
-            panic!("Unable to load constant at index {}", index);
-        }
+    fn name_index(&self, name: &str) -> Option<u32> {
+        self.constants
+            .iter()
+            .position(|constant| matches!(constant, Stackable::String(s) if s == name))
+            .map(|index| index as u32)
     }
 
-    pub fn dump(&mut self) {
-        let item = self.stack.pop();
+    pub(crate) fn lock_globals(&self) -> MutexGuard<'_, HashMap<u32, Stackable>> {
+        self.globals
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 
-        if let Some(i) = item {
-            println!("{:?}", i);
-        } else {
-            panic!("Unable to pop an empty stack");
-        }
+    pub fn limits(&self) -> &VmLimits {
+        &self.limits
     }
 
-    pub fn add(&mut self) {
-        if let [right, left] = &self.pop(2)[..] {
-            let (promoted_right, promoted_left, precedence) =
-                Stackable::promote(right.clone(), left.clone());
-            let result_value = get_value!(promoted_left) + get_value!(promoted_right);
+    pub fn constants(&self) -> &[Stackable] {
+        &self.constants
+    }
 
-            self.stack.push(make_stackable!(precedence, result_value));
-        }
+    pub fn code(&self) -> &Code {
+        &self.code
     }
 
-    pub fn sub(&mut self) {
-        if let [right, left] = &self.pop(2)[..] {
-            let (promoted_right, promoted_left, precedence) =
-                Stackable::promote(right.clone(), left.clone());
-            let result_value = get_value!(promoted_left) - get_value!(promoted_right);
+    /// Readable name of the function, e.g. `add/1`, `<main>` if there is none.
+    pub fn function_name(&self, function: Option<&FunctionSignature>) -> String {
+        match function {
+            Some(signature) => match self.constants.get(signature.function_name_index as usize) {
+                Some(name) => format!("{:?}/{}", name, signature.parameter_size),
+                None => format!("<Unknown function name>/{}", signature.parameter_size),
+            },
+            None => "<main>".to_string(),
+        }
+    }
 
-            self.stack.push(make_stackable!(precedence, result_value));
+    /// Readable name stored at `name_index` of the constant pool.
+    pub(crate) fn constant_name(&self, name_index: u32) -> String {
+        match self.constants.get(name_index as usize) {
+            Some(name) => format!("{:?}", name),
+            None => "<Unknown name>".to_string(),
         }
     }
 
-    pub fn mul(&mut self) {
-        if let [right, left] = &self.pop(2)[..] {
-            let (promoted_right, promoted_left, precedence) =
-                Stackable::promote(right.clone(), left.clone());
-            let result_value = get_value!(promoted_left) * get_value!(promoted_right);
+    /// The `func` preceding function entry `pos`.
+    fn function_declaration(&self, pos: u32) -> Option<&Opcode> {
+        pos.checked_sub(1)
+            .and_then(|pos| self.code.instructions.get(pos as usize))
+    }
 
-            self.stack.push(make_stackable!(precedence, result_value));
+    /// Return size declared by the `func` preceding function entry `pos`.
+    fn function_return_size(&self, pos: u32) -> Option<u8> {
+        match self.function_declaration(pos) {
+            Some(Opcode::Func(_, _, return_size, _, _)) => Some(*return_size),
+            _ => None,
         }
     }
 
-    pub fn div(&mut self) {
-        if let [right, left] = &self.pop(2)[..] {
-            let (promoted_right, promoted_left, precedence) =
-                Stackable::promote(right.clone(), left.clone());
-            let result_value = get_value!(promoted_left) / get_value!(promoted_right);
+    /// Local variable slots declared by the `func` preceding function entry `pos`.
+    fn function_slots(&self, pos: u32) -> u32 {
+        match self.function_declaration(pos) {
+            Some(Opcode::Func(_, _, _, max_locals, _)) => *max_locals,
+            _ => 0,
+        }
+    }
 
-            self.stack.push(make_stackable!(precedence, result_value));
+    /// Optional and rest parameters declared by the `func` preceding function entry `pos`.
+    fn function_arity(&self, pos: u32) -> Arity {
+        match self.function_declaration(pos) {
+            Some(Opcode::Func(_, _, _, _, arity)) => *arity,
+            _ => Arity::default(),
         }
     }
 
-    pub fn r#mod(&mut self) {
-        if let [right, left] = &self.pop(2)[..] {
-            let (promoted_right, promoted_left, precedence) =
-                Stackable::promote(right.clone(), left.clone());
-            let result_value = get_value!(promoted_left) % get_value!(promoted_right);
+    /// Moves the VM into a [`Scheduler`] whose main process runs the code, each process runs
+    /// `reductions` instructions per turn.
+    pub fn into_scheduler(self, reductions: usize) -> Scheduler {
+        Scheduler::new(self, reductions)
+    }
 
-            self.stack.push(make_stackable!(precedence, result_value));
-        }
+    pub fn execute(self) -> Result<(), VmError> {
+        let main_proc = Process::new_process(self, 0);
+
+        main_proc.run().map(|_| ())
     }
+}
+
+// Shared VMs are handed to other threads and processes are moved onto them
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    const fn assert_send<T: Send>() {}
+
+    assert_send_sync::<VM>();
+    assert_send::<Process>();
+};
 
-    pub fn dup(&mut self) {
-        self.check_stack_size(1);
+/// Modules loaded by `import`, shared by every process of a VM and the modules it loads.
+struct ModuleCache {
+    resolver: Box<dyn ModuleResolver>,
+    loaded: Mutex<HashMap<String, Arc<LoadedModule>>>,
+}
 
-        let stackable = self.stack.last().unwrap().clone();
-        self.stack.push(stackable);
+impl ModuleCache {
+    fn get(&self, name: &str) -> Option<Arc<LoadedModule>> {
+        self.lock_loaded().get(name).cloned()
     }
 
-    pub fn swp(&mut self) {
-        self.check_stack_size(2);
+    /// Loads the module `name` with `limits` unless it is loaded already, then `None` is
+    /// returned.
+    fn load(&self, name: &str, limits: VmLimits) -> Result<Option<Arc<LoadedModule>>, VmError> {
+        let mut loaded = self.lock_loaded();
 
-        if let [top1, top2] = &self.pop(2)[..] {
-            self.push(&[top2.clone(), top1.clone()]);
+        if loaded.contains_key(name) {
+            return Ok(None);
         }
+
+        let bytecode = self
+            .resolver
+            .resolve(name)
+            .ok_or_else(|| VmError::UnknownModule {
+                name: name.to_string(),
+            })?;
+        let vm = Loader::new(&bytecode)
+            .try_load()
+            .map_err(|reason| VmError::MalformedModule {
+                name: name.to_string(),
+                reason,
+            })?
+            .with_limits(limits);
+        let instructions = vm.code.instructions();
+        let scopes = function_scopes(instructions);
+        let functions = instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, opcode)| match opcode {
+                Opcode::Func(function_name_index, parameter_size, _, _, _) if scopes[pos] == 0 => {
+                    Some((
+                        FunctionSignature::new(*function_name_index, *parameter_size),
+                        pos as u32 + 1,
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+        let module = Arc::new(LoadedModule {
+            vm: Arc::new(vm),
+            functions,
+        });
+
+        loaded.insert(name.to_string(), module.clone());
+
+        Ok(Some(module))
     }
 
-    pub fn store(&mut self, index: usize) {
-        self.check_stack_size(1);
+    fn lock_loaded(&self) -> MutexGuard<'_, HashMap<String, Arc<LoadedModule>>> {
+        self.loaded
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
 
-        if index <= u16::MAX.into() {
-            let stackable = self.stack.pop().unwrap();
-            self.local_variable.insert(index as u16, stackable);
-        } else {
-            panic!("Unable to store variable: index exceeded VM's hard limit.\n    Got: {}\n    Limit: 65535", index);
+impl Debug for ModuleCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} loaded modules>", self.lock_loaded().len())
+    }
+}
+
+/// Module loaded by `import`.
+struct LoadedModule {
+    vm: Arc<VM>,
+    /// Top level functions, which calls from other modules resolve among and functions of
+    /// the module see
+    functions: HashMap<FunctionSignature, u32>,
+}
+
+/// Function a call resolves to.
+struct Callee {
+    pos: u32,
+    function: FunctionSignature,
+    /// Module declaring the function if the caller's module imports it
+    module: Option<Arc<LoadedModule>>,
+}
+
+impl Callee {
+    /// VM whose code declares the function, `caller` unless it is imported.
+    fn vm(&self, caller: &Arc<VM>) -> Arc<VM> {
+        match &self.module {
+            Some(module) => module.vm.clone(),
+            None => caller.clone(),
         }
     }
 
-    pub fn load(&mut self, index: usize) {
-        if index <= u16::MAX.into() {
-            let stackable = self.local_variable.get(&(index as u16)).unwrap().clone();
-            self.stack.push(stackable);
-        } else {
-            panic!("Unable to load variable: index exceeded VM's hard limit.\n    Got: {}\n    Limit: 65535", index);
+    /// Functions the callee's frame sees, the caller's unless it is imported.
+    fn functions(
+        &self,
+        caller: &HashMap<FunctionSignature, u32>,
+    ) -> HashMap<FunctionSignature, u32> {
+        match &self.module {
+            Some(module) => module.functions.clone(),
+            None => caller.clone(),
         }
     }
+}
 
-    pub fn goto(&mut self, index: u32) {
-        self.pos = index;
+#[derive(Debug, Clone, PartialEq)]
+pub struct Code {
+    instructions: Vec<Opcode>,
+    /// Local variable slots of top level code, functions declare their own in `func`
+    max_locals: u32,
+    /// Same positions as `instructions`, run by [`Process::run`] and [`Process::run_for`]
+    decoded: Vec<Instruction>,
+}
+
+impl Code {
+    pub fn new(instructions: Vec<Opcode>, max_locals: u32) -> Self {
+        Self {
+            decoded: dispatch::decode(&instructions),
+            instructions,
+            max_locals,
+        }
     }
 
-    pub fn func(&mut self, function_name_index: u32, parameter_size: u8) {
-        self.functions.insert(
-            FunctionSignature {
-                function_name_index,
-                parameter_size,
-            },
-            self.pos + 1,
-        );
+    pub fn instructions(&self) -> &[Opcode] {
+        &self.instructions
+    }
 
-        let mut func_level = 0;
+    pub fn max_locals(&self) -> u32 {
+        self.max_locals
+    }
+}
 
-        self.pos += 1;
+/// Maps instruction positions back to the source they were compiled from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugInfo {
+    source: String,
+    /// Sorted by position, each line covers instructions until the next entry
+    lines: Vec<(u32, u32)>,
+}
 
-        // Set current pos to nearest paired return opcode
-        while let Some(opcode) = self.get_instruction() {
-            match opcode {
-                Opcode::Func(_, _) => {
-                    func_level += 1;
-                }
-                Opcode::Return => {
-                    if func_level == 0 {
-                        break;
-                    } else {
-                        func_level -= 1;
-                    }
-                }
-                _ => {}
-            }
+impl DebugInfo {
+    pub fn new(source: String, mut lines: Vec<(u32, u32)>) -> Self {
+        lines.sort_by_key(|(pos, _)| *pos);
+
+        Self { source, lines }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn lines(&self) -> &[(u32, u32)] {
+        &self.lines
+    }
 
-            self.pos += 1;
+    /// Source line of the instruction at `pos`, if any.
+    pub fn line_of(&self, pos: u32) -> Option<u32> {
+        match self.lines.binary_search_by_key(&pos, |(pos, _)| *pos) {
+            Ok(index) => Some(self.lines[index].1),
+            Err(0) => None,
+            Err(index) => Some(self.lines[index - 1].1),
         }
     }
 
-    pub fn r#return(&mut self) -> Option<Stackable> {
-        return self.stack.pop();
+    /// First instruction position of each range compiled from `line`.
+    pub fn positions_of(&self, line: u32) -> Vec<u32> {
+        self.lines
+            .iter()
+            .filter(|(_, entry_line)| *entry_line == line)
+            .map(|(pos, _)| *pos)
+            .collect()
     }
+}
 
-    pub fn invoke(&mut self, function_name_index: u32, parameter_size: u8) {
-        let function_initial_pos = self
-            .functions
-            .get(&FunctionSignature {
-                function_name_index,
-                parameter_size,
-            })
-            .cloned();
+/// Execution state of a [`Process`], returned by [`Process::step`] and [`Process::run_for`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessStatus {
+    /// Process can execute further instructions.
+    Running,
+    /// Process handed control back to the host before finishing, resume it with
+    /// [`Process::step`] or [`Process::run_for`].
+    Yielded,
+    /// Process returned from its outermost frame with these values.
+    Finished(Vec<Stackable>),
+    /// Process stopped on a runtime error.
+    Faulted(VmError),
+    /// Process is blocked on `receive` until a message arrives in its mailbox.
+    Waiting,
+}
+
+impl ProcessStatus {
+    pub fn is_terminated(&self) -> bool {
+        matches!(self, Self::Finished(_) | Self::Faulted(_))
+    }
+}
+
+/// Destination of `dump` output, see [`Process::set_output`].
+pub type OutputSink = Arc<Mutex<dyn Write + Send>>;
+
+/// Activation record of a function call, `invoke` pushes one and `return` pops it.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Invoked function, `None` for the frame a process starts with
+    function: Option<FunctionSignature>,
+    functions: HashMap<FunctionSignature, u32>,
+    stack: Vec<Stackable>,
+    /// Fixed slots sized by the function's declared locals, `None` until stored to
+    local_variable: Vec<Option<Stackable>>,
+    /// Values `return` hands to the caller as declared by the function, the whole stack for
+    /// the frame a process starts with
+    return_size: Option<u8>,
+    pos: u32,
+    /// Module the caller runs in if the frame runs in another one, restored once it returns
+    caller_vm: Option<Arc<VM>>,
+}
+
+impl Frame {
+    pub fn function(&self) -> Option<&FunctionSignature> {
+        self.function.as_ref()
+    }
+
+    pub fn stack(&self) -> &[Stackable] {
+        &self.stack
+    }
 
-        if let Some(pos) = function_initial_pos {
-            self.check_stack_size(parameter_size as usize);
+    pub fn local_variable(&self) -> &[Option<Stackable>] {
+        &self.local_variable
+    }
 
-            let enter_pos = self.pos;
-            let parameter_stack = self.pop(parameter_size as usize);
+    pub fn pos(&self) -> u32 {
+        self.pos
+    }
+}
 
-            let proc = self.subprocess(pos, parameter_stack);
+#[derive(Clone)]
+pub struct Process {
+    vm: Arc<VM>,
+    /// VM the process started from, coverage and breakpoints count positions of its code only
+    root: Arc<VM>,
+    frames: Vec<Frame>,
+    status: ProcessStatus,
+    heap: HeapShare,
+    /// Call depth of whoever drives this process, frames are counted on top of it
+    base_depth: usize,
+    /// Whether `yield` is allowed, only true for processes backing a [`Coroutine`]
+    coroutine: bool,
+    yielded: Option<Stackable>,
+    /// Pid and scheduler of the process, set once a [`Scheduler`] runs it
+    scheduler: Option<(Pid, SchedulerHandle)>,
+    waiting: bool,
+    /// Where `dump` prints to, stdout if unset
+    output: Option<OutputSink>,
+    /// Hit counts shared with processes this one creates, set by [`Process::enable_coverage`]
+    coverage: Option<Arc<Mutex<Coverage>>>,
+    /// Modules of the VM the process started with, `vm` switches to them on calls
+    modules: Option<Arc<ModuleCache>>,
+    #[cfg(feature = "trace")]
+    tracer: Option<Arc<Mutex<dyn Tracer>>>,
+}
 
-            let mut return_value = proc.run();
+impl Process {
+    pub fn new_process(vm: VM, pos: u32) -> Self {
+        Self::new_shared_process(Arc::new(vm), pos)
+    }
 
-            self.stack.append(&mut return_value);
+    /// Creates a process running a VM shared with other processes, possibly on other
+    /// threads. The VM is immutable, each process owns its execution state.
+    /// The process starts faulted if the heap has no room for its locals.
+    pub fn new_shared_process(vm: Arc<VM>, pos: u32) -> Self {
+        let mut heap = HeapShare::new(vm.heap.clone());
+        let (local_variable, status) = match local_slots(&mut heap, &vm, vm.code.max_locals) {
+            Ok(local_variable) => (local_variable, ProcessStatus::Running),
+            Err(err) => (Vec::new(), ProcessStatus::Faulted(err)),
+        };
 
-            self.pos = enter_pos;
-        } else {
-            panic!(
-                "Unknown function {:?} with {} parameters",
-                self.vm
-                    .constants
-                    .get(function_name_index as usize)
-                    .unwrap_or(&Stackable::String("<Unknown function name>".to_string())),
-                parameter_size
-            );
+        Self {
+            modules: vm.modules.clone(),
+            heap,
+            root: vm.clone(),
+            vm,
+            frames: vec![Frame {
+                function: None,
+                functions: HashMap::new(),
+                stack: Vec::new(),
+                local_variable,
+                return_size: None,
+                pos,
+                caller_vm: None,
+            }],
+            status,
+            base_depth: 0,
+            coroutine: false,
+            yielded: None,
+            scheduler: None,
+            waiting: false,
+            output: None,
+            coverage: None,
+            #[cfg(feature = "trace")]
+            tracer: None,
         }
     }
 
-    fn pop(&mut self, pop_size: usize) -> Vec<Stackable> {
-        self.check_stack_size(pop_size);
+    /// Creates a process running the called function, which inherits functions of the
+    /// current frame like `invoke` does. Used by coroutines and spawned processes.
+    fn new_child(&self, callee: Callee, parameters: Vec<Stackable>) -> Result<Self, VmError> {
+        let vm = callee.vm(&self.vm);
+        let mut heap = HeapShare::new(self.heap.counter().clone());
+        let local_variable = local_slots(&mut heap, &vm, vm.function_slots(callee.pos))?;
+        let mut proc = Self {
+            frames: vec![Frame {
+                function: Some(callee.function),
+                functions: callee.functions(&self.frame().functions),
+                stack: Vec::with_capacity(parameters.len()),
+                local_variable,
+                return_size: vm.function_return_size(callee.pos),
+                pos: callee.pos,
+                caller_vm: None,
+            }],
+            vm,
+            root: self.root.clone(),
+            status: ProcessStatus::Running,
+            heap,
+            base_depth: 0,
+            coroutine: false,
+            yielded: None,
+            scheduler: self.scheduler.clone(),
+            waiting: false,
+            output: self.output.clone(),
+            coverage: self.coverage.clone(),
+            modules: self.modules.clone(),
+            #[cfg(feature = "trace")]
+            tracer: self.tracer.clone(),
+        };
+
+        for parameter in parameters {
+            proc.push_value(parameter)?;
+        }
 
-        self.stack.drain(self.stack.len() - pop_size..).collect()
+        Ok(proc)
     }
 
-    fn push(&mut self, items: &[Stackable]) {
-        self.stack.extend_from_slice(items);
+    /// Redirects `dump` output of this process and processes it creates.
+    pub fn set_output(&mut self, output: OutputSink) {
+        self.output = Some(output);
     }
 
-    fn check_stack_size(&self, required_size: usize) {
-        if self.stack.len() < required_size {
-            panic!(
-                "Unable to perform addition, requires {}+ items on stack but got {}",
-                required_size,
-                self.stack.len()
-            );
-        }
+    /// Records how many times each instruction is executed by this process and processes it
+    /// creates from now on.
+    pub fn enable_coverage(&mut self) {
+        let instructions_size = self.vm.code.instructions.len();
+
+        self.coverage = Some(Arc::new(Mutex::new(Coverage::new(instructions_size))));
+    }
+
+    /// Hit counts recorded so far, `None` unless coverage is enabled.
+    pub fn coverage(&self) -> Option<Coverage> {
+        self.coverage.as_ref().map(|coverage| {
+            coverage
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone()
+        })
+    }
+
+    /// Reports every executed instruction of this process and processes it creates.
+    #[cfg(feature = "trace")]
+    pub fn set_tracer(&mut self, tracer: Arc<Mutex<dyn Tracer>>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub(crate) fn attach(&mut self, pid: Pid, scheduler: SchedulerHandle) {
+        self.scheduler = Some((pid, scheduler));
+    }
+
+    pub(crate) fn set_base_depth(&mut self, base_depth: usize) {
+        self.base_depth = base_depth;
+    }
+
+    pub(crate) fn take_yielded(&mut self) -> Option<Stackable> {
+        self.yielded.take()
+    }
+
+    fn depth(&self) -> usize {
+        self.base_depth + self.frames.len()
     }
+
+    pub fn status(&self) -> &ProcessStatus {
+        &self.status
+    }
+
+    /// Current instruction position of the innermost frame, `None` once the process finished.
+    pub fn pos(&self) -> Option<u32> {
+        self.frames.last().map(|frame| frame.pos)
+    }
+
+    /// Call stack, outermost frame first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// Position of the next instruction if it belongs to the code the process started with,
+    /// `None` while an imported module's code runs.
+    pub(crate) fn root_pos(&self) -> Option<u32> {
+        self.pos().filter(|_| Arc::ptr_eq(&self.vm, &self.root))
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn get_instruction(&self) -> Option<&Opcode> {
+        self.vm.code.instructions.get(self.frame().pos as usize)
+    }
+
+    /// Runs the process to completion, consuming it.
+    pub fn run(mut self) -> Result<Vec<Stackable>, VmError> {
+        if let Some(register_code) = self.register_code() {
+            return register::execute(&self.vm, register_code, self.output.as_ref());
+        }
+
+        if self.vm.compiled_code.is_some() && !self.observed() {
+            return self.run_compiled();
+        }
+
+        loop {
+            self.dispatch(usize::MAX);
+
+            match &self.status {
+                ProcessStatus::Finished(values) => return Ok(values.clone()),
+                ProcessStatus::Faulted(err) => return Err(err.clone()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Executes up to `n` instructions, the process is [`ProcessStatus::Yielded`] if it is
+    /// still runnable afterwards.
+    pub fn run_for(&mut self, n: usize) -> &ProcessStatus {
+        let mut budget = n;
+
+        while budget > 0 {
+            budget = budget.saturating_sub(self.dispatch(budget));
+
+            if matches!(
+                self.status,
+                ProcessStatus::Finished(_) | ProcessStatus::Faulted(_) | ProcessStatus::Waiting
+            ) {
+                return &self.status;
+            }
+        }
+
+        self.status = ProcessStatus::Yielded;
+        &self.status
+    }
+
+    /// Register code to run instead if the VM has it and nothing ran or observes this process.
+    fn register_code(&self) -> Option<&RegisterCode> {
+        match &self.frames[..] {
+            [frame]
+                if frame.pos == 0
+                    && frame.functions.is_empty()
+                    && frame.stack.is_empty()
+                    && frame.local_variable.iter().all(Option::is_none)
+                    && !self.observed() =>
+            {
+                self.vm.register_code()
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether coverage or tracing watch single instructions.
+    fn observed(&self) -> bool {
+        #[cfg(feature = "trace")]
+        if self.tracer.is_some() {
+            return true;
+        }
+
+        self.coverage.is_some()
+    }
+
+    /// Runs compiled blocks of the closure backend until the process finishes or faults.
+    fn run_compiled(mut self) -> Result<Vec<Stackable>, VmError> {
+        let vm = self.vm.clone();
+        let compiled_code = vm.compiled_code.as_ref().unwrap();
+
+        loop {
+            self.waiting = false;
+
+            // Positions outside of blocks, e.g. running off the end, and code of imported
+            // modules are interpreted
+            let values = match self
+                .pos()
+                .filter(|_| Arc::ptr_eq(&self.vm, &vm))
+                .and_then(|pos| compiled_code.block(pos))
+            {
+                Some(block) => block(&mut self)?,
+                None => self.execute_instruction()?,
+            };
+
+            if let Some(values) = values {
+                return Ok(values);
+            }
+        }
+    }
+
+    /// Executes the next pre-decoded instruction, which may be several fused ones if `budget`
+    /// covers all of them, and returns how many instructions ran. Falls back to
+    /// [`Process::step`] while coverage or tracing observe single instructions.
+    fn dispatch(&mut self, budget: usize) -> usize {
+        if self.status.is_terminated() {
+            return 0;
+        }
+
+        if self.observed() {
+            self.step();
+            return 1;
+        }
+
+        self.waiting = false;
+
+        let (executed, result) = self.execute_decoded(budget);
+        self.status = self.status_after(result);
+
+        executed
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> &ProcessStatus {
+        if self.status.is_terminated() {
+            return &self.status;
+        }
+
+        #[cfg(feature = "trace")]
+        let before = self.tracer.as_ref().and_then(|_| {
+            let frame = self.frames.last()?;
+            let opcode = *self.get_instruction()?;
+
+            Some((self.frames.len(), frame.pos, opcode, frame.stack.clone()))
+        });
+
+        if let (Some(coverage), Some(pos)) = (&self.coverage, self.root_pos()) {
+            coverage
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .hit(pos);
+        }
+
+        self.waiting = false;
+
+        let result = self.execute_instruction();
+        self.status = self.status_after(result);
+
+        #[cfg(feature = "trace")]
+        if let (Some(tracer), Some((depth, pos, opcode, stack_before))) = (&self.tracer, before) {
+            let stack_after = match (&self.status, self.frames.last()) {
+                (ProcessStatus::Finished(values), _) => &values[..],
+                (_, Some(frame)) => &frame.stack[..],
+                (_, None) => &[],
+            };
+
+            tracer
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .trace(&TraceEvent {
+                    depth,
+                    pos,
+                    opcode,
+                    stack_before: &stack_before,
+                    stack_after,
+                });
+        }
+
+        &self.status
+    }
+
+    fn status_after(&self, result: Result<Option<Vec<Stackable>>, VmError>) -> ProcessStatus {
+        match result {
+            Ok(Some(values)) => ProcessStatus::Finished(values),
+            Ok(None) if self.yielded.is_some() => ProcessStatus::Yielded,
+            Ok(None) if self.waiting => ProcessStatus::Waiting,
+            Ok(None) => ProcessStatus::Running,
+            Err(err) => ProcessStatus::Faulted(err),
+        }
+    }
+
+    /// Returns values left by the outermost frame once it returns.
+    pub(crate) fn execute_instruction(&mut self) -> Result<Option<Vec<Stackable>>, VmError> {
+        let opcode = if let Some(opcode) = self.get_instruction() {
+            *opcode
+        } else {
+            // Running off the end of code returns nothing
+            let mut values = self.leave_frame();
+            values.clear();
+            return self.return_to_caller(values);
+        };
+
+        match opcode {
+            Opcode::Ldc(index) => {
+                self.ldc(index as usize)?;
+            }
+            Opcode::Dump => {
+                self.dump()?;
+            }
+            Opcode::Add => {
+                self.add()?;
+            }
+            Opcode::Sub => {
+                self.sub()?;
+            }
+            Opcode::Mul => {
+                self.mul()?;
+            }
+            Opcode::Div => {
+                self.div()?;
+            }
+            Opcode::Mod => {
+                self.r#mod()?;
+            }
+            Opcode::Dup => {
+                self.dup()?;
+            }
+            Opcode::Swp => {
+                self.swp()?;
+            }
+            Opcode::Store(index) => {
+                self.store(index as usize)?;
+            }
+            Opcode::Load(index) => {
+                self.load(index as usize)?;
+            }
+            Opcode::Goto(index) => {
+                self.goto(index);
+                return Ok(None);
+            }
+            Opcode::Nop => {
+                // Do nothing code
+            }
+            Opcode::Func(function_name_index, parameter_size, _, _, _) => {
+                self.func(function_name_index, parameter_size);
+            }
+            Opcode::Return => {
+                if let Some(return_size) = self.frame().return_size {
+                    self.check_stack_size(return_size as usize)?;
+                }
+
+                let values = self.leave_frame();
+                return self.return_to_caller(values);
+            }
+            Opcode::Invoke(function_name_index, parameter_size) => {
+                // Moves caller's pos itself
+                self.invoke(function_name_index, parameter_size)?;
+                return Ok(None);
+            }
+            Opcode::Coroutine(function_name_index, parameter_size) => {
+                self.coroutine(function_name_index, parameter_size)?;
+            }
+            Opcode::Yield => {
+                self.r#yield()?;
+            }
+            Opcode::Resume => {
+                self.resume()?;
+            }
+            Opcode::Done => {
+                self.done()?;
+            }
+            Opcode::Spawn(function_name_index, parameter_size) => {
+                self.spawn(function_name_index, parameter_size)?;
+            }
+            Opcode::Send => {
+                self.send()?;
+            }
+            Opcode::Receive => {
+                self.receive()?;
+            }
+            Opcode::Pid => {
+                self.pid()?;
+            }
+            Opcode::SetGlobal(name_index) => {
+                self.setglobal(name_index)?;
+            }
+            Opcode::GetGlobal(name_index) => {
+                self.getglobal(name_index)?;
+            }
+            Opcode::TailInvoke(function_name_index, parameter_size) => {
+                // Replaces current frame's pos
+                self.tailinvoke(function_name_index, parameter_size)?;
+                return Ok(None);
+            }
+            Opcode::Import(name_index) => {
+                // Moves caller's pos itself
+                self.import(name_index)?;
+                return Ok(None);
+            }
+        }
+
+        if self.waiting {
+            // Retry blocked instruction on next step
+            return Ok(None);
+        }
+
+        self.frame_mut().pos += 1;
+
+        Ok(None)
+    }
+
+    /// Executes the pre-decoded instruction at the current position, a fused one runs as a
+    /// whole unless it might fault or `budget` doesn't cover it, then only its first plain
+    /// instruction runs. Returns how many instructions ran.
+    fn execute_decoded(
+        &mut self,
+        budget: usize,
+    ) -> (usize, Result<Option<Vec<Stackable>>, VmError>) {
+        let pos = self.frame().pos;
+        let executed = match self.vm.code.decoded.get(pos as usize) {
+            Some(Instruction::LoadLoadArith(a, b, opcode)) if budget >= 3 => {
+                self.load_load_arith(*a as usize, *b as usize, *opcode)
+            }
+            Some(Instruction::LdcArith(index, opcode)) if budget >= 2 => {
+                self.ldc_arith(*index as usize, *opcode)
+            }
+            _ => None,
+        };
+
+        match executed {
+            Some(size) => {
+                self.frame_mut().pos += size;
+                (size as usize, Ok(None))
+            }
+            None => (1, self.execute_instruction()),
+        }
+    }
+
+    /// Fused `load a; load b; <arithmetic>`, returns instructions executed.
+    fn load_load_arith(&mut self, a: usize, b: usize, opcode: Opcode) -> Option<u32> {
+        let limits = self.vm.limits;
+        let value_size = std::mem::size_of::<Stackable>();
+        let frame = self.frames.last_mut()?;
+
+        // Both loads have to fit before arithmetic pops them
+        if frame.stack.len() + 2 > limits.max_stack_size
+            || !self
+                .heap
+                .counter()
+                .fits(2 * value_size, limits.max_heap_bytes)
+        {
+            return None;
+        }
+
+        let (right, left) = match (frame.local_variable.get(a), frame.local_variable.get(b)) {
+            (Some(Some(right)), Some(Some(left))) if right.is_numeric() && left.is_numeric() => {
+                (right.clone(), left.clone())
+            }
+            _ => return None,
+        };
+
+        let result = Stackable::arithmetic(opcode, right, left)?;
+        self.heap.allocate(value_size, limits.max_heap_bytes).ok()?;
+        frame.stack.push(result);
+
+        Some(3)
+    }
+
+    /// Fused `ldc c; <arithmetic>`, returns instructions executed.
+    fn ldc_arith(&mut self, index: usize, opcode: Opcode) -> Option<u32> {
+        let limits = self.vm.limits;
+        let left = self
+            .vm
+            .constants
+            .get(index)
+            .filter(|c| c.is_numeric())?
+            .clone();
+        let frame = self.frames.last_mut()?;
+
+        if frame.stack.len() + 1 > limits.max_stack_size
+            || !self
+                .heap
+                .counter()
+                .fits(std::mem::size_of::<Stackable>(), limits.max_heap_bytes)
+        {
+            return None;
+        }
+
+        let right = frame.stack.last_mut().filter(|right| right.is_numeric())?;
+        *right = Stackable::arithmetic(opcode, right.clone(), left)?;
+
+        Some(2)
+    }
+
+    /// Pops the innermost frame, releasing heap accounted to its locals and stack. Returns the
+    /// top of its stack as declared by the function.
+    fn leave_frame(&mut self) -> Vec<Stackable> {
+        let frame = self.frames.pop().unwrap();
+
+        if let Some(caller_vm) = frame.caller_vm {
+            self.vm = caller_vm;
+        }
+
+        let locals_size: usize = frame
+            .local_variable
+            .iter()
+            .flatten()
+            .map(Stackable::heap_size)
+            .sum();
+        let stack_size: usize = frame.stack.iter().map(Stackable::heap_size).sum();
+
+        self.release(slots_size(frame.local_variable.len()) + locals_size + stack_size);
+
+        let mut stack = frame.stack;
+
+        if let Some(return_size) = frame.return_size {
+            stack.drain(..stack.len().saturating_sub(return_size as usize));
+        }
+
+        stack
+    }
+
+    /// Hands returned values to the calling frame, or yields them as the process result if
+    /// the outermost frame returned.
+    fn return_to_caller(
+        &mut self,
+        values: Vec<Stackable>,
+    ) -> Result<Option<Vec<Stackable>>, VmError> {
+        if self.frames.is_empty() {
+            return Ok(Some(values));
+        }
+
+        for value in values {
+            self.push_value(value)?;
+        }
+
+        Ok(None)
+    }
+
+    pub fn ldc(&mut self, index: usize) -> Result<(), VmError> {
+        let constant = self.vm.constants.get(index);
+
+        if let Some(c) = constant {
+            let c = c.clone();
+            self.push_value(c)
+        } else {
+            Err(VmError::UnknownConstant { index })
+        }
+    }
+
+    pub fn dump(&mut self) -> Result<(), VmError> {
+        self.check_stack_size(1)?;
+
+        let item = self.pop_value().unwrap();
+
+        match &self.output {
+            Some(output) => {
+                let mut output = output
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                // Output is best effort, a broken sink must not fault the process
+                let _ = writeln!(output, "{:?}", item);
+            }
+            None => println!("{:?}", item),
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&mut self) -> Result<(), VmError> {
+        self.arithmetic(Opcode::Add)
+    }
+
+    pub fn sub(&mut self) -> Result<(), VmError> {
+        self.arithmetic(Opcode::Sub)
+    }
+
+    pub fn mul(&mut self) -> Result<(), VmError> {
+        self.arithmetic(Opcode::Mul)
+    }
+
+    pub fn div(&mut self) -> Result<(), VmError> {
+        self.arithmetic(Opcode::Div)
+    }
+
+    pub fn r#mod(&mut self) -> Result<(), VmError> {
+        self.arithmetic(Opcode::Mod)
+    }
+
+    fn arithmetic(&mut self, opcode: Opcode) -> Result<(), VmError> {
+        if let [right, left] = &self.pop_operands()?[..] {
+            let result = Stackable::arithmetic(opcode, right.clone(), left.clone()).unwrap();
+
+            self.push_value(result)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn dup(&mut self) -> Result<(), VmError> {
+        self.check_stack_size(1)?;
+
+        let stackable = self.frame().stack.last().unwrap().clone();
+        self.push_value(stackable)
+    }
+
+    pub fn swp(&mut self) -> Result<(), VmError> {
+        if let [top1, top2] = &self.pop(2)?[..] {
+            self.push(&[top2.clone(), top1.clone()])?;
+        }
+
+        Ok(())
+    }
+
+    pub fn store(&mut self, index: usize) -> Result<(), VmError> {
+        self.check_stack_size(1)?;
+        self.check_local_index(index)?;
+
+        let stackable = self.pop_value().unwrap();
+        self.allocate(stackable.heap_size())?;
+
+        if let Some(previous) = self.frame_mut().local_variable[index].replace(stackable) {
+            self.release(previous.heap_size());
+        }
+
+        Ok(())
+    }
+
+    pub fn load(&mut self, index: usize) -> Result<(), VmError> {
+        self.check_local_index(index)?;
+
+        let stackable = self.frame().local_variable[index]
+            .clone()
+            .ok_or(VmError::UninitializedLocal { index })?;
+        self.push_value(stackable)
+    }
+
+    pub fn goto(&mut self, index: u32) {
+        self.frame_mut().pos = index;
+    }
+
+    pub fn func(&mut self, function_name_index: u32, parameter_size: u8) {
+        let frame = self.frame_mut();
+
+        frame.functions.insert(
+            FunctionSignature {
+                function_name_index,
+                parameter_size,
+            },
+            frame.pos + 1,
+        );
+
+        let mut func_level = 0;
+
+        frame.pos += 1;
+
+        // Set current pos to nearest paired return opcode
+        while let Some(opcode) = self.get_instruction() {
+            match opcode {
+                Opcode::Func(_, _, _, _, _) => {
+                    func_level += 1;
+                }
+                Opcode::Return => {
+                    if func_level == 0 {
+                        break;
+                    } else {
+                        func_level -= 1;
+                    }
+                }
+                _ => {}
+            }
+
+            self.frame_mut().pos += 1;
+        }
+    }
+
+    pub fn r#return(&mut self) -> Option<Stackable> {
+        self.pop_value()
+    }
+
+    /// Enters the invoked function's frame, which inherits the caller's functions and starts
+    /// with uninitialized locals. Caller resumes at the next instruction once the callee returns.
+    /// Imported functions run in their module and see its top level functions instead.
+    pub fn invoke(&mut self, function_name_index: u32, parameter_size: u8) -> Result<(), VmError> {
+        let callee = self.resolve_function(function_name_index, parameter_size)?;
+        let limit = self.vm.limits.max_call_depth;
+
+        if self.depth() >= limit {
+            return Err(VmError::CallDepthExceeded { limit });
+        }
+
+        let vm = callee.vm(&self.vm);
+        let parameters = self.pop_arguments(&vm, callee.pos, parameter_size)?;
+        let local_variable = local_slots(&mut self.heap, &vm, vm.function_slots(callee.pos))?;
+        let return_size = vm.function_return_size(callee.pos);
+        let caller = self.frame_mut();
+        caller.pos += 1;
+
+        let functions = callee.functions(&caller.functions);
+        let caller_vm = callee
+            .module
+            .is_some()
+            .then(|| std::mem::replace(&mut self.vm, vm));
+        let frame = Frame {
+            function: Some(callee.function),
+            functions,
+            stack: Vec::with_capacity(parameters.len()),
+            local_variable,
+            return_size,
+            pos: callee.pos,
+            caller_vm,
+        };
+
+        self.frames.push(frame);
+
+        for parameter in parameters {
+            self.push_value(parameter)?;
+        }
+
+        Ok(())
+    }
+
+    /// Invokes the function in place of the current one, reusing its frame so the call
+    /// doesn't add to call depth. The rest of the stack is discarded, locals start
+    /// uninitialized and the callee returns to the current function's caller, like
+    /// `invoke; return` would if both functions declare the same return size.
+    pub fn tailinvoke(
+        &mut self,
+        function_name_index: u32,
+        parameter_size: u8,
+    ) -> Result<(), VmError> {
+        let callee = self.resolve_function(function_name_index, parameter_size)?;
+        let vm = callee.vm(&self.vm);
+        let parameters = self.pop_arguments(&vm, callee.pos, parameter_size)?;
+        let local_variable = local_slots(&mut self.heap, &vm, vm.function_slots(callee.pos))?;
+        let return_size = vm.function_return_size(callee.pos);
+
+        if callee.module.is_some() {
+            let caller_vm = std::mem::replace(&mut self.vm, vm);
+            let frame = self.frame_mut();
+
+            // Function left its caller's module before if it is set already
+            frame.caller_vm.get_or_insert(caller_vm);
+            frame.functions = callee.functions(&frame.functions);
+        }
+
+        let frame = self.frame_mut();
+        let previous_locals = std::mem::replace(&mut frame.local_variable, local_variable);
+        let previous_stack = std::mem::take(&mut frame.stack);
+
+        frame.function = Some(callee.function);
+        frame.return_size = return_size;
+        frame.pos = callee.pos;
+        self.release(
+            slots_size(previous_locals.len())
+                + previous_locals
+                    .iter()
+                    .flatten()
+                    .chain(&previous_stack)
+                    .map(Stackable::heap_size)
+                    .sum::<usize>(),
+        );
+
+        for parameter in parameters {
+            self.push_value(parameter)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pops parameters and pushes a suspended coroutine of the function, its body starts
+    /// running on the first `resume`.
+    pub fn coroutine(
+        &mut self,
+        function_name_index: u32,
+        parameter_size: u8,
+    ) -> Result<(), VmError> {
+        let callee = self.resolve_function(function_name_index, parameter_size)?;
+        let parameters = self.pop_arguments(&callee.vm(&self.vm), callee.pos, parameter_size)?;
+        let mut proc = self.new_child(callee, parameters)?;
+        proc.coroutine = true;
+
+        self.push_value(Stackable::Coroutine(CoroutineRef::new(Coroutine::new(
+            proc,
+        ))))
+    }
+
+    /// Pops the top item and suspends the coroutine, handing the item to its resumer.
+    pub fn r#yield(&mut self) -> Result<(), VmError> {
+        if !self.coroutine {
+            return Err(VmError::YieldOutsideCoroutine);
+        }
+
+        self.check_stack_size(1)?;
+        self.yielded = self.pop_value();
+
+        Ok(())
+    }
+
+    /// Pops a coroutine and runs it until it yields or returns. A yielded item is pushed
+    /// onto stack, returned items are pushed like `invoke` does. Blocks while the coroutine
+    /// waits on `receive`.
+    pub fn resume(&mut self) -> Result<(), VmError> {
+        let coroutine = self.pop_coroutine()?;
+        let limit = self.vm.limits.max_call_depth;
+
+        if self.depth() >= limit {
+            return Err(VmError::CallDepthExceeded { limit });
+        }
+
+        match coroutine.resume_at(self.depth())? {
+            CoroutineState::Yielded(value) => self.push_value(value),
+            CoroutineState::Waiting => {
+                // Put coroutine back so resume is retried
+                self.waiting = true;
+                self.push_value(Stackable::Coroutine(coroutine))
+            }
+            CoroutineState::Finished(values) => {
+                for value in values {
+                    self.push_value(value)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Pops a coroutine and pushes Int 1 if it has finished, otherwise Int 0.
+    pub fn done(&mut self) -> Result<(), VmError> {
+        let coroutine = self.pop_coroutine()?;
+
+        self.push_value(Stackable::Int(coroutine.is_finished() as i32))
+    }
+
+    /// Pops parameters and spawns a process running the function on the current scheduler,
+    /// pushes its pid.
+    pub fn spawn(&mut self, function_name_index: u32, parameter_size: u8) -> Result<(), VmError> {
+        let (_, handle) = self.scheduler.clone().ok_or(VmError::NoScheduler)?;
+        let callee = self.resolve_function(function_name_index, parameter_size)?;
+        let parameters = self.pop_arguments(&callee.vm(&self.vm), callee.pos, parameter_size)?;
+        let proc = self.new_child(callee, parameters)?;
+        let pid = scheduler::register(&handle, proc);
+
+        self.push_value(Stackable::Int(pid as i32))
+    }
+
+    /// Pops a message then a pid and appends the message to that process's mailbox.
+    pub fn send(&mut self) -> Result<(), VmError> {
+        let (_, handle) = self.scheduler.clone().ok_or(VmError::NoScheduler)?;
+
+        if let [pid, message] = &self.pop(2)?[..] {
+            let pid = match pid {
+                Stackable::Int(pid) if *pid >= 0 => *pid as Pid,
+                value => {
+                    return Err(VmError::InvalidOperand {
+                        value: format!("{:?}", value),
+                    })
+                }
+            };
+
+            let limit = self.vm.limits.max_heap_bytes;
+
+            if !scheduler::lock(&handle).send(pid, message.clone(), limit)? {
+                return Err(VmError::UnknownProcess { pid });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the oldest message in the process's mailbox, blocks until one arrives if the
+    /// mailbox is empty.
+    pub fn receive(&mut self) -> Result<(), VmError> {
+        let (pid, handle) = self.scheduler.clone().ok_or(VmError::NoScheduler)?;
+        let message = scheduler::lock(&handle).receive(pid);
+
+        self.waiting = message.is_none();
+
+        match message {
+            Some(message) => self.push_value(message),
+            None => Ok(()),
+        }
+    }
+
+    /// Pops top item and stores it in the global variable named by constant at `name_index`.
+    pub fn setglobal(&mut self, name_index: u32) -> Result<(), VmError> {
+        self.check_stack_size(1)?;
+
+        let stackable = self.pop_value().unwrap();
+        let counter = self.heap.counter();
+        counter.charge(stackable.heap_size(), self.vm.limits.max_heap_bytes)?;
+
+        if let Some(previous) = self.vm.lock_globals().insert(name_index, stackable) {
+            counter.discharge(previous.heap_size());
+        }
+
+        Ok(())
+    }
+
+    /// Pushes the global variable named by constant at `name_index`.
+    pub fn getglobal(&mut self, name_index: u32) -> Result<(), VmError> {
+        let stackable = self.vm.lock_globals().get(&name_index).cloned();
+
+        match stackable {
+            Some(stackable) => self.push_value(stackable),
+            None => Err(VmError::UndefinedGlobal {
+                name: self.vm.constant_name(name_index),
+            }),
+        }
+    }
+
+    /// Pushes pid of the current process.
+    pub fn pid(&mut self) -> Result<(), VmError> {
+        let (pid, _) = self.scheduler.as_ref().ok_or(VmError::NoScheduler)?;
+        let pid = *pid;
+
+        self.push_value(Stackable::Int(pid as i32))
+    }
+
+    /// Loads the module named by constant at `name_index` from the VM's module resolver and
+    /// runs its top level code in a frame of its own, the caller resumes at the next
+    /// instruction once it returns. Does nothing if the module is loaded already.
+    pub fn import(&mut self, name_index: u32) -> Result<(), VmError> {
+        let name = match self.vm.constants.get(name_index as usize) {
+            Some(Stackable::String(name)) => name.clone(),
+            Some(value) => {
+                return Err(VmError::InvalidOperand {
+                    value: format!("{:?}", value),
+                })
+            }
+            None => {
+                return Err(VmError::UnknownConstant {
+                    index: name_index as usize,
+                })
+            }
+        };
+        let modules = self.modules.clone().ok_or(VmError::NoModuleResolver)?;
+        let limit = self.vm.limits.max_call_depth;
+
+        if self.depth() >= limit {
+            return Err(VmError::CallDepthExceeded { limit });
+        }
+
+        self.frame_mut().pos += 1;
+
+        let module = match modules.load(&name, self.vm.limits)? {
+            Some(module) => module,
+            None => return Ok(()),
+        };
+        let local_variable = local_slots(&mut self.heap, &module.vm, module.vm.code.max_locals)?;
+        let caller_vm = std::mem::replace(&mut self.vm, module.vm.clone());
+
+        // Top level code hands nothing back
+        self.frames.push(Frame {
+            function: None,
+            functions: HashMap::new(),
+            stack: Vec::new(),
+            local_variable,
+            return_size: Some(0),
+            pos: 0,
+            caller_vm: Some(caller_vm),
+        });
+
+        Ok(())
+    }
+
+    fn pop_coroutine(&mut self) -> Result<CoroutineRef, VmError> {
+        self.check_stack_size(1)?;
+
+        match self.pop_value().unwrap() {
+            Stackable::Coroutine(coroutine) => Ok(coroutine),
+            value => Err(VmError::InvalidOperand {
+                value: format!("{:?}", value),
+            }),
+        }
+    }
+
+    /// Function a call of `parameter_size` parameters resolves to, an import of the current
+    /// module or else a function in scope.
+    fn resolve_function(
+        &self,
+        function_name_index: u32,
+        parameter_size: u8,
+    ) -> Result<Callee, VmError> {
+        if let Some((module, name_index)) =
+            self.imported_function(function_name_index, parameter_size)?
+        {
+            let (pos, function) =
+                resolve_in(&module.vm, &module.functions, name_index, parameter_size)?;
+
+            return Ok(Callee {
+                pos,
+                function,
+                module: Some(module),
+            });
+        }
+
+        let (pos, function) = resolve_in(
+            &self.vm,
+            &self.frame().functions,
+            function_name_index,
+            parameter_size,
+        )?;
+
+        Ok(Callee {
+            pos,
+            function,
+            module: None,
+        })
+    }
+
+    /// Module the function is imported from and index of its name in the module's constant
+    /// pool, if the current module imports it. The module has to be loaded by `import` first.
+    fn imported_function(
+        &self,
+        function_name_index: u32,
+        parameter_size: u8,
+    ) -> Result<Option<(Arc<LoadedModule>, u32)>, VmError> {
+        let imports = &self.vm.module.imports;
+
+        if imports.is_empty() {
+            return Ok(None);
+        }
+
+        let import = match self.vm.constants.get(function_name_index as usize) {
+            Some(Stackable::String(name)) => imports
+                .iter()
+                .find(|import| import.function == *name && import.parameter_size == parameter_size),
+            _ => None,
+        };
+        let import = match import {
+            Some(import) => import,
+            None => return Ok(None),
+        };
+        let module = self
+            .modules
+            .as_ref()
+            .and_then(|modules| modules.get(&import.module))
+            .ok_or_else(|| VmError::ModuleNotLoaded {
+                name: import.module.clone(),
+            })?;
+        let exported = module.vm.module.exports.iter().any(|export| {
+            export.function == import.function && export.parameter_size == parameter_size
+        });
+
+        match module.vm.name_index(&import.function) {
+            Some(name_index) if exported => Ok(Some((module, name_index))),
+            _ => Err(VmError::UnknownFunction {
+                name: self.vm.constant_name(function_name_index),
+                parameter_size,
+            }),
+        }
+    }
+
+    /// Pops `parameter_size` arguments and binds them to parameters of the function at `pos`,
+    /// appending defaults of optional parameters not passed and collecting arguments beyond
+    /// the parameter size into the rest parameter.
+    fn pop_arguments(
+        &mut self,
+        vm: &VM,
+        pos: u32,
+        parameter_size: u8,
+    ) -> Result<Vec<Stackable>, VmError> {
+        let mut arguments = self.pop(parameter_size as usize)?;
+        let (name_index, parameter_size, arity) = match vm.function_declaration(pos) {
+            Some(Opcode::Func(name_index, parameter_size, _, _, arity)) => {
+                (*name_index, *parameter_size as usize, *arity)
+            }
+            _ => return Ok(arguments),
+        };
+        // Loaded code is checked already, code built in place may not be
+        let first_optional = parameter_size
+            .checked_sub(arity.optional as usize)
+            .ok_or_else(|| VmError::InvalidArity {
+                name: vm.constant_name(name_index),
+                parameter_size: parameter_size as u8,
+                optional: arity.optional,
+            })?;
+        let rest = arguments.split_off(std::cmp::min(arguments.len(), parameter_size));
+
+        for parameter in arguments.len()..parameter_size {
+            let index = arity.defaults as usize + parameter - first_optional;
+            let default = vm
+                .constants
+                .get(index)
+                .cloned()
+                .ok_or(VmError::UnknownConstant { index })?;
+
+            arguments.push(default);
+        }
+
+        if arity.rest {
+            arguments.push(Stackable::Array(rest));
+        }
+
+        Ok(arguments)
+    }
+
+    fn pop(&mut self, pop_size: usize) -> Result<Vec<Stackable>, VmError> {
+        self.check_stack_size(pop_size)?;
+
+        let stack = &mut self.frame_mut().stack;
+        let popped: Vec<Stackable> = stack.drain(stack.len() - pop_size..).collect();
+        self.release(popped.iter().map(Stackable::heap_size).sum());
+
+        Ok(popped)
+    }
+
+    /// Pops 2 operands for arithmetic opcodes, both must be numeric.
+    fn pop_operands(&mut self) -> Result<Vec<Stackable>, VmError> {
+        let operands = self.pop(2)?;
+
+        if let Some(operand) = operands.iter().find(|s| !s.is_numeric()) {
+            return Err(VmError::InvalidOperand {
+                value: format!("{:?}", operand),
+            });
+        }
+
+        Ok(operands)
+    }
+
+    fn pop_value(&mut self) -> Option<Stackable> {
+        let stackable = self.frame_mut().stack.pop();
+
+        if let Some(s) = &stackable {
+            self.release(s.heap_size());
+        }
+
+        stackable
+    }
+
+    fn push(&mut self, items: &[Stackable]) -> Result<(), VmError> {
+        for item in items {
+            self.push_value(item.clone())?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn push_value(&mut self, stackable: Stackable) -> Result<(), VmError> {
+        let limits = self.vm.limits;
+
+        if self.frame().stack.len() >= limits.max_stack_size {
+            return Err(VmError::StackOverflow {
+                limit: limits.max_stack_size,
+            });
+        }
+
+        if let Stackable::String(s) = &stackable {
+            if s.len() > limits.max_string_length {
+                return Err(VmError::StringTooLong {
+                    length: s.len(),
+                    limit: limits.max_string_length,
+                });
+            }
+        }
+
+        self.allocate(stackable.heap_size())?;
+        self.frame_mut().stack.push(stackable);
+
+        Ok(())
+    }
+
+    fn allocate(&mut self, size: usize) -> Result<(), VmError> {
+        self.heap.allocate(size, self.vm.limits.max_heap_bytes)
+    }
+
+    fn release(&mut self, size: usize) {
+        self.heap.release(size);
+    }
+
+    fn check_local_index(&self, index: usize) -> Result<(), VmError> {
+        let limit = self.frame().local_variable.len();
+
+        if index >= limit {
+            Err(VmError::LocalsExceeded { index, limit })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_stack_size(&self, required_size: usize) -> Result<(), VmError> {
+        let actual_size = self.frame().stack.len();
+
+        if actual_size < required_size {
+            Err(VmError::StackUnderflow {
+                required_size,
+                actual_size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Entry and signature of the function among `functions` of `vm` a call of `parameter_size`
+/// parameters resolves to, the exact signature or else the one of fewest parameters whose
+/// arity accepts them.
+fn resolve_in(
+    vm: &VM,
+    functions: &HashMap<FunctionSignature, u32>,
+    function_name_index: u32,
+    parameter_size: u8,
+) -> Result<(u32, FunctionSignature), VmError> {
+    let signature = FunctionSignature::new(function_name_index, parameter_size);
+
+    if let Some(pos) = functions.get(&signature) {
+        return Ok((*pos, signature));
+    }
+
+    let mut candidates = functions
+        .iter()
+        .filter(|(signature, _)| signature.function_name_index == function_name_index)
+        .map(|(signature, pos)| (*signature, *pos, vm.function_arity(*pos)))
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(signature, _, _)| signature.parameter_size);
+
+    let resolved = candidates
+        .iter()
+        .find(|(signature, _, arity)| arity.accepts(signature.parameter_size, parameter_size));
+
+    match resolved {
+        Some((signature, pos, _)) => Ok((*pos, *signature)),
+        None if candidates.is_empty() => Err(VmError::UnknownFunction {
+            name: vm.constant_name(function_name_index),
+            parameter_size,
+        }),
+        None => Err(VmError::ArityMismatch {
+            name: vm.constant_name(function_name_index),
+            parameter_size,
+            candidates: candidates
+                .iter()
+                .map(|(signature, _, arity)| arity.describe(signature.parameter_size))
+                .collect(),
+        }),
+    }
+}
+
+/// Uninitialized local variable slots, no more than the VM allows. Slots count on the heap
+/// while their frame lives, so deep calls into functions with many locals can't outgrow it.
+fn local_slots(
+    heap: &mut HeapShare,
+    vm: &VM,
+    max_locals: u32,
+) -> Result<Vec<Option<Stackable>>, VmError> {
+    let slots = std::cmp::min(max_locals as usize, vm.limits.max_locals);
+
+    heap.allocate(slots_size(slots), vm.limits.max_heap_bytes)?;
+
+    Ok(vec![None; slots])
+}
+
+fn slots_size(slots: usize) -> usize {
+    slots * std::mem::size_of::<Option<Stackable>>()
 }
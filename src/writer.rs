@@ -0,0 +1,218 @@
+use crate::{
+    bytecode::{Encoding, FORMAT_VERSION},
+    module::Module,
+    opcode::Opcode,
+    vm::{Stackable, VM},
+};
+
+/// Encodes a [`VM`]'s module, constants, code and debug info back into the bytecode format
+/// [`Loader`](crate::Loader) reads, so that loading written bytecode yields an equal VM.
+pub struct Writer<'a> {
+    vm: &'a VM,
+    encoding: Encoding,
+    byte_pool: Vec<u8>,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(vm: &'a VM) -> Self {
+        Self {
+            vm,
+            encoding: Encoding::default(),
+            byte_pool: vec![],
+        }
+    }
+
+    /// Lays out instruction operands as `encoding` does, fixed width by default.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn write(mut self) -> Vec<u8> {
+        self.byte_pool
+            .extend_from_slice(&[0x47, 0x45, 0x41, 0x52, 0x57, 0x4F, 0x52, 0x4B]);
+        self.byte_pool.push(FORMAT_VERSION);
+        self.byte_pool.push(self.encoding.flag());
+
+        write_module(&mut self.byte_pool, self.vm.module());
+        self.write_constants();
+        self.write_code();
+
+        if let Some(debug_info) = self.vm.debug_info() {
+            write_string(&mut self.byte_pool, debug_info.source());
+            self.write_u32(debug_info.lines().len() as u32);
+
+            for (pos, line) in debug_info.lines() {
+                self.write_u32(*pos);
+                self.write_u32(*line);
+            }
+        }
+
+        self.byte_pool
+    }
+
+    fn write_constants(&mut self) {
+        let constants = self.vm.constants();
+
+        self.write_u32(constants.len() as u32);
+
+        for constant in constants {
+            match constant {
+                Stackable::Int(int) => {
+                    self.byte_pool.push(0x00);
+                    self.byte_pool.extend_from_slice(&int.to_be_bytes());
+                }
+                Stackable::Long(long) => {
+                    self.byte_pool.push(0x01);
+                    self.byte_pool.extend_from_slice(&long.to_be_bytes());
+                }
+                Stackable::Float(float) => {
+                    self.byte_pool.push(0x02);
+                    self.byte_pool.extend_from_slice(&float.to_be_bytes());
+                }
+                Stackable::Double(double) => {
+                    self.byte_pool.push(0x03);
+                    self.byte_pool.extend_from_slice(&double.to_be_bytes());
+                }
+                Stackable::String(string) => {
+                    self.byte_pool.push(0x04);
+                    write_string(&mut self.byte_pool, string);
+                }
+                Stackable::Coroutine(_) => panic!("Coroutine cannot be a constant"),
+                Stackable::Array(_) => panic!("Array cannot be a constant"),
+            }
+        }
+    }
+
+    fn write_code(&mut self) {
+        let code = self.vm.code();
+
+        self.write_u32(code.instructions().len() as u32);
+        self.write_u32(code.max_locals());
+
+        for opcode in code.instructions() {
+            self.write_instruction(*opcode);
+        }
+    }
+
+    fn write_instruction(&mut self, opcode: Opcode) {
+        match opcode {
+            Opcode::Ldc(index) => {
+                self.byte_pool.push(0x00);
+                self.write_operand(index);
+            }
+            Opcode::Dump => self.byte_pool.push(0x01),
+            Opcode::Add => self.byte_pool.push(0x02),
+            Opcode::Sub => self.byte_pool.push(0x03),
+            Opcode::Mul => self.byte_pool.push(0x04),
+            Opcode::Div => self.byte_pool.push(0x05),
+            Opcode::Mod => self.byte_pool.push(0x06),
+            Opcode::Dup => self.byte_pool.push(0x07),
+            Opcode::Swp => self.byte_pool.push(0x08),
+            Opcode::Store(index) => {
+                self.byte_pool.push(0x09);
+                self.encoding.write_u16(&mut self.byte_pool, index);
+            }
+            Opcode::Load(index) => {
+                self.byte_pool.push(0x0A);
+                self.encoding.write_u16(&mut self.byte_pool, index);
+            }
+            Opcode::Goto(index) => {
+                self.byte_pool.push(0x0B);
+                self.write_operand(index);
+            }
+            Opcode::Nop => self.byte_pool.push(0x0C),
+            Opcode::Func(function_name_index, parameter_size, return_size, max_locals, arity) => {
+                self.byte_pool.push(0x0D);
+                self.write_operand(function_name_index);
+                self.byte_pool.push(parameter_size);
+                self.byte_pool.push(return_size);
+                self.write_operand(max_locals);
+                self.byte_pool.push(arity.optional);
+                self.write_operand(arity.defaults);
+                self.byte_pool.push(arity.rest as u8);
+            }
+            Opcode::Return => self.byte_pool.push(0x0E),
+            Opcode::Invoke(function_name_index, parameter_size) => {
+                self.write_call(0x0F, function_name_index, parameter_size)
+            }
+            Opcode::Coroutine(function_name_index, parameter_size) => {
+                self.write_call(0x10, function_name_index, parameter_size)
+            }
+            Opcode::Yield => self.byte_pool.push(0x11),
+            Opcode::Resume => self.byte_pool.push(0x12),
+            Opcode::Done => self.byte_pool.push(0x13),
+            Opcode::Spawn(function_name_index, parameter_size) => {
+                self.write_call(0x14, function_name_index, parameter_size)
+            }
+            Opcode::Send => self.byte_pool.push(0x15),
+            Opcode::Receive => self.byte_pool.push(0x16),
+            Opcode::Pid => self.byte_pool.push(0x17),
+            Opcode::SetGlobal(name_index) => {
+                self.byte_pool.push(0x18);
+                self.write_operand(name_index);
+            }
+            Opcode::GetGlobal(name_index) => {
+                self.byte_pool.push(0x19);
+                self.write_operand(name_index);
+            }
+            Opcode::TailInvoke(function_name_index, parameter_size) => {
+                self.write_call(0x1A, function_name_index, parameter_size)
+            }
+            Opcode::Import(name_index) => {
+                self.byte_pool.push(0x1B);
+                self.write_operand(name_index);
+            }
+        }
+    }
+
+    fn write_call(&mut self, opcode: u8, function_name_index: u32, parameter_size: u8) {
+        self.byte_pool.push(opcode);
+        self.write_operand(function_name_index);
+        self.byte_pool.push(parameter_size);
+    }
+
+    fn write_operand(&mut self, operand: u32) {
+        self.encoding.write_u32(&mut self.byte_pool, operand);
+    }
+
+    fn write_u32(&mut self, data: u32) {
+        self.byte_pool.extend_from_slice(&data.to_be_bytes());
+    }
+}
+
+/// Module name, then imports and exports, as the header of bytecode continues.
+pub(crate) fn write_module(byte_pool: &mut Vec<u8>, module: &Module) {
+    write_string(byte_pool, &module.name);
+    byte_pool.extend_from_slice(&(module.imports.len() as u32).to_be_bytes());
+
+    for import in &module.imports {
+        write_string(byte_pool, &import.module);
+        write_string(byte_pool, &import.function);
+        byte_pool.push(import.parameter_size);
+    }
+
+    byte_pool.extend_from_slice(&(module.exports.len() as u32).to_be_bytes());
+
+    for export in &module.exports {
+        write_string(byte_pool, &export.function);
+        byte_pool.push(export.parameter_size);
+    }
+}
+
+/// String prefixed by 4 bytes of its length.
+///
+/// # Panics
+/// If the string is 4 GiB or longer, its length would not fit in the prefix.
+pub(crate) fn write_string(byte_pool: &mut Vec<u8>, string: &str) {
+    let length = u32::try_from(string.len()).unwrap_or_else(|_| {
+        panic!(
+            "String of {} bytes is longer than bytecode allows, at most {} bytes",
+            string.len(),
+            u32::MAX
+        )
+    });
+
+    byte_pool.extend_from_slice(&length.to_be_bytes());
+    byte_pool.extend_from_slice(string.as_bytes());
+}
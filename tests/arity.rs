@@ -0,0 +1,119 @@
+use cogwork::{
+    error::{LoadError, VmError},
+    opcode::{Arity, Opcode},
+    vm::{Code, Process, Stackable, VM},
+    Loader, Writer,
+};
+
+fn name(name: &str) -> Stackable {
+    Stackable::String(name.to_string())
+}
+
+/// `f/1` taking exactly one parameter, and `f/3` whose last parameter is optional and which
+/// collects extra arguments.
+fn overloads(arguments: u8) -> VM {
+    VM::new_vm(
+        vec![name("f"), Stackable::Int(0)],
+        Code::new(
+            vec![
+                Opcode::Func(0, 1, 1, 0, Arity::default()),
+                Opcode::Return,
+                Opcode::Func(
+                    0,
+                    3,
+                    1,
+                    0,
+                    Arity {
+                        optional: 1,
+                        defaults: 1,
+                        rest: true,
+                    },
+                ),
+                Opcode::Return,
+                Opcode::Ldc(1),
+                Opcode::Ldc(1),
+                Opcode::Ldc(1),
+                Opcode::Ldc(1),
+                Opcode::Ldc(1),
+                Opcode::Invoke(0, arguments),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+}
+
+/// `f/1` which declares 3 optional parameters.
+fn too_many_optional() -> VM {
+    VM::new_vm(
+        vec![name("f"), Stackable::Int(0)],
+        Code::new(
+            vec![
+                Opcode::Func(
+                    0,
+                    1,
+                    1,
+                    1,
+                    Arity {
+                        optional: 3,
+                        defaults: 1,
+                        rest: false,
+                    },
+                ),
+                Opcode::Return,
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+}
+
+#[test]
+fn calls_resolve_by_arity() {
+    for arguments in [1, 2, 3, 5] {
+        assert!(
+            Process::new_process(overloads(arguments), 0).run().is_ok(),
+            "{} arguments",
+            arguments
+        );
+    }
+}
+
+#[test]
+fn arity_mismatch_lists_candidates() {
+    assert_eq!(
+        Process::new_process(overloads(0), 0).run(),
+        Err(VmError::ArityMismatch {
+            name: "f".to_string(),
+            parameter_size: 0,
+            candidates: vec!["1".to_string(), "2+".to_string()],
+        })
+    );
+}
+
+#[test]
+fn more_optional_than_parameters_faults() {
+    assert_eq!(
+        Process::new_process(too_many_optional(), 0).run(),
+        Err(VmError::InvalidArity {
+            name: "f".to_string(),
+            parameter_size: 1,
+            optional: 3,
+        })
+    );
+}
+
+#[test]
+fn more_optional_than_parameters_fails_to_load() {
+    let bytecode = Writer::new(&too_many_optional()).write();
+
+    assert_eq!(
+        Loader::new(&bytecode).try_load(),
+        Err(LoadError::InvalidArity {
+            pos: 0,
+            parameter_size: 1,
+            optional: 3,
+        })
+    );
+}
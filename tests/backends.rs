@@ -0,0 +1,311 @@
+mod common;
+
+use cogwork::{
+    error::VmError,
+    opcode::{Arity, Opcode},
+    vm::{Backend, Code, Stackable, VmLimits, VM},
+};
+use common::{name, run_on, Outcome};
+
+/// Asserts the register backend runs `program` and agrees with the stack backend.
+fn assert_same(program: fn() -> VM) -> Outcome {
+    common::assert_same(program, Backend::Register)
+}
+
+fn nested_functions() -> VM {
+    // a = 10; def add(x): def mul(z): return z * 90; return mul(x + a); dump(add(10))
+    VM::new_vm(
+        vec![
+            Stackable::Int(10),
+            name("a"),
+            name("add"),
+            name("mul"),
+            Stackable::Int(90),
+        ],
+        Code::new(
+            vec![
+                Opcode::Ldc(0),
+                Opcode::Dup,
+                Opcode::SetGlobal(1),
+                Opcode::Func(2, 1, 1, 0, Arity::default()),
+                Opcode::Func(3, 1, 1, 0, Arity::default()),
+                Opcode::Ldc(4),
+                Opcode::Mul,
+                Opcode::Return,
+                Opcode::GetGlobal(1),
+                Opcode::Add,
+                Opcode::Invoke(3, 1),
+                Opcode::Return,
+                Opcode::Invoke(2, 1),
+                Opcode::Dump,
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+}
+
+fn locals_and_mixed_types() -> VM {
+    VM::new_vm(
+        vec![
+            Stackable::Int(7),
+            Stackable::Long(5),
+            Stackable::Double(0.5),
+        ],
+        Code::new(
+            vec![
+                Opcode::Ldc(0),
+                Opcode::Store(0),
+                Opcode::Ldc(1),
+                Opcode::Store(1),
+                Opcode::Load(0),
+                Opcode::Load(1),
+                Opcode::Sub,
+                Opcode::Store(2),
+                Opcode::Load(2),
+                Opcode::Load(2),
+                Opcode::Mul,
+                Opcode::Dup,
+                Opcode::Dump,
+                Opcode::Ldc(2),
+                Opcode::Swp,
+                Opcode::Div,
+                Opcode::Load(0),
+                Opcode::Mod,
+                Opcode::Load(2),
+                Opcode::Return,
+            ],
+            3,
+        ),
+    )
+}
+
+fn skipped_code() -> VM {
+    VM::new_vm(
+        vec![Stackable::Int(1), Stackable::Int(2)],
+        Code::new(
+            vec![
+                Opcode::Ldc(0),
+                Opcode::Goto(4),
+                Opcode::Ldc(1),
+                Opcode::Dump,
+                Opcode::Dump,
+                Opcode::Ldc(1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+}
+
+fn endless_recursion() -> VM {
+    VM::new_vm(
+        vec![name("f"), Stackable::Int(1)],
+        Code::new(
+            vec![
+                Opcode::Func(0, 1, 1, 0, Arity::default()),
+                Opcode::Dump,
+                Opcode::Ldc(1),
+                Opcode::Invoke(0, 1),
+                Opcode::Return,
+                Opcode::Ldc(1),
+                Opcode::Invoke(0, 1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_limits(VmLimits {
+        max_call_depth: 16,
+        ..VmLimits::default()
+    })
+}
+
+#[test]
+fn calls_and_globals() {
+    assert_eq!(
+        assert_same(nested_functions),
+        (Ok(vec![]), "1800\n".to_string())
+    );
+}
+
+#[test]
+fn locals_and_arithmetic() {
+    let (result, output) = assert_same(locals_and_mixed_types);
+
+    assert_eq!(output, "4L\n");
+    assert_eq!(
+        result,
+        Ok(vec![Stackable::Double(7.0), Stackable::Long(-2)])
+    );
+}
+
+#[test]
+fn goto() {
+    assert_eq!(
+        assert_same(skipped_code),
+        (Ok(vec![Stackable::Int(2)]), "1\n".to_string())
+    );
+}
+
+#[test]
+fn call_depth_exceeded() {
+    let (result, output) = assert_same(endless_recursion);
+
+    assert_eq!(result, Err(VmError::CallDepthExceeded { limit: 16 }));
+    assert_eq!(output.lines().count(), 15);
+}
+
+#[test]
+fn string_too_long() {
+    let (result, _) = assert_same(|| {
+        VM::new_vm(
+            vec![name("long")],
+            Code::new(vec![Opcode::Ldc(0), Opcode::Return], 0),
+        )
+        .with_limits(VmLimits {
+            max_string_length: 3,
+            ..VmLimits::default()
+        })
+    });
+
+    assert_eq!(
+        result,
+        Err(VmError::StringTooLong {
+            length: 4,
+            limit: 3
+        })
+    );
+}
+
+#[test]
+fn heap_exhausted() {
+    let (result, _) = assert_same(|| {
+        VM::new_vm(
+            vec![Stackable::String("x".repeat(4096))],
+            Code::new(vec![Opcode::Ldc(0), Opcode::Ldc(0), Opcode::Return], 0),
+        )
+        .with_limits(VmLimits {
+            max_heap_bytes: 6000,
+            ..VmLimits::default()
+        })
+    });
+
+    assert!(matches!(
+        result,
+        Err(VmError::HeapExhausted { limit: 6000, .. })
+    ));
+}
+
+#[test]
+fn faults() {
+    let (result, _) = assert_same(|| {
+        VM::new_vm(
+            vec![name("a")],
+            Code::new(vec![Opcode::GetGlobal(0), Opcode::Return], 0),
+        )
+    });
+
+    assert_eq!(
+        result,
+        Err(VmError::UndefinedGlobal {
+            name: "a".to_string()
+        })
+    );
+
+    let (result, _) = assert_same(|| {
+        VM::new_vm(
+            vec![name("a"), Stackable::Int(1)],
+            Code::new(
+                vec![Opcode::Ldc(0), Opcode::Ldc(1), Opcode::Add, Opcode::Return],
+                0,
+            ),
+        )
+    });
+
+    assert!(matches!(result, Err(VmError::InvalidOperand { .. })));
+}
+
+#[test]
+fn uninitialized_local_faults_at_load() {
+    // Local 1 is never stored, nothing may be dumped before the fault
+    let (result, output) = assert_same(|| {
+        VM::new_vm(
+            vec![Stackable::Int(1)],
+            Code::new(
+                vec![
+                    Opcode::Ldc(0),
+                    Opcode::Store(0),
+                    Opcode::Load(0),
+                    Opcode::Load(1),
+                    Opcode::Ldc(0),
+                    Opcode::Dump,
+                    Opcode::Add,
+                    Opcode::Return,
+                ],
+                2,
+            ),
+        )
+    });
+
+    assert_eq!(result, Err(VmError::UninitializedLocal { index: 1 }));
+    assert_eq!(output, "");
+}
+
+#[test]
+fn code_beyond_stack_limit_runs_on_stack() {
+    let program = || {
+        VM::new_vm(
+            vec![Stackable::Int(1)],
+            Code::new(
+                vec![
+                    Opcode::Ldc(0),
+                    Opcode::Ldc(0),
+                    Opcode::Ldc(0),
+                    Opcode::Return,
+                ],
+                0,
+            ),
+        )
+        .with_limits(VmLimits {
+            max_stack_size: 2,
+            ..VmLimits::default()
+        })
+    };
+
+    assert_eq!(
+        program().with_backend(Backend::Register).backend(),
+        Backend::Stack
+    );
+    assert_eq!(
+        run_on(program(), Backend::Register),
+        run_on(program(), Backend::Stack)
+    );
+    assert_eq!(
+        run_on(program(), Backend::Register).0,
+        Err(VmError::StackOverflow { limit: 2 })
+    );
+}
+
+#[test]
+fn code_beyond_locals_limit_runs_on_stack() {
+    let program = || {
+        VM::new_vm(
+            vec![Stackable::Int(1)],
+            Code::new(vec![Opcode::Ldc(0), Opcode::Store(2), Opcode::Return], 4),
+        )
+        .with_limits(VmLimits {
+            max_locals: 2,
+            ..VmLimits::default()
+        })
+    };
+
+    assert_eq!(
+        program().with_backend(Backend::Register).backend(),
+        Backend::Stack
+    );
+    assert_eq!(
+        run_on(program(), Backend::Register).0,
+        Err(VmError::LocalsExceeded { index: 2, limit: 2 })
+    );
+}
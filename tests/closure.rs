@@ -0,0 +1,330 @@
+mod common;
+
+use cogwork::{
+    error::VmError,
+    opcode::{Arity, Opcode},
+    vm::{Backend, Code, Stackable, VmLimits, VM},
+};
+use common::{name, Outcome};
+
+/// Asserts the closure backend runs `program` and agrees with the stack backend.
+fn assert_same(program: fn() -> VM) -> Outcome {
+    common::assert_same(program, Backend::Closure)
+}
+
+fn func(name_index: u32, parameter_size: u8, return_size: u8) -> Opcode {
+    Opcode::Func(name_index, parameter_size, return_size, 0, Arity::default())
+}
+
+fn with_limits(vm: VM, limits: VmLimits) -> VM {
+    vm.with_limits(limits)
+}
+
+#[test]
+fn calls() {
+    // a = 10; def add(x): def mul(z): return z * 90; return mul(x + a); dump(add(10))
+    let outcome = assert_same(|| {
+        VM::new_vm(
+            vec![
+                Stackable::Int(10),
+                name("a"),
+                name("add"),
+                name("mul"),
+                Stackable::Int(90),
+            ],
+            Code::new(
+                vec![
+                    Opcode::Ldc(0),
+                    Opcode::Dup,
+                    Opcode::SetGlobal(1),
+                    func(2, 1, 1),
+                    func(3, 1, 1),
+                    Opcode::Ldc(4),
+                    Opcode::Mul,
+                    Opcode::Return,
+                    Opcode::GetGlobal(1),
+                    Opcode::Add,
+                    Opcode::Invoke(3, 1),
+                    Opcode::Return,
+                    Opcode::Invoke(2, 1),
+                    Opcode::Dump,
+                    Opcode::Return,
+                ],
+                0,
+            ),
+        )
+    });
+
+    assert_eq!(outcome, (Ok(vec![]), "1800\n".to_string()));
+}
+
+#[test]
+fn optional_and_rest_parameters() {
+    // def f(a, b = 5, *rest): return a + b, rest
+    let outcome = assert_same(|| {
+        VM::new_vm(
+            vec![name("f"), Stackable::Int(5), Stackable::Int(1)],
+            Code::new(
+                vec![
+                    Opcode::Func(
+                        0,
+                        2,
+                        2,
+                        3,
+                        Arity {
+                            optional: 1,
+                            defaults: 1,
+                            rest: true,
+                        },
+                    ),
+                    Opcode::Store(2),
+                    Opcode::Add,
+                    Opcode::Load(2),
+                    Opcode::Return,
+                    Opcode::Ldc(2),
+                    Opcode::Invoke(0, 1),
+                    Opcode::Ldc(2),
+                    Opcode::Ldc(2),
+                    Opcode::Ldc(2),
+                    Opcode::Ldc(2),
+                    Opcode::Invoke(0, 4),
+                    Opcode::Return,
+                ],
+                0,
+            ),
+        )
+    });
+
+    assert_eq!(
+        outcome.0,
+        Ok(vec![
+            Stackable::Int(6),
+            Stackable::Array(vec![]),
+            Stackable::Int(2),
+            Stackable::Array(vec![Stackable::Int(1), Stackable::Int(1)]),
+        ])
+    );
+}
+
+#[test]
+fn tail_calls() {
+    // f0 to f19 each tail call the next, deeper than the call depth limit allows
+    let outcome = assert_same(|| {
+        let mut constants = (0..20)
+            .map(|i| name(&format!("f{}", i)))
+            .collect::<Vec<_>>();
+        let mut instructions = vec![func(19, 1, 1), Opcode::Return];
+
+        for i in (0..19).rev() {
+            instructions.extend_from_slice(&[
+                func(i, 1, 1),
+                Opcode::TailInvoke(i + 1, 1),
+                Opcode::Return,
+            ]);
+        }
+
+        constants.push(Stackable::Int(3));
+        instructions.extend_from_slice(&[Opcode::Ldc(20), Opcode::Invoke(0, 1), Opcode::Return]);
+
+        with_limits(
+            VM::new_vm(constants, Code::new(instructions, 0)),
+            VmLimits {
+                max_call_depth: 4,
+                ..VmLimits::default()
+            },
+        )
+    });
+
+    assert_eq!(outcome.0, Ok(vec![Stackable::Int(3)]));
+}
+
+#[test]
+fn coroutines() {
+    let outcome = assert_same(|| {
+        VM::new_vm(
+            vec![name("gen"), Stackable::Int(1), Stackable::Int(2)],
+            Code::new(
+                vec![
+                    func(0, 0, 1),
+                    Opcode::Ldc(1),
+                    Opcode::Yield,
+                    Opcode::Ldc(2),
+                    Opcode::Return,
+                    Opcode::Coroutine(0, 0),
+                    Opcode::Dup,
+                    Opcode::Resume,
+                    Opcode::Swp,
+                    Opcode::Dup,
+                    Opcode::Resume,
+                    Opcode::Swp,
+                    Opcode::Done,
+                    Opcode::Return,
+                ],
+                0,
+            ),
+        )
+    });
+
+    assert_eq!(
+        outcome.0,
+        Ok(vec![
+            Stackable::Int(1),
+            Stackable::Int(2),
+            Stackable::Int(1)
+        ])
+    );
+}
+
+#[test]
+fn call_depth_exceeded() {
+    let outcome = assert_same(|| {
+        with_limits(
+            VM::new_vm(
+                vec![name("f")],
+                Code::new(
+                    vec![
+                        func(0, 0, 0),
+                        Opcode::Invoke(0, 0),
+                        Opcode::Return,
+                        Opcode::Invoke(0, 0),
+                        Opcode::Return,
+                    ],
+                    0,
+                ),
+            ),
+            VmLimits {
+                max_call_depth: 8,
+                ..VmLimits::default()
+            },
+        )
+    });
+
+    assert_eq!(outcome.0, Err(VmError::CallDepthExceeded { limit: 8 }));
+}
+
+#[test]
+fn stack_overflow() {
+    let outcome = assert_same(|| {
+        with_limits(
+            VM::new_vm(
+                vec![Stackable::Int(1)],
+                Code::new(
+                    vec![Opcode::Ldc(0), Opcode::Dup, Opcode::Dup, Opcode::Return],
+                    0,
+                ),
+            ),
+            VmLimits {
+                max_stack_size: 2,
+                ..VmLimits::default()
+            },
+        )
+    });
+
+    assert_eq!(outcome.0, Err(VmError::StackOverflow { limit: 2 }));
+}
+
+#[test]
+fn heap_and_string_limits() {
+    let outcome = assert_same(|| {
+        with_limits(
+            VM::new_vm(
+                vec![Stackable::String("x".repeat(4096))],
+                Code::new(vec![Opcode::Ldc(0), Opcode::Dup, Opcode::Return], 0),
+            ),
+            VmLimits {
+                max_heap_bytes: 6000,
+                ..VmLimits::default()
+            },
+        )
+    });
+
+    assert!(matches!(
+        outcome.0,
+        Err(VmError::HeapExhausted { limit: 6000, .. })
+    ));
+
+    let outcome = assert_same(|| {
+        with_limits(
+            VM::new_vm(
+                vec![name("long")],
+                Code::new(vec![Opcode::Ldc(0), Opcode::Return], 0),
+            ),
+            VmLimits {
+                max_string_length: 3,
+                ..VmLimits::default()
+            },
+        )
+    });
+
+    assert_eq!(
+        outcome.0,
+        Err(VmError::StringTooLong {
+            length: 4,
+            limit: 3
+        })
+    );
+}
+
+#[test]
+fn faults() {
+    let programs: [fn() -> VM; 5] = [
+        || {
+            VM::new_vm(
+                vec![name("a"), Stackable::Int(1)],
+                Code::new(
+                    vec![Opcode::Ldc(0), Opcode::Ldc(1), Opcode::Add, Opcode::Return],
+                    0,
+                ),
+            )
+        },
+        || {
+            VM::new_vm(
+                vec![name("a")],
+                Code::new(vec![Opcode::GetGlobal(0), Opcode::Return], 0),
+            )
+        },
+        || {
+            VM::new_vm(
+                vec![name("f")],
+                Code::new(vec![Opcode::Invoke(0, 0), Opcode::Return], 0),
+            )
+        },
+        || VM::new_vm(vec![], Code::new(vec![Opcode::Add, Opcode::Return], 0)),
+        || {
+            VM::new_vm(
+                vec![Stackable::Int(1)],
+                Code::new(vec![Opcode::Ldc(0), Opcode::Yield, Opcode::Return], 0),
+            )
+        },
+    ];
+
+    for program in programs {
+        let (result, output) = assert_same(program);
+
+        assert!(result.is_err());
+        assert_eq!(output, "");
+    }
+}
+
+#[test]
+fn dumps_before_fault() {
+    let outcome = assert_same(|| {
+        VM::new_vm(
+            vec![Stackable::Int(1), name("f")],
+            Code::new(
+                vec![
+                    Opcode::Ldc(0),
+                    Opcode::Dump,
+                    Opcode::Goto(4),
+                    Opcode::Dump,
+                    Opcode::Invoke(1, 0),
+                    Opcode::Return,
+                ],
+                0,
+            ),
+        )
+    });
+
+    assert_eq!(outcome.1, "1\n");
+    assert!(matches!(outcome.0, Err(VmError::UnknownFunction { .. })));
+}
@@ -0,0 +1,37 @@
+use std::sync::{Arc, Mutex};
+
+use cogwork::{
+    error::VmError,
+    vm::{Backend, Process, Stackable, VM},
+};
+
+/// Result of a run and what it dumped.
+pub type Outcome = (Result<Vec<Stackable>, VmError>, String);
+
+pub fn name(name: &str) -> Stackable {
+    Stackable::String(name.to_string())
+}
+
+/// Runs `vm` on `backend`, returning its result and what it dumped.
+pub fn run_on(vm: VM, backend: Backend) -> Outcome {
+    let vm = vm.with_backend(backend);
+    let output = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let mut process = Process::new_process(vm, 0);
+
+    process.set_output(output.clone());
+
+    let result = process.run();
+    let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+
+    (result, output)
+}
+
+/// Asserts `backend` runs `program` and agrees with the stack backend.
+pub fn assert_same(program: fn() -> VM, backend: Backend) -> Outcome {
+    assert_eq!(program().with_backend(backend).backend(), backend);
+
+    let expected = run_on(program(), Backend::Stack);
+
+    assert_eq!(run_on(program(), backend), expected);
+    expected
+}
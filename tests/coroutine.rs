@@ -0,0 +1,126 @@
+use cogwork::{
+    error::VmError,
+    opcode::{Arity, Opcode},
+    vm::{Code, Process, Stackable, VM},
+};
+
+fn name(name: &str) -> Stackable {
+    Stackable::String(name.to_string())
+}
+
+fn func(name_index: u32, return_size: u8) -> Opcode {
+    Opcode::Func(name_index, 0, return_size, 0, Arity::default())
+}
+
+fn run(constants: Vec<Stackable>, instructions: Vec<Opcode>) -> Result<Vec<Stackable>, VmError> {
+    Process::new_process(VM::new_vm(constants, Code::new(instructions, 1)), 0).run()
+}
+
+/// `counter` yields 1 and 2, then returns 3.
+fn counter(mut tail: Vec<Opcode>) -> Vec<Opcode> {
+    let mut instructions = vec![
+        func(0, 1),
+        Opcode::Ldc(1),
+        Opcode::Yield,
+        Opcode::Ldc(2),
+        Opcode::Yield,
+        Opcode::Ldc(3),
+        Opcode::Return,
+        Opcode::Coroutine(0, 0),
+        Opcode::Store(0),
+    ];
+
+    instructions.append(&mut tail);
+    instructions
+}
+
+fn constants() -> Vec<Stackable> {
+    vec![
+        name("counter"),
+        Stackable::Int(1),
+        Stackable::Int(2),
+        Stackable::Int(3),
+    ]
+}
+
+#[test]
+fn resumes_until_done() {
+    let result = run(
+        constants(),
+        counter(vec![
+            Opcode::Load(0),
+            Opcode::Resume,
+            Opcode::Load(0),
+            Opcode::Done,
+            Opcode::Load(0),
+            Opcode::Resume,
+            Opcode::Load(0),
+            Opcode::Resume,
+            Opcode::Load(0),
+            Opcode::Done,
+            Opcode::Return,
+        ]),
+    );
+
+    assert_eq!(
+        result,
+        Ok(vec![
+            Stackable::Int(1),
+            Stackable::Int(0),
+            Stackable::Int(2),
+            Stackable::Int(3),
+            Stackable::Int(1),
+        ])
+    );
+}
+
+#[test]
+fn resuming_after_return_faults() {
+    let result = run(
+        constants(),
+        counter(vec![
+            Opcode::Load(0),
+            Opcode::Resume,
+            Opcode::Load(0),
+            Opcode::Resume,
+            Opcode::Load(0),
+            Opcode::Resume,
+            Opcode::Load(0),
+            Opcode::Resume,
+            Opcode::Return,
+        ]),
+    );
+
+    assert_eq!(result, Err(VmError::CoroutineFinished));
+}
+
+#[test]
+fn yield_outside_coroutine_faults() {
+    let result = run(
+        vec![Stackable::Int(1)],
+        vec![Opcode::Ldc(0), Opcode::Yield, Opcode::Return],
+    );
+
+    assert_eq!(result, Err(VmError::YieldOutsideCoroutine));
+}
+
+#[test]
+fn coroutine_resuming_itself_faults() {
+    // `self` finds its own coroutine in global `co` and resumes it
+    let result = run(
+        vec![name("self"), name("co")],
+        vec![
+            func(0, 0),
+            Opcode::GetGlobal(1),
+            Opcode::Resume,
+            Opcode::Return,
+            Opcode::Coroutine(0, 0),
+            Opcode::Dup,
+            Opcode::SetGlobal(1),
+            Opcode::Resume,
+            Opcode::Return,
+        ],
+    );
+
+    assert_eq!(result, Err(VmError::CoroutineRunning));
+}
@@ -0,0 +1,115 @@
+use cogwork::{
+    opcode::{Arity, Opcode},
+    vm::{Code, DebugInfo, Process, Stackable, VM},
+};
+
+fn func(name_index: u32) -> Opcode {
+    Opcode::Func(name_index, 0, 1, 0, Arity::default())
+}
+
+/// Calls `f` on line 5, `g` on lines 3 and 4 is never called.
+fn program() -> VM {
+    VM::new_vm(
+        vec![
+            Stackable::String("f".to_string()),
+            Stackable::String("g".to_string()),
+            Stackable::Int(7),
+        ],
+        Code::new(
+            vec![
+                func(0),
+                Opcode::Ldc(2),
+                Opcode::Return,
+                func(1),
+                Opcode::Ldc(2),
+                Opcode::Return,
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_debug_info(DebugInfo::new(
+        "program.cog".to_string(),
+        vec![(6, 5), (0, 1), (1, 2), (3, 3), (4, 4)],
+    ))
+}
+
+#[test]
+fn debug_info_maps_positions_and_lines() {
+    let debug_info = DebugInfo::new("program.cog".to_string(), vec![(4, 2), (2, 1), (6, 1)]);
+
+    assert_eq!(debug_info.lines(), [(2, 1), (4, 2), (6, 1)]);
+    assert_eq!(debug_info.line_of(0), None);
+    assert_eq!(debug_info.line_of(2), Some(1));
+    assert_eq!(debug_info.line_of(5), Some(2));
+    assert_eq!(debug_info.line_of(100), Some(1));
+    assert_eq!(debug_info.positions_of(1), [2, 6]);
+    assert_eq!(debug_info.positions_of(2), [4]);
+    assert!(debug_info.positions_of(3).is_empty());
+}
+
+#[test]
+fn lcov_reports_lines_and_functions() {
+    let mut process = Process::new_process(program(), 0);
+
+    process.enable_coverage();
+
+    while !process.step().is_terminated() {}
+
+    let report = process
+        .coverage()
+        .unwrap()
+        .to_lcov(process.vm(), "program.gear");
+
+    assert_eq!(
+        report,
+        "\
+TN:
+SF:program.cog
+FN:1,f/0
+FN:3,g/0
+FNDA:1,f/0
+FNDA:0,g/0
+FNF:2
+FNH:1
+DA:1,1
+DA:2,1
+DA:3,1
+DA:4,0
+DA:5,1
+LF:5
+LH:4
+end_of_record
+"
+    );
+}
+
+#[test]
+fn lcov_without_debug_info_counts_instructions_as_lines() {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(1)],
+        Code::new(
+            vec![
+                Opcode::Goto(2),
+                Opcode::Dump,
+                Opcode::Ldc(0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+    let mut process = Process::new_process(vm, 0);
+
+    process.enable_coverage();
+
+    while !process.step().is_terminated() {}
+
+    let report = process
+        .coverage()
+        .unwrap()
+        .to_lcov(process.vm(), "program.gear");
+
+    assert!(report.contains("SF:program.gear\n"));
+    assert!(report.contains("DA:1,1\nDA:2,0\nDA:3,1\nDA:4,1\nLF:4\nLH:3\n"));
+}
@@ -0,0 +1,282 @@
+use std::{env, fs, io::Cursor, path::PathBuf};
+
+use cogwork::{bytecode::BytecodeBuilder, dap::DapServer, vm::Stackable};
+use serde_json::{json, Value};
+
+/// Writes `bytecode` where a launch request can read it.
+fn program(name: &str, bytecode: &[u8]) -> PathBuf {
+    let path = env::temp_dir().join(format!("cogwork-dap-{}-{}.gear", std::process::id(), name));
+
+    fs::write(&path, bytecode).unwrap();
+
+    path
+}
+
+/// Dumps 1 on line 1 and 2 on line 2.
+fn two_lines() -> Vec<u8> {
+    let mut bytecode_builder = BytecodeBuilder::new();
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_source("two_lines.cog");
+    instruction_builder.visit_line(1);
+    instruction_builder.visit_ldc(Stackable::Int(1));
+    instruction_builder.visit_dump();
+    instruction_builder.visit_line(2);
+    instruction_builder.visit_ldc(Stackable::Int(2));
+    instruction_builder.visit_dump();
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+
+    bytecode_builder.visit_end()
+}
+
+/// Dumps 1 before any line is marked, then 2 on line 3.
+fn late_lines() -> Vec<u8> {
+    let mut bytecode_builder = BytecodeBuilder::new();
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_source("late_lines.cog");
+    instruction_builder.visit_ldc(Stackable::Int(1));
+    instruction_builder.visit_dump();
+    instruction_builder.visit_line(3);
+    instruction_builder.visit_ldc(Stackable::Int(2));
+    instruction_builder.visit_dump();
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+
+    bytecode_builder.visit_end()
+}
+
+/// Serves `requests` in order, returning every message sent back.
+fn serve(requests: &[(&str, Value)]) -> Vec<Value> {
+    let input = requests
+        .iter()
+        .enumerate()
+        .map(|(seq, (command, arguments))| {
+            let content = json!({
+                "seq": seq + 1,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            })
+            .to_string();
+
+            format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+        })
+        .collect::<String>();
+    let mut output = Vec::new();
+
+    DapServer::new(Cursor::new(input), &mut output)
+        .run()
+        .unwrap();
+
+    let mut output = output.as_slice();
+    let mut messages = Vec::new();
+
+    while let Some(rest) = output.strip_prefix(b"Content-Length: ") {
+        let header_end = rest
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let length = std::str::from_utf8(&rest[..header_end])
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        let content = &rest[header_end + 4..header_end + 4 + length];
+
+        messages.push(serde_json::from_slice(content).unwrap());
+        output = &rest[header_end + 4 + length..];
+    }
+
+    assert!(output.is_empty());
+
+    messages
+}
+
+fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+    messages
+        .iter()
+        .find(|message| message["type"] == "response" && message["command"] == command)
+        .unwrap_or_else(|| panic!("No response to {}", command))
+}
+
+/// Events in the order they were sent, as `event` or `event:reason`.
+fn events(messages: &[Value]) -> Vec<String> {
+    messages
+        .iter()
+        .filter(|message| message["type"] == "event")
+        .map(|message| match message["body"]["reason"].as_str() {
+            Some(reason) => format!("{}:{}", message["event"].as_str().unwrap(), reason),
+            None => message["event"].as_str().unwrap().to_string(),
+        })
+        .collect()
+}
+
+#[test]
+fn stops_at_breakpoint_and_runs_to_exit() {
+    let path = program("breakpoint", &two_lines());
+    let messages = serve(&[
+        ("initialize", json!({})),
+        ("launch", json!({ "program": path })),
+        ("setBreakpoints", json!({ "breakpoints": [{ "line": 2 }] })),
+        ("configurationDone", json!({})),
+        ("stackTrace", json!({ "threadId": 1 })),
+        ("variables", json!({ "variablesReference": 2 })),
+        ("continue", json!({ "threadId": 1 })),
+        ("disconnect", json!({})),
+    ]);
+
+    assert_eq!(
+        response(&messages, "setBreakpoints")["body"]["breakpoints"],
+        json!([{ "verified": true, "line": 2 }])
+    );
+
+    let frames = &response(&messages, "stackTrace")["body"]["stackFrames"];
+
+    assert_eq!(frames.as_array().unwrap().len(), 1);
+    assert_eq!(frames[0]["line"], 2);
+    assert_eq!(frames[0]["source"], json!({ "path": "two_lines.cog" }));
+    assert_eq!(
+        response(&messages, "variables")["body"]["variables"],
+        json!([])
+    );
+    assert_eq!(
+        events(&messages),
+        [
+            "initialized",
+            "output",
+            "stopped:breakpoint",
+            "output",
+            "exited",
+            "terminated"
+        ]
+    );
+
+    let output = messages
+        .iter()
+        .filter(|message| message["event"] == "output")
+        .map(|message| message["body"]["output"].as_str().unwrap())
+        .collect::<String>();
+
+    assert_eq!(output, "1\n2\n");
+    assert!(messages
+        .iter()
+        .filter(|message| message["type"] == "response")
+        .all(|message| message["success"] == true));
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn steps_over_instructions() {
+    let path = program("step", &two_lines());
+    let messages = serve(&[
+        ("initialize", json!({})),
+        ("launch", json!({ "program": path, "stopOnEntry": true })),
+        ("configurationDone", json!({})),
+        ("next", json!({ "threadId": 1 })),
+        ("next", json!({ "threadId": 1 })),
+        ("stackTrace", json!({ "threadId": 1 })),
+        ("disconnect", json!({})),
+    ]);
+
+    assert_eq!(
+        events(&messages),
+        [
+            "initialized",
+            "stopped:entry",
+            "stopped:step",
+            "output",
+            "stopped:step"
+        ]
+    );
+    assert_eq!(
+        response(&messages, "stackTrace")["body"]["stackFrames"][0]["line"],
+        2
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn malformed_program_fails_launch() {
+    let mut truncated = two_lines();
+
+    truncated.truncate(truncated.len() / 2);
+
+    let path = program("malformed", &truncated);
+    let messages = serve(&[
+        ("initialize", json!({})),
+        ("launch", json!({ "program": path })),
+        ("threads", json!({})),
+        ("stackTrace", json!({ "threadId": 1 })),
+        ("disconnect", json!({})),
+    ]);
+    let launch = response(&messages, "launch");
+
+    assert_eq!(launch["success"], false);
+    assert!(launch["message"]
+        .as_str()
+        .unwrap()
+        .ends_with("Unexpected end of bytecode"));
+    // The server outlives the bad program
+    assert_eq!(response(&messages, "threads")["success"], true);
+    assert_eq!(
+        response(&messages, "stackTrace")["message"],
+        "No program launched"
+    );
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn pause_is_unsupported() {
+    let path = program("pause", &two_lines());
+    let messages = serve(&[
+        ("initialize", json!({})),
+        ("launch", json!({ "program": path, "stopOnEntry": true })),
+        ("configurationDone", json!({})),
+        ("pause", json!({ "threadId": 1 })),
+        ("disconnect", json!({})),
+    ]);
+
+    assert_eq!(response(&messages, "pause")["success"], false);
+    assert_eq!(events(&messages), ["initialized", "stopped:entry"]);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn stops_at_breakpoint_on_first_line() {
+    let path = program("first_line", &two_lines());
+    let messages = serve(&[
+        ("initialize", json!({})),
+        ("launch", json!({ "program": path })),
+        ("setBreakpoints", json!({ "breakpoints": [{ "line": 1 }] })),
+        ("configurationDone", json!({})),
+        ("disconnect", json!({})),
+    ]);
+
+    assert_eq!(events(&messages), ["initialized", "stopped:breakpoint"]);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn positions_before_first_line_belong_to_it() {
+    let path = program("late_lines", &late_lines());
+    let messages = serve(&[
+        ("initialize", json!({})),
+        ("launch", json!({ "program": path, "stopOnEntry": true })),
+        ("configurationDone", json!({})),
+        ("stackTrace", json!({ "threadId": 1 })),
+        ("disconnect", json!({})),
+    ]);
+
+    assert_eq!(
+        response(&messages, "stackTrace")["body"]["stackFrames"][0]["line"],
+        3
+    );
+
+    fs::remove_file(path).unwrap();
+}
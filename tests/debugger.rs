@@ -0,0 +1,182 @@
+use cogwork::{
+    debugger::{Debugger, StopReason},
+    opcode::{Arity, Opcode},
+    vm::{Code, Process, ProcessStatus, Stackable, VM},
+};
+
+/// Doubles 5 in a function, then adds 2.
+fn program() -> Debugger {
+    let vm = VM::new_vm(
+        vec![
+            Stackable::String("double".to_string()),
+            Stackable::Int(2),
+            Stackable::Int(5),
+        ],
+        Code::new(
+            vec![
+                Opcode::Func(0, 1, 1, 0, Arity::default()),
+                Opcode::Ldc(1),
+                Opcode::Mul,
+                Opcode::Return,
+                Opcode::Ldc(2),
+                Opcode::Invoke(0, 1),
+                Opcode::Ldc(1),
+                Opcode::Add,
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+
+    Debugger::new(Process::new_process(vm, 0))
+}
+
+fn finished() -> StopReason {
+    StopReason::Terminated(ProcessStatus::Finished(vec![Stackable::Int(12)]))
+}
+
+#[test]
+fn continue_stops_at_breakpoint_on_first_instruction() {
+    let mut debugger = program();
+
+    debugger.add_breakpoint(0);
+
+    assert_eq!(debugger.cont(), StopReason::Breakpoint(0));
+    assert_eq!(debugger.cont(), finished());
+}
+
+#[test]
+fn breakpoints_hit_until_deleted() {
+    let mut debugger = program();
+
+    assert!(debugger.add_breakpoint(5));
+    assert!(debugger.add_breakpoint(1));
+    assert!(!debugger.add_breakpoint(1));
+
+    assert_eq!(debugger.cont(), StopReason::Breakpoint(5));
+    assert_eq!(debugger.call_stack().len(), 1);
+    assert_eq!(debugger.cont(), StopReason::Breakpoint(1));
+    assert_eq!(debugger.call_stack().len(), 2);
+
+    assert!(debugger.remove_breakpoint(1));
+    assert!(!debugger.remove_breakpoint(1));
+    assert_eq!(debugger.breakpoints().iter().collect::<Vec<_>>(), [&5]);
+    assert_eq!(debugger.cont(), finished());
+}
+
+#[test]
+fn step_into_enters_functions() {
+    let mut debugger = program();
+
+    // func skips its body
+    assert_eq!(debugger.step_into(), StopReason::Step);
+    assert_eq!(debugger.process().pos(), Some(4));
+    assert_eq!(debugger.step_into(), StopReason::Step);
+    assert_eq!(debugger.step_into(), StopReason::Step);
+    assert_eq!(debugger.call_stack().len(), 2);
+    assert_eq!(debugger.process().pos(), Some(1));
+    assert_eq!(
+        debugger.current_frame().unwrap().stack(),
+        [Stackable::Int(5)]
+    );
+}
+
+#[test]
+fn step_over_runs_functions_through() {
+    let mut debugger = program();
+
+    debugger.add_breakpoint(5);
+    debugger.cont();
+
+    assert_eq!(debugger.step_over(), StopReason::Step);
+    assert_eq!(debugger.call_stack().len(), 1);
+    assert_eq!(debugger.process().pos(), Some(6));
+    assert_eq!(
+        debugger.current_frame().unwrap().stack(),
+        [Stackable::Int(10)]
+    );
+}
+
+#[test]
+fn step_over_stops_at_breakpoints_inside_functions() {
+    let mut debugger = program();
+
+    debugger.add_breakpoint(5);
+    debugger.add_breakpoint(2);
+    debugger.cont();
+
+    assert_eq!(debugger.step_over(), StopReason::Breakpoint(2));
+    assert_eq!(debugger.call_stack().len(), 2);
+}
+
+#[test]
+fn step_out_returns_to_caller() {
+    let mut debugger = program();
+
+    debugger.add_breakpoint(1);
+
+    assert_eq!(debugger.cont(), StopReason::Breakpoint(1));
+    assert_eq!(debugger.step_out(), StopReason::Step);
+    assert_eq!(debugger.call_stack().len(), 1);
+    assert_eq!(debugger.process().pos(), Some(6));
+    // Out of the outermost frame is the end
+    assert_eq!(debugger.step_out(), finished());
+}
+
+#[test]
+fn describe_resolves_constant_operands() {
+    let debugger = program();
+
+    assert_eq!(
+        debugger.describe(0).unwrap(),
+        "0: Func(0, 1, 1, 0, Arity { optional: 0, defaults: 0, rest: false }) ; double/1"
+    );
+    assert_eq!(debugger.describe(4).unwrap(), "4: Ldc(2) ; 5");
+    assert_eq!(debugger.describe(5).unwrap(), "5: Invoke(0, 1) ; double/1");
+    assert_eq!(debugger.describe(7).unwrap(), "7: Add");
+    assert_eq!(debugger.describe(9), None);
+}
+
+#[test]
+fn prompt_runs_scripted_session() {
+    let mut debugger = program();
+    let mut output = Vec::new();
+    let script = "\
+break 1
+continue
+stack
+backtrace
+delete 1
+delete 1
+list
+break x
+bogus
+continue
+";
+
+    debugger
+        .prompt(std::io::Cursor::new(script), &mut output)
+        .unwrap();
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+(cwdb) Breakpoint at 1
+(cwdb) Breakpoint hit at 1
+=> 1: Ldc(1) ; 2
+(cwdb) [5]
+(cwdb) #1 double/1 at 1
+#0 <main> at 6
+(cwdb) Deleted breakpoint at 1
+(cwdb) No breakpoint at 1
+(cwdb)    0: Func(0, 1, 1, 0, Arity { optional: 0, defaults: 0, rest: false }) ; double/1
+=> 1: Ldc(1) ; 2
+   2: Mul
+   3: Return
+   4: Ldc(2) ; 5
+(cwdb) Invalid position x
+(cwdb) Unknown command bogus, try `help`
+(cwdb) Process terminated: Finished([12])
+(cwdb) "
+    );
+}
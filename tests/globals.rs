@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use cogwork::{
+    error::VmError,
+    opcode::{Arity, Opcode},
+    vm::{Code, Process, Stackable, VM},
+};
+
+fn name(name: &str) -> Stackable {
+    Stackable::String(name.to_string())
+}
+
+#[test]
+fn global_set_in_function_is_visible_to_caller() {
+    // `init/0` sets `answer`, which main reads after the call
+    let vm = VM::new_vm(
+        vec![name("init"), name("answer"), Stackable::Int(42)],
+        Code::new(
+            vec![
+                Opcode::Func(0, 0, 0, 0, Arity::default()),
+                Opcode::Ldc(2),
+                Opcode::SetGlobal(1),
+                Opcode::Return,
+                Opcode::Invoke(0, 0),
+                Opcode::GetGlobal(1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+    let vm = Arc::new(vm);
+
+    assert_eq!(
+        Process::new_shared_process(vm.clone(), 0).run(),
+        Ok(vec![Stackable::Int(42)])
+    );
+    assert_eq!(vm.global("answer"), Some(Stackable::Int(42)));
+}
+
+#[test]
+fn undefined_global_reports_name() {
+    let vm = VM::new_vm(
+        vec![name("missing")],
+        Code::new(vec![Opcode::GetGlobal(0), Opcode::Return], 0),
+    );
+    let result = Process::new_process(vm, 0).run();
+
+    assert_eq!(
+        result,
+        Err(VmError::UndefinedGlobal {
+            name: "missing".to_string()
+        })
+    );
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Unable to get undefined global variable missing"
+    );
+}
+
+#[test]
+fn host_sets_globals_by_name() {
+    let vm = VM::new_vm(
+        vec![name("limit")],
+        Code::new(vec![Opcode::GetGlobal(0), Opcode::Return], 0),
+    );
+
+    assert!(vm.set_global("limit", Stackable::Long(300)));
+    assert!(!vm.set_global("unknown", Stackable::Int(1)));
+    assert_eq!(
+        Process::new_process(vm, 0).run(),
+        Ok(vec![Stackable::Long(300)])
+    );
+}
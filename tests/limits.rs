@@ -0,0 +1,308 @@
+use std::sync::Arc;
+
+use cogwork::{
+    error::VmError,
+    opcode::{Arity, Opcode},
+    scheduler::Scheduler,
+    vm::{Code, Process, ProcessStatus, Stackable, VmLimits, VM},
+};
+
+fn big_string() -> Stackable {
+    Stackable::String("x".repeat(4096))
+}
+
+/// Fits a single copy of [`big_string`] and a few small values, but not two copies.
+fn heap_limits() -> VmLimits {
+    VmLimits {
+        max_heap_bytes: 6000,
+        ..VmLimits::default()
+    }
+}
+
+fn run(vm: VM) -> Result<Vec<Stackable>, VmError> {
+    Process::new_process(vm, 0).run()
+}
+
+fn is_heap_exhausted(status: Option<&ProcessStatus>) -> bool {
+    matches!(
+        status,
+        Some(ProcessStatus::Faulted(VmError::HeapExhausted { .. }))
+    )
+}
+
+#[test]
+fn stack_overflow() {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(1)],
+        Code::new(
+            vec![
+                Opcode::Ldc(0),
+                Opcode::Ldc(0),
+                Opcode::Ldc(0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_limits(VmLimits {
+        max_stack_size: 2,
+        ..VmLimits::default()
+    });
+
+    assert_eq!(run(vm), Err(VmError::StackOverflow { limit: 2 }));
+}
+
+#[test]
+fn call_depth_exceeded() {
+    let vm = VM::new_vm(
+        vec![Stackable::String("f".to_string())],
+        Code::new(
+            vec![
+                Opcode::Func(0, 0, 0, 0, Arity::default()),
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_limits(VmLimits {
+        max_call_depth: 8,
+        ..VmLimits::default()
+    });
+
+    assert_eq!(run(vm), Err(VmError::CallDepthExceeded { limit: 8 }));
+}
+
+#[test]
+fn string_too_long() {
+    let vm = VM::new_vm(
+        vec![Stackable::String("long".to_string())],
+        Code::new(vec![Opcode::Ldc(0), Opcode::Return], 0),
+    )
+    .with_limits(VmLimits {
+        max_string_length: 3,
+        ..VmLimits::default()
+    });
+
+    assert_eq!(
+        run(vm),
+        Err(VmError::StringTooLong {
+            length: 4,
+            limit: 3
+        })
+    );
+}
+
+#[test]
+fn locals_exceeded() {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(1)],
+        Code::new(vec![Opcode::Ldc(0), Opcode::Store(2), Opcode::Return], 4),
+    )
+    .with_limits(VmLimits {
+        max_locals: 1,
+        ..VmLimits::default()
+    });
+
+    assert_eq!(run(vm), Err(VmError::LocalsExceeded { index: 2, limit: 1 }));
+}
+
+#[test]
+fn heap_exhausted() {
+    let vm = VM::new_vm(
+        vec![big_string()],
+        Code::new(vec![Opcode::Ldc(0), Opcode::Ldc(0), Opcode::Return], 0),
+    )
+    .with_limits(heap_limits());
+
+    assert!(matches!(
+        run(vm),
+        Err(VmError::HeapExhausted { limit: 6000, .. })
+    ));
+}
+
+#[test]
+fn heap_is_released_once_process_ends() {
+    let vm = Arc::new(
+        VM::new_vm(
+            vec![big_string()],
+            Code::new(vec![Opcode::Ldc(0), Opcode::Return], 0),
+        )
+        .with_limits(heap_limits()),
+    );
+
+    for _ in 0..3 {
+        let values = Process::new_shared_process(vm.clone(), 0).run();
+
+        assert_eq!(values, Ok(vec![big_string()]));
+        assert_eq!(vm.heap_usage(), 0);
+    }
+}
+
+#[test]
+fn globals_count_on_heap() {
+    let vm = Arc::new(
+        VM::new_vm(
+            vec![big_string(), Stackable::String("a".to_string())],
+            Code::new(
+                vec![Opcode::Ldc(0), Opcode::SetGlobal(1), Opcode::Return],
+                0,
+            ),
+        )
+        .with_limits(heap_limits()),
+    );
+
+    assert_eq!(Process::new_shared_process(vm.clone(), 0).run(), Ok(vec![]));
+    assert!(vm.heap_usage() >= 4096);
+
+    // The global still holds the first copy
+    let vm = VM::new_vm(
+        vec![big_string(), Stackable::String("a".to_string())],
+        Code::new(
+            vec![
+                Opcode::Ldc(0),
+                Opcode::SetGlobal(1),
+                Opcode::Ldc(0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_limits(heap_limits());
+
+    assert!(matches!(run(vm), Err(VmError::HeapExhausted { .. })));
+}
+
+#[test]
+fn replaced_global_is_released() {
+    let vm = VM::new_vm(
+        vec![big_string(), Stackable::String("a".to_string())],
+        Code::new(
+            vec![
+                Opcode::Ldc(0),
+                Opcode::SetGlobal(1),
+                Opcode::Ldc(0),
+                Opcode::SetGlobal(1),
+                Opcode::Ldc(0),
+                Opcode::SetGlobal(1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_limits(VmLimits {
+        max_heap_bytes: 10000,
+        ..VmLimits::default()
+    });
+
+    assert_eq!(run(vm), Ok(vec![]));
+}
+
+#[test]
+fn processes_share_heap() {
+    // The worker holds one copy and wakes the main process, which loads another
+    let vm = VM::new_vm(
+        vec![
+            big_string(),
+            Stackable::String("worker".to_string()),
+            Stackable::Int(0),
+            Stackable::Int(1),
+        ],
+        Code::new(
+            vec![
+                Opcode::Func(1, 0, 0, 0, Arity::default()),
+                Opcode::Ldc(0),
+                Opcode::Ldc(2),
+                Opcode::Ldc(3),
+                Opcode::Send,
+                Opcode::Receive,
+                Opcode::Return,
+                Opcode::Spawn(1, 0),
+                Opcode::Receive,
+                Opcode::Ldc(0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_limits(heap_limits());
+    let mut scheduler = Scheduler::new(vm, 100);
+
+    assert!(is_heap_exhausted(scheduler.run().get(&0)));
+}
+
+#[test]
+fn mailboxes_count_on_heap() {
+    // The worker gets no turn before the main process loads a second copy
+    let vm = VM::new_vm(
+        vec![big_string(), Stackable::String("worker".to_string())],
+        Code::new(
+            vec![
+                Opcode::Func(1, 0, 0, 0, Arity::default()),
+                Opcode::Receive,
+                Opcode::Return,
+                Opcode::Spawn(1, 0),
+                Opcode::Ldc(0),
+                Opcode::Send,
+                Opcode::Ldc(0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_limits(heap_limits());
+    let mut scheduler = Scheduler::new(vm, 100);
+
+    assert!(is_heap_exhausted(scheduler.run().get(&0)));
+}
+
+#[test]
+fn local_slots_count_on_heap() {
+    // Every frame declares as many locals as it can, recursing until the heap runs out
+    let vm = VM::new_vm(
+        vec![Stackable::String("f".to_string())],
+        Code::new(
+            vec![
+                Opcode::Func(0, 0, 0, u32::MAX, Arity::default()),
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+
+    assert!(matches!(run(vm), Err(VmError::HeapExhausted { .. })));
+}
+
+#[test]
+fn local_slots_are_released_on_return() {
+    let vm = Arc::new(VM::new_vm(
+        vec![Stackable::String("f".to_string())],
+        Code::new(
+            vec![
+                Opcode::Func(0, 0, 0, 100, Arity::default()),
+                Opcode::Return,
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+            ],
+            10,
+        ),
+    ));
+    let mut process = Process::new_shared_process(vm.clone(), 0);
+    let top_level = vm.heap_usage();
+
+    assert!(top_level > 0);
+
+    process.run_for(2);
+    assert!(vm.heap_usage() > top_level);
+
+    process.run_for(1);
+    assert_eq!(vm.heap_usage(), top_level);
+
+    drop(process);
+    assert_eq!(vm.heap_usage(), 0);
+}
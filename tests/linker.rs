@@ -0,0 +1,311 @@
+use cogwork::{
+    error::{LinkError, VmError},
+    linker::link,
+    module::{Export, Import, Module},
+    opcode::{Arity, Opcode},
+    verifier,
+    vm::{Code, DebugInfo, Process, Stackable, VM},
+};
+
+fn name(name: &str) -> Stackable {
+    Stackable::String(name.to_string())
+}
+
+fn func(name_index: u32, parameter_size: u8, return_size: u8) -> Opcode {
+    Opcode::Func(name_index, parameter_size, return_size, 0, Arity::default())
+}
+
+fn import(module: &str, function: &str, parameter_size: u8) -> Import {
+    Import {
+        module: module.to_string(),
+        function: function.to_string(),
+        parameter_size,
+    }
+}
+
+fn export(function: &str, parameter_size: u8) -> Export {
+    Export {
+        function: function.to_string(),
+        parameter_size,
+    }
+}
+
+fn module(
+    name: &str,
+    imports: Vec<Import>,
+    exports: Vec<Export>,
+    constants: Vec<Stackable>,
+    code: Code,
+) -> VM {
+    VM::new_vm(constants, code).with_module(Module {
+        name: name.to_string(),
+        imports,
+        exports,
+    })
+}
+
+/// Exports `sq/1` and `get/0`, which reads a global its top level code sets. Top level code
+/// jumps over `sq` and leaves a value on its stack and in a local.
+fn library() -> VM {
+    module(
+        "lib",
+        vec![],
+        vec![export("sq", 1), export("get", 0)],
+        vec![name("sq"), Stackable::Int(3), name("n"), name("get")],
+        Code::new(
+            vec![
+                Opcode::Ldc(1),
+                Opcode::Goto(3),
+                Opcode::Dump,
+                func(0, 1, 1),
+                Opcode::Goto(6),
+                Opcode::Dump,
+                Opcode::Dup,
+                Opcode::Mul,
+                Opcode::Return,
+                func(3, 0, 1),
+                Opcode::GetGlobal(2),
+                Opcode::Return,
+                Opcode::SetGlobal(2),
+                Opcode::Ldc(1),
+                Opcode::Ldc(1),
+                Opcode::Store(0),
+                Opcode::Return,
+            ],
+            1,
+        ),
+    )
+}
+
+fn main_module() -> VM {
+    module(
+        "main",
+        vec![import("lib", "sq", 1), import("lib", "get", 0)],
+        vec![],
+        vec![name("sq"), name("get"), Stackable::Int(7)],
+        Code::new(
+            vec![
+                Opcode::Goto(2),
+                Opcode::Dump,
+                Opcode::Ldc(2),
+                Opcode::Invoke(0, 1),
+                Opcode::Invoke(1, 0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+}
+
+fn run(vm: VM) -> Result<Vec<Stackable>, VmError> {
+    Process::new_process(vm, 0).run()
+}
+
+#[test]
+fn calls_imported_functions() {
+    let linked = link(main_module(), vec![library()]).unwrap();
+
+    assert_eq!(verifier::verify(&linked), Ok(()));
+    assert_eq!(run(linked), Ok(vec![Stackable::Int(49), Stackable::Int(3)]));
+}
+
+#[test]
+fn imported_modules_keep_their_stack_and_locals() {
+    let entry = module(
+        "main",
+        vec![import("lib", "sq", 1)],
+        vec![],
+        vec![],
+        Code::new(vec![Opcode::Load(0), Opcode::Return], 1),
+    );
+    let linked = link(entry, vec![library()]).unwrap();
+
+    assert_eq!(run(linked), Err(VmError::UninitializedLocal { index: 0 }));
+
+    let entry = module(
+        "main",
+        vec![import("lib", "sq", 1)],
+        vec![],
+        vec![],
+        Code::new(vec![Opcode::Return], 0),
+    );
+
+    assert_eq!(run(link(entry, vec![library()]).unwrap()), Ok(vec![]));
+}
+
+#[test]
+fn entry_offsets_are_rebased() {
+    let entry =
+        main_module().with_debug_info(DebugInfo::new("main".to_string(), vec![(0, 1), (2, 2)]));
+    let linked = link(entry, vec![library()]).unwrap();
+    let offset =
+        (linked.code().instructions().len() - main_module().code().instructions().len()) as u32;
+
+    assert_eq!(
+        linked.code().instructions()[offset as usize],
+        Opcode::Goto(offset + 2)
+    );
+    assert_eq!(
+        linked.debug_info().unwrap().lines(),
+        [(offset, 1), (offset + 2, 2)]
+    );
+}
+
+#[test]
+fn same_names_in_different_modules() {
+    // Both modules export `h`, `b.g` calls its own
+    let a = module(
+        "a",
+        vec![],
+        vec![export("h", 0)],
+        vec![name("h"), Stackable::Int(1)],
+        Code::new(vec![func(0, 0, 1), Opcode::Ldc(1), Opcode::Return], 0),
+    );
+    let b = module(
+        "b",
+        vec![],
+        vec![export("h", 0), export("g", 0)],
+        vec![name("h"), Stackable::Int(2), name("g")],
+        Code::new(
+            vec![
+                func(0, 0, 1),
+                Opcode::Ldc(1),
+                Opcode::Return,
+                func(2, 0, 1),
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+    let entry = module(
+        "main",
+        vec![import("a", "h", 0), import("b", "g", 0)],
+        vec![],
+        vec![name("h"), name("g")],
+        Code::new(
+            vec![Opcode::Invoke(0, 0), Opcode::Invoke(1, 0), Opcode::Return],
+            0,
+        ),
+    );
+
+    assert_eq!(
+        run(link(entry, vec![a, b]).unwrap()),
+        Ok(vec![Stackable::Int(1), Stackable::Int(2)])
+    );
+}
+
+#[test]
+fn ambiguous_import() {
+    let a = module(
+        "a",
+        vec![],
+        vec![export("h", 0)],
+        vec![name("h")],
+        Code::new(vec![func(0, 0, 0), Opcode::Return], 0),
+    );
+    let b = module(
+        "b",
+        vec![],
+        vec![export("h", 0)],
+        vec![name("h")],
+        Code::new(vec![func(0, 0, 0), Opcode::Return], 0),
+    );
+    let entry = module(
+        "main",
+        vec![import("a", "h", 0), import("b", "h", 0)],
+        vec![],
+        vec![],
+        Code::new(vec![Opcode::Return], 0),
+    );
+
+    assert_eq!(
+        link(entry, vec![a, b]).err(),
+        Some(LinkError::AmbiguousImport {
+            module: "main".to_string(),
+            import: import("b", "h", 0),
+        })
+    );
+}
+
+#[test]
+fn duplicate_module() {
+    assert_eq!(
+        link(main_module(), vec![library(), library()]).err(),
+        Some(LinkError::DuplicateModule {
+            name: "lib".to_string()
+        })
+    );
+}
+
+#[test]
+fn undeclared_export() {
+    let lib = module(
+        "lib",
+        vec![],
+        vec![export("sq", 2)],
+        vec![name("sq")],
+        Code::new(vec![func(0, 1, 1), Opcode::Return], 0),
+    );
+
+    assert_eq!(
+        link(main_module(), vec![lib]).err(),
+        Some(LinkError::UndeclaredExport {
+            module: "lib".to_string(),
+            export: export("sq", 2),
+        })
+    );
+}
+
+#[test]
+fn missing_imports() {
+    let entry = module(
+        "main",
+        vec![import("lib", "cube", 1), import("other", "f", 0)],
+        vec![],
+        vec![],
+        Code::new(vec![Opcode::Return], 0),
+    );
+
+    assert_eq!(
+        link(entry, vec![library()]).err(),
+        Some(LinkError::UnresolvedImports {
+            imports: vec![
+                ("main".to_string(), import("lib", "cube", 1)),
+                ("main".to_string(), import("other", "f", 0)),
+            ]
+        })
+    );
+}
+
+#[test]
+fn negative_zero_stays_apart_from_zero() {
+    let dependency = module(
+        "zero",
+        vec![],
+        vec![export("negative", 0)],
+        vec![name("negative"), Stackable::Double(-0.0)],
+        Code::new(vec![func(0, 0, 1), Opcode::Ldc(1), Opcode::Return], 0),
+    );
+    let entry = module(
+        "main",
+        vec![import("zero", "negative", 0)],
+        vec![],
+        vec![Stackable::Double(0.0), name("negative")],
+        Code::new(
+            vec![Opcode::Ldc(0), Opcode::Invoke(1, 0), Opcode::Return],
+            0,
+        ),
+    );
+    let linked = link(entry, vec![dependency]).unwrap();
+    let values = Process::new_process(linked, 0).run().unwrap();
+    let bits = values
+        .iter()
+        .map(|value| match value {
+            Stackable::Double(double) => double.to_bits(),
+            value => panic!("Expected double, got {:?}", value),
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(bits, [0.0f64.to_bits(), (-0.0f64).to_bits()]);
+}
@@ -0,0 +1,82 @@
+use cogwork::{error::LoadError, Loader};
+
+const FIXED_GOLDEN: &[u8] = include_bytes!("golden/fixed.gear");
+const COMPACT_GOLDEN: &[u8] = include_bytes!("golden/compact.gear");
+
+#[test]
+fn truncated_bytecode_fails_to_load() {
+    for golden in [FIXED_GOLDEN, COMPACT_GOLDEN] {
+        // Everything after code is optional debug info, which must be complete once started
+        let code_end = golden.len() - 34;
+
+        for len in (0..golden.len()).filter(|len| *len != code_end) {
+            assert_eq!(
+                Loader::new(&golden[..len]).try_load(),
+                Err(LoadError::UnexpectedEnd),
+                "bytecode of {} bytes",
+                len
+            );
+        }
+    }
+}
+
+#[test]
+fn huge_counts_fail_without_allocating() {
+    let mut bytecode = FIXED_GOLDEN.to_vec();
+
+    // Count of imports follows the 6 byte module name
+    bytecode[20..24].copy_from_slice(&0xFFFF_FFF0u32.to_be_bytes());
+
+    assert_eq!(
+        Loader::new(&bytecode).try_load(),
+        Err(LoadError::UnexpectedEnd)
+    );
+}
+
+#[test]
+fn malformed_header_fails_to_load() {
+    let mut bytecode = FIXED_GOLDEN.to_vec();
+
+    bytecode[0] = b'g';
+    assert_eq!(
+        Loader::new(&bytecode).try_load(),
+        Err(LoadError::InvalidHeader {
+            header: b"gEARWORK".to_vec()
+        })
+    );
+
+    let mut bytecode = FIXED_GOLDEN.to_vec();
+
+    bytecode[8] = 1;
+    assert_eq!(
+        Loader::new(&bytecode).try_load(),
+        Err(LoadError::UnsupportedVersion { version: 1 })
+    );
+
+    let mut bytecode = FIXED_GOLDEN.to_vec();
+
+    bytecode[9] = 7;
+    assert_eq!(
+        Loader::new(&bytecode).try_load(),
+        Err(LoadError::UnknownEncoding { flag: 7 })
+    );
+}
+
+#[test]
+fn unknown_opcode_fails_to_load() {
+    let mut bytecode = FIXED_GOLDEN.to_vec();
+
+    // First instruction follows the code's instruction count and max locals
+    bytecode[0x83] = 0xFF;
+
+    assert_eq!(
+        Loader::new(&bytecode).try_load(),
+        Err(LoadError::UnknownOpcode { opcode: 0xFF })
+    );
+}
+
+#[test]
+#[should_panic(expected = "Unexpected end of bytecode")]
+fn load_panics_on_malformed_bytecode() {
+    Loader::new(&FIXED_GOLDEN[..FIXED_GOLDEN.len() / 2]).load();
+}
@@ -0,0 +1,71 @@
+use cogwork::{
+    bytecode::{BytecodeBuilder, FORMAT_VERSION},
+    error::VmError,
+    vm::{Process, Stackable},
+    Loader,
+};
+
+fn run(bytecode: &[u8]) -> Result<Vec<Stackable>, VmError> {
+    Process::new_process(Loader::new(bytecode).load(), 0).run()
+}
+
+#[test]
+fn functions_do_not_see_caller_locals() {
+    let mut bytecode_builder = BytecodeBuilder::new();
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_ldc(Stackable::Int(1));
+    instruction_builder.visit_store(0);
+    instruction_builder.visit_func("read", 0, 1);
+    instruction_builder.visit_load(0);
+    instruction_builder.visit_return();
+    instruction_builder.visit_invoke("read", 0);
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+
+    assert_eq!(
+        run(&bytecode_builder.visit_end()),
+        Err(VmError::UninitializedLocal { index: 0 })
+    );
+}
+
+#[test]
+fn functions_do_not_overwrite_caller_locals() {
+    let mut bytecode_builder = BytecodeBuilder::new();
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_ldc(Stackable::Int(1));
+    instruction_builder.visit_store(0);
+    instruction_builder.visit_func("write", 0, 0);
+    instruction_builder.visit_ldc(Stackable::Int(2));
+    instruction_builder.visit_store(0);
+    instruction_builder.visit_load(0);
+    instruction_builder.visit_return();
+    instruction_builder.visit_invoke("write", 0);
+    instruction_builder.visit_load(0);
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+
+    assert_eq!(
+        run(&bytecode_builder.visit_end()),
+        Ok(vec![Stackable::Int(1)])
+    );
+}
+
+#[test]
+fn bytecode_carries_format_version() {
+    let mut bytecode_builder = BytecodeBuilder::new();
+    bytecode_builder.visit_code().visit_end();
+
+    assert_eq!(bytecode_builder.visit_end()[8], FORMAT_VERSION);
+}
+
+#[test]
+#[should_panic(expected = "Unsupported bytecode version 0")]
+fn loader_rejects_bytecode_with_shared_locals() {
+    // Version 1 had no version byte, its constant pool size follows the magic number
+    let mut bytecode = b"GEARWORK".to_vec();
+    bytecode.extend_from_slice(&[0; 8]);
+
+    Loader::new(&bytecode).load();
+}
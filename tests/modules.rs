@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use cogwork::{
+    bytecode::BytecodeBuilder,
+    debugger::{Debugger, StopReason},
+    error::{LoadError, VmError},
+    module::ModuleResolver,
+    vm::{Process, ProcessStatus, Stackable, VM},
+    Loader,
+};
+
+/// Serves bytecode by module name, counting how often each is resolved.
+#[derive(Default, Clone)]
+struct Modules {
+    bytecode: HashMap<String, Vec<u8>>,
+    resolved: Arc<AtomicUsize>,
+}
+
+impl Modules {
+    fn with(mut self, name: &str, bytecode: Vec<u8>) -> Self {
+        self.bytecode.insert(name.to_string(), bytecode);
+        self
+    }
+}
+
+impl ModuleResolver for Modules {
+    fn resolve(&self, name: &str) -> Option<Vec<u8>> {
+        self.resolved.fetch_add(1, Ordering::Relaxed);
+        self.bytecode.get(name).cloned()
+    }
+}
+
+/// Exports `square/1`, and `count/0` reading the global its top level code sets to 1.
+fn math() -> Vec<u8> {
+    let mut bytecode_builder = BytecodeBuilder::new();
+
+    bytecode_builder.visit_module("math");
+    bytecode_builder.visit_export("square", 1);
+    bytecode_builder.visit_export("count", 0);
+
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_func("square", 1, 1);
+    instruction_builder.visit_dup();
+    instruction_builder.visit_mul();
+    instruction_builder.visit_return();
+    instruction_builder.visit_func("count", 0, 1);
+    instruction_builder.visit_getglobal("count");
+    instruction_builder.visit_return();
+    instruction_builder.visit_ldc(Stackable::Int(1));
+    instruction_builder.visit_setglobal("count");
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+
+    bytecode_builder.visit_end()
+}
+
+/// Sets its own `count` global to 50, imports math twice and calls both its functions.
+fn main_program(import: bool) -> VM {
+    let mut bytecode_builder = BytecodeBuilder::new();
+
+    bytecode_builder.visit_module("main");
+    bytecode_builder.visit_import("math", "square", 1);
+    bytecode_builder.visit_import("math", "count", 0);
+
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_ldc(Stackable::Int(50));
+    instruction_builder.visit_setglobal("count");
+
+    if import {
+        instruction_builder.visit_import("math");
+        instruction_builder.visit_import("math");
+    }
+
+    instruction_builder.visit_ldc(Stackable::Int(7));
+    instruction_builder.visit_invoke("square", 1);
+    instruction_builder.visit_invoke("count", 0);
+    instruction_builder.visit_getglobal("count");
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+
+    Loader::new(&bytecode_builder.visit_end()).load()
+}
+
+#[test]
+fn imported_functions_run_in_their_module() {
+    let vm = main_program(true).with_resolver(Modules::default().with("math", math()));
+
+    // Each module sees its own `count`
+    assert_eq!(
+        Process::new_process(vm, 0).run(),
+        Ok(vec![
+            Stackable::Int(49),
+            Stackable::Int(1),
+            Stackable::Int(50)
+        ])
+    );
+}
+
+#[test]
+fn modules_load_once_per_vm() {
+    let modules = Modules::default().with("math", math());
+    let resolved = modules.resolved.clone();
+    let vm = Arc::new(main_program(true).with_resolver(modules));
+
+    for _ in 0..3 {
+        assert!(Process::new_shared_process(vm.clone(), 0).run().is_ok());
+    }
+
+    assert_eq!(resolved.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn import_without_resolver_fails() {
+    assert_eq!(
+        Process::new_process(main_program(true), 0).run(),
+        Err(VmError::NoModuleResolver)
+    );
+}
+
+#[test]
+fn import_of_unknown_module_fails() {
+    let vm = main_program(true).with_resolver(Modules::default());
+
+    assert_eq!(
+        Process::new_process(vm, 0).run(),
+        Err(VmError::UnknownModule {
+            name: "math".to_string()
+        })
+    );
+}
+
+#[test]
+fn import_of_malformed_module_fails() {
+    let vm = main_program(true).with_resolver(Modules::default().with("math", vec![1, 2, 3]));
+
+    assert_eq!(
+        Process::new_process(vm, 0).run(),
+        Err(VmError::MalformedModule {
+            name: "math".to_string(),
+            reason: LoadError::UnexpectedEnd,
+        })
+    );
+}
+
+#[test]
+fn calls_before_import_fail() {
+    let vm = main_program(false).with_resolver(Modules::default().with("math", math()));
+
+    assert_eq!(
+        Process::new_process(vm, 0).run(),
+        Err(VmError::ModuleNotLoaded {
+            name: "math".to_string()
+        })
+    );
+}
+
+#[test]
+fn coverage_counts_only_the_started_code() {
+    let vm = main_program(true).with_resolver(Modules::default().with("math", math()));
+    let mut process = Process::new_process(vm, 0);
+
+    process.enable_coverage();
+
+    while !process.step().is_terminated() {}
+
+    // Module code at the same positions runs too, but isn't counted
+    assert_eq!(process.coverage().unwrap().hits(), [1; 9]);
+}
+
+#[test]
+fn breakpoints_ignore_module_code() {
+    let vm = main_program(true).with_resolver(Modules::default().with("math", math()));
+    let mut debugger = Debugger::new(Process::new_process(vm, 0));
+
+    // Both main's `getglobal` and math's top level `ldc` are at 7
+    debugger.add_breakpoint(7);
+
+    assert_eq!(debugger.cont(), StopReason::Breakpoint(7));
+    assert_eq!(debugger.call_stack().len(), 1);
+    assert_eq!(
+        debugger.cont(),
+        StopReason::Terminated(ProcessStatus::Finished(vec![
+            Stackable::Int(49),
+            Stackable::Int(1),
+            Stackable::Int(50)
+        ]))
+    );
+}
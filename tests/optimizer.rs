@@ -0,0 +1,300 @@
+use cogwork::{
+    module::{Export, Import, Module, ModuleResolver},
+    opcode::{Arity, Opcode},
+    optimizer::{eliminate_dead_code, optimize, peephole, tail_calls},
+    vm::{Backend, Code, Process, Stackable, VmLimits, VM},
+    Writer,
+};
+
+fn name(name: &str) -> Stackable {
+    Stackable::String(name.to_string())
+}
+
+fn func(name_index: u32, parameter_size: u8, return_size: u8) -> Opcode {
+    Opcode::Func(name_index, parameter_size, return_size, 0, Arity::default())
+}
+
+fn run(vm: VM) -> Vec<Stackable> {
+    Process::new_process(vm, 0).run().unwrap()
+}
+
+/// Module `lib` exporting `sq/1`, which nothing in the module calls.
+fn library() -> VM {
+    VM::new_vm(
+        vec![name("sq")],
+        Code::new(
+            vec![
+                func(0, 1, 1),
+                Opcode::Dup,
+                Opcode::Mul,
+                Opcode::Return,
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_module(Module {
+        name: "lib".to_string(),
+        imports: vec![],
+        exports: vec![Export {
+            function: "sq".to_string(),
+            parameter_size: 1,
+        }],
+    })
+}
+
+struct Library;
+
+impl ModuleResolver for Library {
+    fn resolve(&self, name: &str) -> Option<Vec<u8>> {
+        (name == "lib").then(|| Writer::new(&optimize(library())).write())
+    }
+}
+
+#[test]
+fn peephole_folds_constants() {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(2), Stackable::Int(3)],
+        Code::new(
+            vec![Opcode::Ldc(0), Opcode::Ldc(1), Opcode::Add, Opcode::Return],
+            0,
+        ),
+    );
+    let optimized = peephole(vm);
+
+    match optimized.code().instructions() {
+        [Opcode::Ldc(index), Opcode::Return] => {
+            assert_eq!(optimized.constants()[*index as usize], Stackable::Int(5))
+        }
+        instructions => panic!("not folded: {:?}", instructions),
+    }
+    assert_eq!(run(optimized), vec![Stackable::Int(5)]);
+}
+
+#[test]
+fn peephole_removes_store_loads_and_nops() {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(1)],
+        Code::new(
+            vec![
+                Opcode::Ldc(0),
+                Opcode::Store(0),
+                Opcode::Load(0),
+                Opcode::Nop,
+                Opcode::Return,
+            ],
+            1,
+        ),
+    );
+    let optimized = peephole(vm);
+
+    assert_eq!(
+        optimized.code().instructions(),
+        [Opcode::Ldc(0), Opcode::Return]
+    );
+    assert_eq!(run(optimized), vec![Stackable::Int(1)]);
+}
+
+#[test]
+fn peephole_threads_jumps() {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(1)],
+        Code::new(
+            vec![
+                Opcode::Goto(2),
+                Opcode::Dump,
+                Opcode::Goto(4),
+                Opcode::Dump,
+                Opcode::Ldc(0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+
+    assert_eq!(peephole(vm).code().instructions()[0], Opcode::Goto(4));
+}
+
+#[test]
+fn dead_code_is_removed() {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(1), name("f"), Stackable::Int(9)],
+        Code::new(
+            vec![
+                func(1, 0, 1),
+                Opcode::Ldc(2),
+                Opcode::Return,
+                Opcode::Goto(6),
+                Opcode::Ldc(2),
+                Opcode::Dump,
+                Opcode::Ldc(0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+    let optimized = optimize(vm);
+
+    assert_eq!(
+        optimized.code().instructions(),
+        [Opcode::Ldc(0), Opcode::Return]
+    );
+    assert_eq!(optimized.constants(), [Stackable::Int(1)]);
+}
+
+#[test]
+fn called_functions_are_kept() {
+    let vm = VM::new_vm(
+        vec![name("f"), Stackable::Int(9)],
+        Code::new(
+            vec![
+                func(0, 0, 1),
+                Opcode::Ldc(1),
+                Opcode::Return,
+                Opcode::Invoke(0, 0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+    let instructions = vm.code().instructions().to_vec();
+    let optimized = eliminate_dead_code(vm);
+
+    assert_eq!(optimized.code().instructions(), instructions);
+    assert_eq!(run(optimized), vec![Stackable::Int(9)]);
+}
+
+#[test]
+fn exported_functions_are_kept() {
+    let vm = library();
+    let instructions = vm.code().instructions().to_vec();
+
+    assert_eq!(optimize(vm).code().instructions(), instructions);
+}
+
+#[test]
+fn tail_calls_within_functions() {
+    let vm = VM::new_vm(
+        vec![name("f"), name("g"), Stackable::Int(4)],
+        Code::new(
+            vec![
+                func(1, 1, 1),
+                Opcode::Return,
+                func(0, 1, 1),
+                Opcode::Invoke(1, 1),
+                Opcode::Return,
+                Opcode::Ldc(2),
+                Opcode::Invoke(0, 1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+    let optimized = tail_calls(vm);
+
+    assert_eq!(optimized.code().instructions()[3], Opcode::TailInvoke(1, 1));
+    // Top level code has no frame to reuse
+    assert_eq!(optimized.code().instructions()[6], Opcode::Invoke(0, 1));
+    assert_eq!(run(optimized), vec![Stackable::Int(4)]);
+}
+
+#[test]
+fn tail_calls_need_same_return_size() {
+    let vm = VM::new_vm(
+        vec![name("f"), name("g"), Stackable::Int(4)],
+        Code::new(
+            vec![
+                func(1, 1, 0),
+                Opcode::Return,
+                func(0, 1, 1),
+                Opcode::Ldc(2),
+                Opcode::Swp,
+                Opcode::Invoke(1, 1),
+                Opcode::Return,
+                Opcode::Ldc(2),
+                Opcode::Invoke(0, 1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+
+    assert_eq!(
+        tail_calls(vm).code().instructions()[5],
+        Opcode::Invoke(1, 1)
+    );
+}
+
+#[test]
+fn optimized_vm_keeps_settings() {
+    let limits = VmLimits {
+        max_call_depth: 8,
+        ..VmLimits::default()
+    };
+    let vm = library().with_limits(limits).with_backend(Backend::Closure);
+    let optimized = optimize(vm);
+
+    assert_eq!(*optimized.limits(), limits);
+    assert_eq!(optimized.backend(), Backend::Closure);
+    assert_eq!(optimized.module(), library().module());
+}
+
+#[test]
+fn optimized_vm_keeps_module_resolver() {
+    let vm = VM::new_vm(
+        vec![name("lib"), name("sq"), Stackable::Int(7)],
+        Code::new(
+            vec![
+                Opcode::Import(0),
+                Opcode::Ldc(2),
+                Opcode::Invoke(1, 1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+    .with_module(Module {
+        name: "main".to_string(),
+        imports: vec![Import {
+            module: "lib".to_string(),
+            function: "sq".to_string(),
+            parameter_size: 1,
+        }],
+        exports: vec![],
+    })
+    .with_resolver(Library);
+
+    assert_eq!(run(optimize(vm)), vec![Stackable::Int(49)]);
+}
+
+#[test]
+fn folding_keeps_negative_zero_apart() {
+    let vm = || {
+        VM::new_vm(
+            vec![Stackable::Double(0.0), Stackable::Double(-1.0)],
+            Code::new(
+                vec![
+                    Opcode::Ldc(0),
+                    Opcode::Ldc(1),
+                    Opcode::Mul,
+                    Opcode::Ldc(0),
+                    Opcode::Return,
+                ],
+                0,
+            ),
+        )
+    };
+    let unoptimized = run(vm());
+    let optimized = run(optimize(vm()));
+
+    assert_eq!(optimized.len(), 2);
+
+    for (optimized, unoptimized) in optimized.iter().zip(&unoptimized) {
+        match (optimized, unoptimized) {
+            (Stackable::Double(optimized), Stackable::Double(unoptimized)) => {
+                assert_eq!(optimized.to_bits(), unoptimized.to_bits())
+            }
+            values => panic!("Expected doubles, got {:?}", values),
+        }
+    }
+}
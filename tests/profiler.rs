@@ -0,0 +1,88 @@
+use cogwork::{
+    opcode::{Arity, Opcode},
+    profiler::{Profile, Profiler},
+    vm::{Code, Process, ProcessStatus, Stackable, VM},
+};
+
+/// `f/1` adds its parameter to what `g/0` returns, main calls `f(2)`.
+fn program() -> VM {
+    VM::new_vm(
+        vec![
+            Stackable::String("f".to_string()),
+            Stackable::String("g".to_string()),
+            Stackable::Int(1),
+            Stackable::Int(2),
+        ],
+        Code::new(
+            vec![
+                Opcode::Func(0, 1, 1, 0, Arity::default()),
+                Opcode::Invoke(1, 0),
+                Opcode::Add,
+                Opcode::Return,
+                Opcode::Func(1, 0, 1, 0, Arity::default()),
+                Opcode::Ldc(2),
+                Opcode::Return,
+                Opcode::Ldc(3),
+                Opcode::Invoke(0, 1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+}
+
+fn profile() -> Profile {
+    let mut profiler = Profiler::new(Process::new_process(program(), 0));
+
+    assert_eq!(
+        *profiler.run(),
+        ProcessStatus::Finished(vec![Stackable::Int(3)])
+    );
+
+    profiler.into_profile()
+}
+
+#[test]
+fn counts_opcodes_and_positions() {
+    let profile = profile();
+
+    assert_eq!(
+        profile.opcode_counts.into_iter().collect::<Vec<_>>(),
+        [
+            ("add", 1),
+            ("func", 2),
+            ("invoke", 2),
+            ("ldc", 2),
+            ("return", 3)
+        ]
+    );
+    assert_eq!(profile.position_counts.values().sum::<u64>(), 10);
+    assert!(profile.position_counts.values().all(|count| *count == 1));
+}
+
+#[test]
+fn counts_calls_and_folds_nested_stacks() {
+    let profile = profile();
+
+    assert_eq!(
+        profile
+            .functions
+            .iter()
+            .map(|(name, time)| (name.as_str(), time.calls))
+            .collect::<Vec<_>>(),
+        [("<main>", 1), ("f/1", 1), ("g/0", 1)]
+    );
+
+    let folded = profile.folded_stacks();
+    let stacks = folded
+        .lines()
+        .map(|line| {
+            let (stack, nanoseconds) = line.rsplit_once(' ').unwrap();
+
+            nanoseconds.parse::<u128>().unwrap();
+            stack
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(stacks, ["<main>", "<main>;f/1", "<main>;f/1;g/0"]);
+}
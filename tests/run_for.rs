@@ -0,0 +1,110 @@
+use cogwork::{
+    opcode::Opcode,
+    vm::{Code, Process, ProcessStatus, Stackable, VM},
+};
+
+/// `load; load; add` and `ldc; add` are fused by dispatch.
+fn fusable() -> VM {
+    VM::new_vm(
+        vec![Stackable::Int(1), Stackable::Int(2)],
+        Code::new(
+            vec![
+                Opcode::Ldc(0),
+                Opcode::Store(0),
+                Opcode::Load(0),
+                Opcode::Load(0),
+                Opcode::Add,
+                Opcode::Ldc(1),
+                Opcode::Add,
+                Opcode::Return,
+            ],
+            1,
+        ),
+    )
+}
+
+#[test]
+fn run_for_never_exceeds_budget_on_fused_instructions() {
+    let mut process = Process::new_process(fusable(), 0);
+
+    assert_eq!(process.run_for(2), &ProcessStatus::Yielded);
+    assert_eq!(process.pos(), Some(2));
+
+    for pos in 3..=7 {
+        assert_eq!(process.run_for(1), &ProcessStatus::Yielded);
+        assert_eq!(process.pos(), Some(pos));
+    }
+
+    assert_eq!(
+        process.run_for(1),
+        &ProcessStatus::Finished(vec![Stackable::Int(4)])
+    );
+}
+
+#[test]
+fn run_for_fuses_when_budget_covers_group() {
+    let mut process = Process::new_process(fusable(), 0);
+
+    process.run_for(2);
+
+    assert_eq!(process.run_for(3), &ProcessStatus::Yielded);
+    assert_eq!(process.pos(), Some(5));
+    assert_eq!(process.run_for(2), &ProcessStatus::Yielded);
+    assert_eq!(process.pos(), Some(7));
+}
+
+/// Calls `twice`, which doubles its parameter.
+fn calling() -> VM {
+    VM::new_vm(
+        vec![Stackable::String("twice".to_string()), Stackable::Long(21)],
+        Code::new(
+            vec![
+                Opcode::Func(0, 1, 1, 0, Default::default()),
+                Opcode::Dup,
+                Opcode::Add,
+                Opcode::Return,
+                Opcode::Ldc(1),
+                Opcode::Invoke(0, 1),
+                Opcode::Dump,
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+}
+
+#[test]
+fn run_for_advances_exactly_n_instructions() {
+    let mut process = Process::new_process(calling(), 0);
+
+    assert_eq!(process.run_for(0), &ProcessStatus::Yielded);
+    assert_eq!(process.pos(), Some(0));
+    // func skips its body, then ldc
+    assert_eq!(process.run_for(2), &ProcessStatus::Yielded);
+    assert_eq!(process.pos(), Some(5));
+    assert_eq!(process.run_for(1), &ProcessStatus::Yielded);
+    assert_eq!(process.frames().len(), 2);
+    assert_eq!(process.pos(), Some(1));
+    assert_eq!(process.run_for(2), &ProcessStatus::Yielded);
+    assert_eq!(process.pos(), Some(3));
+}
+
+#[test]
+fn run_for_resumes_after_yielding() {
+    let expected = Process::new_process(calling(), 0).run();
+
+    for n in 1..=4 {
+        let mut process = Process::new_process(calling(), 0);
+        let mut turns = 0;
+
+        let status = loop {
+            match process.run_for(n) {
+                ProcessStatus::Yielded => turns += 1,
+                status => break status.clone(),
+            }
+        };
+
+        assert_eq!(Ok(status), expected.clone().map(ProcessStatus::Finished));
+        assert_eq!(turns, 7 / n, "turns of {} instructions", n);
+    }
+}
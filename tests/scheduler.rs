@@ -0,0 +1,107 @@
+use cogwork::{
+    opcode::{Arity, Opcode},
+    scheduler::Scheduler,
+    vm::{Code, ProcessStatus, Stackable, VM},
+};
+
+fn finished(values: &[i32]) -> ProcessStatus {
+    ProcessStatus::Finished(values.iter().map(|value| Stackable::Int(*value)).collect())
+}
+
+#[test]
+fn processes_take_turns_round_robin() {
+    // Each worker sends its tag to the main process twice, four instructions apart
+    let vm = VM::new_vm(
+        vec![
+            Stackable::String("worker".to_string()),
+            Stackable::Int(0),
+            Stackable::Int(10),
+            Stackable::Int(20),
+        ],
+        Code::new(
+            vec![
+                Opcode::Func(0, 1, 0, 1, Arity::default()),
+                Opcode::Store(0),
+                Opcode::Ldc(1),
+                Opcode::Load(0),
+                Opcode::Send,
+                Opcode::Ldc(1),
+                Opcode::Load(0),
+                Opcode::Send,
+                Opcode::Return,
+                Opcode::Ldc(2),
+                Opcode::Spawn(0, 1),
+                Opcode::Ldc(3),
+                Opcode::Spawn(0, 1),
+                Opcode::Receive,
+                Opcode::Receive,
+                Opcode::Receive,
+                Opcode::Receive,
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+    let mut scheduler = Scheduler::new(vm, 4);
+    let statuses = scheduler.run();
+
+    // Pids of the workers, then their tags interleaved turn by turn
+    assert_eq!(statuses.get(&0), Some(&finished(&[1, 2, 10, 20, 10, 20])));
+    assert_eq!(statuses.get(&1), Some(&finished(&[])));
+    assert_eq!(statuses.get(&2), Some(&finished(&[])));
+}
+
+#[test]
+fn mailboxes_deliver_in_order() {
+    let vm = VM::new_vm(
+        vec![],
+        Code::new(vec![Opcode::Receive, Opcode::Receive, Opcode::Return], 0),
+    );
+    let mut scheduler = Scheduler::new(vm, 10);
+
+    assert_eq!(scheduler.run().get(&0), Some(&ProcessStatus::Waiting));
+    assert!(scheduler.send(0, Stackable::Int(5)));
+    assert!(scheduler.send(0, Stackable::Int(6)));
+    assert!(!scheduler.send(1, Stackable::Int(7)));
+    assert_eq!(scheduler.run().get(&0), Some(&finished(&[5, 6])));
+    // Mail to finished processes is dropped
+    assert!(scheduler.send(0, Stackable::Int(8)));
+    assert_eq!(scheduler.status(0), Some(&finished(&[5, 6])));
+}
+
+#[test]
+fn spawned_processes_get_distinct_pids() {
+    let vm = VM::new_vm(
+        vec![Stackable::String("worker".to_string())],
+        Code::new(
+            vec![
+                Opcode::Func(0, 0, 0, 0, Arity::default()),
+                Opcode::Return,
+                Opcode::Spawn(0, 0),
+                Opcode::Spawn(0, 0),
+                Opcode::Spawn(0, 0),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    );
+    let mut scheduler = Scheduler::new(vm, 10);
+    let statuses = scheduler.run();
+
+    assert_eq!(statuses.get(&0), Some(&finished(&[1, 2, 3])));
+    assert_eq!(statuses.len(), 4);
+}
+
+#[test]
+fn zero_reductions_still_make_progress() {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(1), Stackable::Int(2)],
+        Code::new(
+            vec![Opcode::Ldc(0), Opcode::Ldc(1), Opcode::Add, Opcode::Return],
+            0,
+        ),
+    );
+    let mut scheduler = Scheduler::new(vm, 0);
+
+    assert_eq!(scheduler.run().get(&0), Some(&finished(&[3])));
+}
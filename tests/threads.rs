@@ -0,0 +1,78 @@
+use std::{sync::Arc, thread};
+
+use cogwork::{
+    opcode::Opcode,
+    vm::{Code, Process, Stackable, VM},
+};
+
+fn name(name: &str) -> Stackable {
+    Stackable::String(name.to_string())
+}
+
+/// Sets global `a` and returns 1 from 0, sets global `b` and returns 2 from 4, reads both
+/// globals from 8.
+fn program() -> VM {
+    VM::new_vm(
+        vec![
+            name("a"),
+            name("b"),
+            Stackable::String("x".repeat(1000)),
+            Stackable::String("y".repeat(1000)),
+            Stackable::Int(1),
+            Stackable::Int(2),
+        ],
+        Code::new(
+            vec![
+                Opcode::Ldc(2),
+                Opcode::SetGlobal(0),
+                Opcode::Ldc(4),
+                Opcode::Return,
+                Opcode::Ldc(3),
+                Opcode::SetGlobal(1),
+                Opcode::Ldc(5),
+                Opcode::Return,
+                Opcode::GetGlobal(0),
+                Opcode::GetGlobal(1),
+                Opcode::Return,
+            ],
+            0,
+        ),
+    )
+}
+
+#[test]
+fn processes_share_vm_across_threads() {
+    let vm = Arc::new(program());
+
+    assert_eq!(vm.heap_usage(), 0);
+
+    let threads = [0, 4].map(|pos| {
+        let vm = vm.clone();
+
+        thread::spawn(move || Process::new_shared_process(vm, pos).run())
+    });
+    let results = threads.map(|thread| thread.join().unwrap());
+
+    assert_eq!(
+        results,
+        [Ok(vec![Stackable::Int(1)]), Ok(vec![Stackable::Int(2)])]
+    );
+
+    // Globals outlive the processes that set them, and are counted until replaced
+    assert_eq!(vm.global("a"), Some(Stackable::String("x".repeat(1000))));
+    assert_eq!(vm.global("b"), Some(Stackable::String("y".repeat(1000))));
+    assert!(vm.heap_usage() >= 2000);
+    assert_eq!(
+        Process::new_shared_process(vm.clone(), 8).run(),
+        Ok(vec![
+            Stackable::String("x".repeat(1000)),
+            Stackable::String("y".repeat(1000))
+        ])
+    );
+
+    let globals_usage = vm.heap_usage();
+
+    assert!(vm.set_global("a", Stackable::Int(0)));
+    assert!(vm.set_global("b", Stackable::Int(0)));
+    assert!(vm.heap_usage() <= globals_usage - 2000);
+}
@@ -0,0 +1,76 @@
+#![cfg(feature = "trace")]
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use cogwork::{
+    opcode::Opcode,
+    trace::{BinaryTracer, TextTracer, Tracer},
+    vm::{Code, Process, Stackable, VM},
+};
+
+/// Output a tracer owns while the test keeps reading it.
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Traces a run of 10 + 10 with `tracer`.
+fn trace(tracer: impl Tracer + 'static) {
+    let vm = VM::new_vm(
+        vec![Stackable::Int(10)],
+        Code::new(
+            vec![Opcode::Ldc(0), Opcode::Dup, Opcode::Add, Opcode::Return],
+            0,
+        ),
+    );
+    let mut process = Process::new_process(vm, 0);
+
+    process.set_tracer(Arc::new(Mutex::new(tracer)));
+
+    assert_eq!(process.run(), Ok(vec![Stackable::Int(20)]));
+}
+
+#[test]
+fn text_tracer_writes_a_line_per_instruction() {
+    let output = SharedOutput::default();
+
+    trace(TextTracer::new(output.clone()));
+
+    assert_eq!(
+        String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+        "\
+#1 0: Ldc(0) [] -> [10]
+#1 1: Dup [10] -> [10, 10]
+#1 2: Add [10, 10] -> [20]
+#1 3: Return [20] -> [20]
+"
+    );
+}
+
+#[test]
+fn binary_tracer_writes_fixed_records() {
+    let output = SharedOutput::default();
+
+    trace(BinaryTracer::new(output.clone()));
+
+    let output = output.0.lock().unwrap().clone();
+    let records = output.chunks(15).collect::<Vec<_>>();
+
+    assert_eq!(output.len(), 4 * 15);
+    // pos, opcode index, depth, stack size before, stack size after
+    assert_eq!(records[0], [0, 0, 0, 0, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(records[1], [0, 0, 0, 1, 0x07, 0, 1, 0, 0, 0, 1, 0, 0, 0, 2]);
+    assert_eq!(records[2], [0, 0, 0, 2, 0x02, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1]);
+    assert_eq!(records[3], [0, 0, 0, 3, 0x0E, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1]);
+}
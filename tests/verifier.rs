@@ -0,0 +1,146 @@
+use cogwork::{
+    error::VerifyError,
+    opcode::{Arity, Opcode},
+    verifier::verify,
+    vm::{Code, Process, Stackable, VM},
+};
+
+fn name(name: &str) -> Stackable {
+    Stackable::String(name.to_string())
+}
+
+fn func(name_index: u32, parameter_size: u8, return_size: u8) -> Opcode {
+    Opcode::Func(name_index, parameter_size, return_size, 0, Arity::default())
+}
+
+fn vm(constants: Vec<Stackable>, instructions: Vec<Opcode>) -> VM {
+    VM::new_vm(constants, Code::new(instructions, 0))
+}
+
+#[test]
+fn accepts_multiple_return_values() {
+    let vm = vm(
+        vec![name("pair"), Stackable::Int(3), Stackable::Int(4)],
+        vec![
+            func(0, 0, 2),
+            Opcode::Ldc(1),
+            Opcode::Ldc(2),
+            Opcode::Return,
+            Opcode::Invoke(0, 0),
+            Opcode::Add,
+            Opcode::Return,
+        ],
+    );
+
+    assert_eq!(verify(&vm), Ok(()));
+    assert_eq!(
+        Process::new_process(vm, 0).run(),
+        Ok(vec![Stackable::Int(7)])
+    );
+}
+
+#[test]
+fn rejects_stack_underflow() {
+    let vm = vm(
+        vec![Stackable::Int(1)],
+        vec![Opcode::Ldc(0), Opcode::Add, Opcode::Return],
+    );
+
+    assert_eq!(
+        verify(&vm),
+        Err(VerifyError::StackUnderflow {
+            pos: 1,
+            required_size: 2,
+            actual_size: 1
+        })
+    );
+}
+
+#[test]
+fn rejects_missing_return_values() {
+    let vm = vm(
+        vec![name("pair"), Stackable::Int(3)],
+        vec![
+            func(0, 0, 2),
+            Opcode::Ldc(1),
+            Opcode::Return,
+            Opcode::Return,
+        ],
+    );
+
+    assert_eq!(
+        verify(&vm),
+        Err(VerifyError::MissingReturnValues {
+            pos: 2,
+            return_size: 2,
+            actual_size: 1
+        })
+    );
+}
+
+#[test]
+fn rejects_inconsistent_stack() {
+    // Every lap of the loop leaves one more item behind
+    let vm = vm(
+        vec![Stackable::Int(1)],
+        vec![Opcode::Ldc(0), Opcode::Goto(0)],
+    );
+
+    assert_eq!(
+        verify(&vm),
+        Err(VerifyError::InconsistentStack {
+            pos: 0,
+            depth: 0,
+            other_depth: 1
+        })
+    );
+}
+
+#[test]
+fn rejects_tail_call_of_other_return_size() {
+    let vm = vm(
+        vec![name("one"), name("two"), Stackable::Int(1)],
+        vec![
+            func(0, 0, 1),
+            Opcode::Ldc(2),
+            Opcode::Return,
+            func(1, 0, 2),
+            Opcode::TailInvoke(0, 0),
+            Opcode::Return,
+            Opcode::Return,
+        ],
+    );
+
+    assert_eq!(
+        verify(&vm),
+        Err(VerifyError::ReturnSizeMismatch {
+            pos: 4,
+            return_size: 2,
+            callee_return_size: 1
+        })
+    );
+}
+
+#[test]
+fn rejects_unknown_function() {
+    let vm = vm(
+        vec![name("f"), Stackable::Int(1)],
+        vec![
+            func(0, 0, 1),
+            Opcode::Ldc(1),
+            Opcode::Return,
+            Opcode::Ldc(1),
+            Opcode::Invoke(0, 1),
+            Opcode::Return,
+        ],
+    );
+
+    assert_eq!(
+        verify(&vm),
+        Err(VerifyError::UnknownFunction {
+            pos: 4,
+            name: "f".to_string(),
+            parameter_size: 1
+        })
+    );
+}
@@ -0,0 +1,311 @@
+use cogwork::{
+    bytecode::{BytecodeBuilder, Encoding},
+    module::{Export, Import, Module},
+    opcode::{Arity, Opcode},
+    vm::{Code, DebugInfo, Process, Stackable, VM},
+    Loader, Writer,
+};
+
+/// Xorshift generator, so failures reproduce without a seed being printed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Operands of every LEB128 width.
+    fn u32(&mut self) -> u32 {
+        match self.below(4) {
+            0 => self.below(128) as u32,
+            1 => self.below(1 << 14) as u32,
+            2 => self.next() as u32,
+            _ => u32::MAX,
+        }
+    }
+
+    fn u16(&mut self) -> u16 {
+        self.u32() as u16
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.next() as u8
+    }
+
+    fn string(&mut self) -> String {
+        let chars = ['a', 'z', '0', ' ', '.', 'é', '語', '🦀'];
+
+        (0..self.below(12))
+            .map(|_| chars[self.below(chars.len() as u64) as usize])
+            .collect()
+    }
+
+    fn constant(&mut self) -> Stackable {
+        match self.below(5) {
+            0 => Stackable::Int(self.next() as i32),
+            1 => Stackable::Long(self.next() as i64),
+            // Finite floats only, NaN never equals itself
+            2 => Stackable::Float(self.next() as i32 as f32 / 7.0),
+            3 => Stackable::Double(self.next() as i64 as f64 / 7.0),
+            _ => Stackable::String(self.string()),
+        }
+    }
+
+    fn opcode(&mut self) -> Opcode {
+        match self.below(28) {
+            0 => Opcode::Ldc(self.u32()),
+            1 => Opcode::Dump,
+            2 => Opcode::Add,
+            3 => Opcode::Sub,
+            4 => Opcode::Mul,
+            5 => Opcode::Div,
+            6 => Opcode::Mod,
+            7 => Opcode::Dup,
+            8 => Opcode::Swp,
+            9 => Opcode::Store(self.u16()),
+            10 => Opcode::Load(self.u16()),
+            11 => Opcode::Goto(self.u32()),
+            12 => Opcode::Nop,
+            13 => {
+                let parameter_size = self.u8();
+
+                Opcode::Func(
+                    self.u32(),
+                    parameter_size,
+                    self.u8(),
+                    self.u32(),
+                    Arity {
+                        optional: self.below(parameter_size as u64 + 1) as u8,
+                        defaults: self.u32(),
+                        rest: self.below(2) == 0,
+                    },
+                )
+            }
+            14 => Opcode::Return,
+            15 => Opcode::Invoke(self.u32(), self.u8()),
+            16 => Opcode::Coroutine(self.u32(), self.u8()),
+            17 => Opcode::Yield,
+            18 => Opcode::Resume,
+            19 => Opcode::Done,
+            20 => Opcode::Spawn(self.u32(), self.u8()),
+            21 => Opcode::Send,
+            22 => Opcode::Receive,
+            23 => Opcode::Pid,
+            24 => Opcode::SetGlobal(self.u32()),
+            25 => Opcode::GetGlobal(self.u32()),
+            26 => Opcode::TailInvoke(self.u32(), self.u8()),
+            _ => Opcode::Import(self.u32()),
+        }
+    }
+
+    fn module(&mut self) -> Module {
+        Module {
+            name: self.string(),
+            imports: (0..self.below(4))
+                .map(|_| Import {
+                    module: self.string(),
+                    function: self.string(),
+                    parameter_size: self.u8(),
+                })
+                .collect(),
+            exports: (0..self.below(4))
+                .map(|_| Export {
+                    function: self.string(),
+                    parameter_size: self.u8(),
+                })
+                .collect(),
+        }
+    }
+
+    fn vm(&mut self) -> VM {
+        let constants = (0..self.below(16)).map(|_| self.constant()).collect();
+        let instructions = (0..self.below(64)).map(|_| self.opcode()).collect();
+        let vm =
+            VM::new_vm(constants, Code::new(instructions, self.u32())).with_module(self.module());
+
+        if self.below(2) == 0 {
+            return vm;
+        }
+
+        let lines = (0..self.below(8))
+            .map(|_| (self.u32(), self.u32()))
+            .collect();
+
+        vm.with_debug_info(DebugInfo::new(self.string(), lines))
+    }
+}
+
+/// Program behind the golden files, touching every part of the format.
+fn golden(bytecode_builder: &mut BytecodeBuilder) {
+    bytecode_builder.visit_module("golden");
+    bytecode_builder.visit_import("math", "square", 1);
+    bytecode_builder.visit_export("scale", 2);
+
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_source("golden.cog");
+    instruction_builder.visit_line(1);
+    instruction_builder.visit_func_with_arity("scale", 2, 1, &[Stackable::Long(300)], false);
+    instruction_builder.visit_load(0);
+    instruction_builder.visit_load(1);
+    instruction_builder.visit_mul();
+    instruction_builder.visit_return();
+    instruction_builder.visit_line(2);
+    instruction_builder.visit_ldc(Stackable::Int(-7));
+    instruction_builder.visit_ldc(Stackable::Float(1.5));
+    instruction_builder.visit_ldc(Stackable::Double(-0.25));
+    instruction_builder.visit_ldc(Stackable::String("gear 語".to_string()));
+    instruction_builder.visit_store(200);
+    instruction_builder.visit_invoke("scale", 1);
+    instruction_builder.visit_setglobal("scaled");
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+}
+
+/// Bytes are big endian and never depend on the target, so these are compared byte for byte
+/// wherever tests run.
+const FIXED_GOLDEN: &[u8] = include_bytes!("golden/fixed.gear");
+const COMPACT_GOLDEN: &[u8] = include_bytes!("golden/compact.gear");
+
+#[test]
+fn builder_output_matches_golden_files() {
+    let mut bytecode_builder = BytecodeBuilder::new();
+
+    golden(&mut bytecode_builder);
+
+    assert_eq!(bytecode_builder.visit_end(), FIXED_GOLDEN);
+
+    let mut bytecode_builder = BytecodeBuilder::with_encoding(Encoding::Compact);
+
+    golden(&mut bytecode_builder);
+
+    assert_eq!(bytecode_builder.visit_end(), COMPACT_GOLDEN);
+}
+
+#[test]
+fn compact_encoding_is_smaller() {
+    let mut fixed = BytecodeBuilder::new();
+    let mut compact = BytecodeBuilder::with_encoding(Encoding::Compact);
+
+    golden(&mut fixed);
+    golden(&mut compact);
+
+    let fixed = fixed.visit_end();
+    let compact = compact.visit_end();
+
+    // Only operands shrink, one byte each for small ones instead of four or two
+    assert_eq!(fixed.len(), 225);
+    assert_eq!(compact.len(), 196);
+}
+
+#[test]
+fn compact_bytecode_runs_as_fixed_does() {
+    let mut fixed = BytecodeBuilder::new();
+    let mut compact = BytecodeBuilder::with_encoding(Encoding::Compact);
+
+    for bytecode_builder in [&mut fixed, &mut compact] {
+        let mut instruction_builder = bytecode_builder.visit_code();
+
+        instruction_builder.visit_func("double", 1, 1);
+        instruction_builder.visit_ldc(Stackable::Int(2));
+        instruction_builder.visit_mul();
+        instruction_builder.visit_return();
+        instruction_builder.visit_ldc(Stackable::Long(1 << 40));
+        instruction_builder.visit_store(300);
+        instruction_builder.visit_ldc(Stackable::Int(21));
+        instruction_builder.visit_invoke("double", 1);
+        instruction_builder.visit_load(300);
+        instruction_builder.visit_return();
+        instruction_builder.visit_end();
+    }
+
+    let fixed = Loader::new(&fixed.visit_end()).load();
+    let compact = Loader::new(&compact.visit_end()).load();
+
+    assert_eq!(compact, fixed);
+    assert_eq!(
+        Process::new_process(compact, 0).run(),
+        Ok(vec![Stackable::Int(42), Stackable::Long(1 << 40)])
+    );
+}
+
+#[test]
+fn writer_output_matches_golden_files() {
+    let vm = Loader::new(FIXED_GOLDEN).load();
+
+    assert_eq!(Writer::new(&vm).write(), FIXED_GOLDEN);
+    assert_eq!(
+        Writer::new(&vm).with_encoding(Encoding::Compact).write(),
+        COMPACT_GOLDEN
+    );
+    assert_eq!(Loader::new(COMPACT_GOLDEN).load(), vm);
+}
+
+fn round_trip(vm: &VM, encoding: Encoding) -> VM {
+    Loader::new(&Writer::new(vm).with_encoding(encoding).write()).load()
+}
+
+#[test]
+fn load_of_written_vm_is_equal() {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+
+    for _ in 0..500 {
+        let vm = rng.vm();
+
+        assert_eq!(round_trip(&vm, Encoding::Fixed), vm);
+        assert_eq!(round_trip(&vm, Encoding::Compact), vm);
+    }
+}
+
+#[test]
+fn written_bytecode_is_what_builder_emits() {
+    let mut bytecode_builder = BytecodeBuilder::new();
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_ldc(Stackable::Int(10));
+    instruction_builder.visit_store(0);
+    instruction_builder.visit_func("f", 1, 1);
+    instruction_builder.visit_load(0);
+    instruction_builder.visit_return();
+    instruction_builder.visit_invoke("f", 1);
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+
+    let bytecode = bytecode_builder.visit_end();
+
+    assert_eq!(
+        Writer::new(&Loader::new(&bytecode).load()).write(),
+        bytecode
+    );
+}
+
+#[test]
+fn div_and_mod_decode_to_their_opcodes() {
+    let mut bytecode_builder = BytecodeBuilder::new();
+    let mut instruction_builder = bytecode_builder.visit_code();
+
+    instruction_builder.visit_ldc(Stackable::Int(2));
+    instruction_builder.visit_ldc(Stackable::Int(7));
+    instruction_builder.visit_div();
+    instruction_builder.visit_ldc(Stackable::Int(2));
+    instruction_builder.visit_ldc(Stackable::Int(7));
+    instruction_builder.visit_mod();
+    instruction_builder.visit_return();
+    instruction_builder.visit_end();
+
+    let vm = Loader::new(&bytecode_builder.visit_end()).load();
+
+    assert_eq!(vm.code().instructions()[2], Opcode::Div);
+    assert_eq!(vm.code().instructions()[5], Opcode::Mod);
+    assert_eq!(
+        Process::new_process(vm, 0).run(),
+        Ok(vec![Stackable::Int(3), Stackable::Int(1)])
+    );
+}